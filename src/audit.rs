@@ -0,0 +1,515 @@
+//! Append-only journal of the changes made to Zitadel
+//!
+//! Every write to Zitadel is recorded as a line of JSON before it is
+//! sent, and again along with whether it succeeded once it returned.
+//! Each record contains the hash of the previous one and its own hash
+//! over its contents, so records cannot be altered, removed or reordered
+//! without breaking the chain, see [`verify_journal`].
+//!
+//! The chain alone cannot tell whether records were cut off the end, or
+//! the whole journal was rewritten. To detect that, the [`JournalHead`]
+//! is kept in the state store and checked against the journal.
+use std::{
+	collections::BTreeMap,
+	fs::{File, OpenOptions},
+	io::{BufRead, BufReader, ErrorKind, Write},
+	path::{Path, PathBuf},
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc, Mutex, PoisonError,
+	},
+};
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::user_ids::UserRef;
+
+/// The previous hash of the first record of a journal
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// A kind of write to Zitadel
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum AuditAction {
+	/// A user was created
+	Create,
+	/// The login name of a user was changed
+	UpdateUsername,
+	/// The name or display name of a user was changed
+	UpdateProfile,
+	/// The email address of a user was changed
+	UpdateEmail,
+	/// The phone number of a user was changed or removed
+	UpdatePhone,
+	/// A user was linked to an identity provider
+	LinkIdp,
+	/// A metadata value of a user was set
+	SetMetadata,
+	/// A metadata value of a user was removed
+	RemoveMetadata,
+	/// A user was granted roles in a project
+	AddGrant,
+	/// The roles of a user in a project were changed
+	UpdateGrant,
+	/// The roles of a user in a project were revoked
+	RemoveGrant,
+	/// A user deactivated in Zitadel was reactivated
+	Reactivate,
+	/// A user was deleted
+	Delete,
+}
+
+/// Whether a write to Zitadel succeeded
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "status")]
+pub(crate) enum AuditResult {
+	/// The write is about to be sent, its outcome is recorded next
+	Pending,
+	/// The write succeeded
+	Success,
+	/// The write failed
+	Failure {
+		/// Why the write failed
+		error: String,
+	},
+}
+
+/// A write to Zitadel, as recorded in the journal
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct AuditRecord {
+	/// When the write was made
+	pub(crate) timestamp: DateTime<Utc>,
+	/// The source the change came from
+	pub(crate) source: String,
+	/// What was written
+	pub(crate) action: AuditAction,
+	/// The ID of the user in the source, if known
+	pub(crate) external_id: Option<String>,
+	/// The Zitadel ID of the user, if known
+	pub(crate) zitadel_id: Option<String>,
+	/// The values of the changed fields before the write, as far as they
+	/// are known
+	pub(crate) before: BTreeMap<String, Value>,
+	/// The values of the changed fields after the write
+	pub(crate) after: BTreeMap<String, Value>,
+	/// Whether the write succeeded
+	pub(crate) result: AuditResult,
+}
+
+impl AuditRecord {
+	/// Start a record of a write to the given user
+	pub(crate) fn new(
+		source: &str,
+		action: AuditAction,
+		external_id: Option<&str>,
+		user_ref: Option<&UserRef>,
+	) -> Self {
+		Self {
+			timestamp: Utc::now(),
+			source: source.to_owned(),
+			action,
+			external_id: external_id.map(ToOwned::to_owned),
+			zitadel_id: user_ref.map(|user_ref| user_ref.user_id.clone()),
+			before: BTreeMap::new(),
+			after: BTreeMap::new(),
+			result: AuditResult::Success,
+		}
+	}
+
+	/// Record the value of a field before the write
+	#[must_use]
+	pub(crate) fn before(mut self, field: &str, value: impl Into<Value>) -> Self {
+		self.before.insert(field.to_owned(), value.into());
+		self
+	}
+
+	/// Record the value of a field after the write
+	#[must_use]
+	pub(crate) fn after(mut self, field: &str, value: impl Into<Value>) -> Self {
+		self.after.insert(field.to_owned(), value.into());
+		self
+	}
+
+	/// Record a field changed by the write
+	#[must_use]
+	pub(crate) fn change(
+		self,
+		field: &str,
+		before: impl Into<Value>,
+		after: impl Into<Value>,
+	) -> Self {
+		self.before(field, before).after(field, after)
+	}
+}
+
+/// The number of records of a journal and the hash of the last one
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct JournalHead {
+	/// The number of records
+	pub(crate) count: usize,
+	/// The hash of the last record
+	pub(crate) hash: String,
+}
+
+impl JournalHead {
+	/// The head of an empty journal
+	fn empty() -> Self {
+		Self { count: 0, hash: GENESIS_HASH.to_owned() }
+	}
+
+	/// Fail unless a journal with the given number of records, whose
+	/// record at this head's position has the given hash, continues this
+	/// head
+	fn check(&self, count: usize, hash: Option<&str>) -> Result<()> {
+		if count < self.count {
+			bail!(
+				"the journal holds {} records, but {} were recorded, records were removed from \
+				 its end",
+				count,
+				self.count
+			);
+		}
+		if self.count > 0 && hash != Some(self.hash.as_str()) {
+			bail!(
+				"record {} does not match the recorded head, the journal was rewritten",
+				self.count
+			);
+		}
+		Ok(())
+	}
+}
+
+/// The append-only journal of writes to Zitadel
+///
+/// Clones share the same journal. Records are written on the blocking
+/// thread pool. Once appending a record failed, the journal is broken
+/// and refuses further records, so that no change goes unrecorded.
+#[derive(Debug, Clone)]
+pub(crate) struct AuditJournal {
+	/// Where the journal is kept
+	path: PathBuf,
+	/// The open journal
+	state: Arc<Mutex<JournalState>>,
+	/// Whether appending a record failed
+	broken: Arc<AtomicBool>,
+}
+
+/// The open journal
+#[derive(Debug)]
+struct JournalState {
+	/// The journal file, opened for appending
+	file: File,
+	/// The head of the journal
+	head: JournalHead,
+}
+
+impl AuditJournal {
+	/// Open the journal at the given path, creating it if necessary
+	///
+	/// Fails if the journal does not continue the given head, as
+	/// recorded in the state store.
+	pub(crate) fn open(path: &Path, recorded_head: Option<&JournalHead>) -> Result<Self> {
+		let head = match File::open(path) {
+			Ok(file) => read_head(file, recorded_head)
+				.context(format!("audit journal {} is corrupt", path.display()))?,
+			Err(err) if err.kind() == ErrorKind::NotFound => {
+				if let Some(recorded_head) = recorded_head {
+					recorded_head
+						.check(0, None)
+						.context(format!("audit journal {} is missing", path.display()))?;
+				}
+				JournalHead::empty()
+			}
+			Err(err) => {
+				return Err(err).context(format!("failed to read audit journal {}", path.display()))
+			}
+		};
+
+		let file = OpenOptions::new()
+			.create(true)
+			.append(true)
+			.open(path)
+			.context(format!("failed to open audit journal {}", path.display()))?;
+
+		Ok(Self {
+			path: path.to_owned(),
+			state: Arc::new(Mutex::new(JournalState { file, head })),
+			broken: Arc::new(AtomicBool::new(false)),
+		})
+	}
+
+	/// Whether appending a record failed, so no further changes may be
+	/// made
+	pub(crate) fn is_broken(&self) -> bool {
+		self.broken.load(Ordering::SeqCst)
+	}
+
+	/// Append a record to the journal and wait until it is on disk
+	pub(crate) async fn append(&self, record: AuditRecord) -> Result<()> {
+		if self.is_broken() {
+			bail!("audit journal {} is broken", self.path.display());
+		}
+
+		let state = Arc::clone(&self.state);
+		let broken = Arc::clone(&self.broken);
+		let result = tokio::task::spawn_blocking(move || {
+			let mut journal = state.lock().unwrap_or_else(PoisonError::into_inner);
+			// A failed append may have left a partial record behind
+			if broken.load(Ordering::SeqCst) {
+				bail!("audit journal is broken");
+			}
+
+			let (line, hash) = chain(&record, &journal.head.hash)?;
+			let result = journal
+				.file
+				.write_all(line.as_bytes())
+				.and_then(|()| journal.file.sync_data())
+				.context("failed to write record");
+			if result.is_err() {
+				broken.store(true, Ordering::SeqCst);
+			} else {
+				journal.head = JournalHead { count: journal.head.count + 1, hash };
+			}
+			result
+		})
+		.await
+		.map_err(anyhow::Error::from)
+		.and_then(|result| result);
+
+		result.map_err(|error| {
+			self.broken.store(true, Ordering::SeqCst);
+			error.context(format!("failed to append to audit journal {}", self.path.display()))
+		})
+	}
+
+	/// The head of the journal, to be kept in the state store
+	pub(crate) async fn head(&self) -> Result<JournalHead> {
+		let state = Arc::clone(&self.state);
+		tokio::task::spawn_blocking(move || {
+			state.lock().unwrap_or_else(PoisonError::into_inner).head.clone()
+		})
+		.await
+		.context("failed to read the head of the audit journal")
+	}
+}
+
+/// Check that the hash chain of a journal is intact and continues the
+/// given head, as recorded in the state store, returning the number of
+/// records
+pub(crate) fn verify_journal(path: &Path, recorded_head: Option<&JournalHead>) -> Result<usize> {
+	let file =
+		File::open(path).context(format!("failed to read audit journal {}", path.display()))?;
+
+	let mut previous_hash = GENESIS_HASH.to_owned();
+	let mut count = 0;
+	let mut hash_at_head = None;
+	for (index, line) in BufReader::new(file).lines().enumerate() {
+		let line = line?;
+		if line.is_empty() {
+			continue;
+		}
+
+		previous_hash = verify_line(&line, &previous_hash)
+			.context(format!("record on line {} of the audit journal is invalid", index + 1))?;
+		count += 1;
+		if recorded_head.is_some_and(|head| head.count == count) {
+			hash_at_head = Some(previous_hash.clone());
+		}
+	}
+
+	if let Some(recorded_head) = recorded_head {
+		recorded_head.check(count, hash_at_head.as_deref())?;
+	}
+
+	Ok(count)
+}
+
+/// Check that a line of the journal follows the record with the given
+/// hash, returning its own hash
+fn verify_line(line: &str, previous_hash: &str) -> Result<String> {
+	let mut value: Value = serde_json::from_str(line)?;
+	let Some(Value::String(hash)) = value.as_object_mut().and_then(|record| record.remove("hash"))
+	else {
+		bail!("record has no hash");
+	};
+
+	if value.get("previous_hash").and_then(Value::as_str) != Some(previous_hash) {
+		bail!("record does not follow the previous record, records were removed or reordered");
+	}
+	if hash_value(&value) != hash {
+		bail!("record does not match its hash, it was altered");
+	}
+	serde_json::from_value::<AuditRecord>(value)?;
+
+	Ok(hash)
+}
+
+/// Read the head of a journal, checking that it continues the given
+/// head
+fn read_head(file: File, recorded_head: Option<&JournalHead>) -> Result<JournalHead> {
+	let mut last_line = None;
+	let mut count = 0;
+	let mut hash_at_head = None;
+	for line in BufReader::new(file).lines() {
+		let line = line?;
+		if line.is_empty() {
+			continue;
+		}
+
+		count += 1;
+		if recorded_head.is_some_and(|head| head.count == count) {
+			hash_at_head = Some(record_hash(&line)?);
+		}
+		last_line = Some(line);
+	}
+
+	if let Some(recorded_head) = recorded_head {
+		recorded_head.check(count, hash_at_head.as_deref())?;
+	}
+
+	match last_line {
+		Some(line) => Ok(JournalHead { count, hash: record_hash(&line)? }),
+		None => Ok(JournalHead::empty()),
+	}
+}
+
+/// The hash a record of the journal claims to have
+fn record_hash(line: &str) -> Result<String> {
+	match serde_json::from_str::<Value>(line)?.get("hash").and_then(Value::as_str) {
+		Some(hash) => Ok(hash.to_owned()),
+		None => bail!("record has no hash"),
+	}
+}
+
+/// Chain a record to the record with the given hash, returning the line
+/// to append and the hash of the record
+fn chain(record: &AuditRecord, previous_hash: &str) -> Result<(String, String)> {
+	let mut value = serde_json::to_value(record)?;
+	let Some(object) = value.as_object_mut() else {
+		bail!("audit record is not an object");
+	};
+	object.insert("previous_hash".to_owned(), previous_hash.into());
+
+	let hash = hash_value(&value);
+	if let Some(object) = value.as_object_mut() {
+		object.insert("hash".to_owned(), hash.clone().into());
+	}
+
+	Ok((format!("{value}\n"), hash))
+}
+
+/// The hex-encoded SHA-256 hash of the JSON form of a value
+fn hash_value(value: &Value) -> String {
+	format!("{:x}", Sha256::digest(value.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+	use tempfile::TempDir;
+
+	use super::*;
+
+	fn record(action: AuditAction) -> AuditRecord {
+		let user_ref = UserRef { user_id: "1".to_owned(), organization_id: "org".to_owned() };
+		AuditRecord::new("LDAP", action, Some("alice"), Some(&user_ref)).change(
+			"email",
+			"alice@example.org",
+			"alice@example.com",
+		)
+	}
+
+	#[tokio::test]
+	async fn test_audit_journal() {
+		let tempdir = TempDir::new().expect("failed to create temp dir");
+		let path = tempdir.path().join("audit.jsonl");
+
+		let journal = AuditJournal::open(&path, None).expect("failed to open journal");
+		let pending =
+			AuditRecord { result: AuditResult::Pending, ..record(AuditAction::UpdateEmail) };
+		journal.append(pending).await.expect("failed to append");
+		journal.append(record(AuditAction::UpdateEmail)).await.expect("failed to append");
+		let head = journal.head().await.expect("failed to read head");
+		assert_eq!(head.count, 2);
+		drop(journal);
+
+		// Reopened journals continue the chain
+		let journal = AuditJournal::open(&path, Some(&head)).expect("failed to reopen journal");
+		let failed = AuditRecord {
+			result: AuditResult::Failure { error: "unavailable".to_owned() },
+			..record(AuditAction::Delete)
+		};
+		journal.append(failed).await.expect("failed to append");
+		assert!(!journal.is_broken());
+
+		assert_eq!(verify_journal(&path, Some(&head)).expect("journal must be intact"), 3);
+
+		let data = std::fs::read_to_string(&path).expect("failed to read journal");
+		let lines: Vec<&str> = data.lines().collect();
+		let first: Value = serde_json::from_str(lines[0]).expect("invalid record");
+		assert_eq!(first["previous_hash"], GENESIS_HASH);
+		assert_eq!(first["action"], "update_email");
+		assert_eq!(first["before"]["email"], "alice@example.org");
+		assert_eq!(first["result"]["status"], "pending");
+		let second: Value = serde_json::from_str(lines[1]).expect("invalid record");
+		assert_eq!(second["previous_hash"], first["hash"]);
+		assert_eq!(second["result"]["status"], "success");
+		let third: Value = serde_json::from_str(lines[2]).expect("invalid record");
+		assert_eq!(third["result"]["error"], "unavailable");
+	}
+
+	#[tokio::test]
+	async fn test_audit_journal_tampering() {
+		let tempdir = TempDir::new().expect("failed to create temp dir");
+		let path = tempdir.path().join("audit.jsonl");
+
+		let journal = AuditJournal::open(&path, None).expect("failed to open journal");
+		for action in [AuditAction::Create, AuditAction::SetMetadata, AuditAction::Delete] {
+			journal.append(record(action)).await.expect("failed to append");
+		}
+		let head = journal.head().await.expect("failed to read head");
+		let data = std::fs::read_to_string(&path).expect("failed to read journal");
+		let lines: Vec<&str> = data.lines().collect();
+
+		// Altered records
+		std::fs::write(&path, data.replacen("alice@example.com", "mallory@example.com", 1))
+			.expect("failed to write journal");
+		assert!(verify_journal(&path, None).is_err());
+
+		// Removed records
+		std::fs::write(&path, format!("{}\n{}\n", lines[0], lines[2]))
+			.expect("failed to write journal");
+		assert!(verify_journal(&path, None).is_err());
+
+		// Reordered records
+		std::fs::write(&path, format!("{}\n{}\n{}\n", lines[1], lines[0], lines[2]))
+			.expect("failed to write journal");
+		assert!(verify_journal(&path, None).is_err());
+
+		// Truncated records
+		std::fs::write(&path, &data[..data.len() - 10]).expect("failed to write journal");
+		assert!(verify_journal(&path, None).is_err());
+		assert!(AuditJournal::open(&path, None).is_err());
+
+		// Records removed from the end, only detected with the head
+		std::fs::write(&path, format!("{}\n{}\n", lines[0], lines[1]))
+			.expect("failed to write journal");
+		assert_eq!(verify_journal(&path, None).expect("chain must be intact"), 2);
+		assert!(verify_journal(&path, Some(&head)).is_err());
+		assert!(AuditJournal::open(&path, Some(&head)).is_err());
+		std::fs::remove_file(&path).expect("failed to remove journal");
+		assert!(AuditJournal::open(&path, Some(&head)).is_err());
+
+		// Rewritten journals, only detected with the head
+		let journal = AuditJournal::open(&path, None).expect("failed to open journal");
+		for action in [AuditAction::Create, AuditAction::SetMetadata, AuditAction::AddGrant] {
+			journal.append(record(action)).await.expect("failed to append");
+		}
+		assert_eq!(verify_journal(&path, None).expect("chain must be intact"), 3);
+		assert!(verify_journal(&path, Some(&head)).is_err());
+		assert!(AuditJournal::open(&path, Some(&head)).is_err());
+	}
+}
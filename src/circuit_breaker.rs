@@ -0,0 +1,78 @@
+//! Circuit breaker stopping a sync run once Zitadel is unreachable
+use std::sync::{
+	atomic::{AtomicBool, AtomicU32, Ordering},
+	Arc,
+};
+
+/// Trips after a number of consecutive failures, after which requests
+/// should no longer be sent
+///
+/// Any successful request resets the count. Once tripped, the breaker
+/// stays open for the rest of the run. Clones share the same state.
+#[derive(Debug, Clone)]
+pub(crate) struct CircuitBreaker {
+	/// How many consecutive failures trip the breaker
+	threshold: u32,
+	/// The current number of consecutive failures
+	failures: Arc<AtomicU32>,
+	/// Whether the breaker has tripped
+	open: Arc<AtomicBool>,
+}
+
+impl CircuitBreaker {
+	/// Create a closed circuit breaker
+	pub(crate) fn new(threshold: u32) -> Self {
+		Self {
+			threshold: threshold.max(1),
+			failures: Arc::new(AtomicU32::new(0)),
+			open: Arc::new(AtomicBool::new(false)),
+		}
+	}
+
+	/// Whether the breaker has tripped
+	pub(crate) fn is_open(&self) -> bool {
+		self.open.load(Ordering::SeqCst)
+	}
+
+	/// Record a successful request
+	pub(crate) fn record_success(&self) {
+		self.failures.store(0, Ordering::SeqCst);
+	}
+
+	/// Record a failed request, tripping the breaker once the threshold
+	/// is reached
+	pub(crate) fn record_failure(&self) {
+		let failures = self.failures.fetch_add(1, Ordering::SeqCst).saturating_add(1);
+
+		if failures >= self.threshold && !self.open.swap(true, Ordering::SeqCst) {
+			tracing::error!(
+				"Zitadel failed {} times in a row, aborting all further requests of this run",
+				failures
+			);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_circuit_breaker() {
+		let breaker = CircuitBreaker::new(3);
+
+		breaker.record_failure();
+		breaker.record_failure();
+		breaker.record_success();
+		breaker.record_failure();
+		breaker.record_failure();
+		assert!(!breaker.is_open());
+
+		breaker.clone().record_failure();
+		assert!(breaker.is_open());
+
+		// The breaker stays open for the rest of the run
+		breaker.record_success();
+		assert!(breaker.is_open());
+	}
+}
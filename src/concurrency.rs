@@ -0,0 +1,211 @@
+//! Bounded-concurrency execution of per-user operations
+use std::{future::Future, time::Instant};
+
+use anyhow::Result;
+use itertools::Itertools;
+use tokio::task::JoinSet;
+
+/// The outcome of running an operation on a batch of users
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct BatchSummary {
+	/// How many operations succeeded
+	pub(crate) succeeded: usize,
+	/// How many operations failed
+	pub(crate) failed: usize,
+	/// The keys of the users whose operations failed
+	pub(crate) failed_users: Vec<String>,
+	/// How many operations were not started, because the batch was
+	/// aborted
+	pub(crate) skipped: usize,
+}
+
+/// Run an operation on each of a list of users, keyed by a stable
+/// user identifier, with at most `concurrency` users in flight at once
+///
+/// Operations on users with the same key are run in order by a single
+/// task, so they never overlap. No further operations are started once
+/// `is_aborted` returns true. Failures are logged, and the outcome of
+/// the batch is logged along with its throughput.
+pub(crate) async fn for_each_concurrent<T, F, Fut>(
+	concurrency: usize,
+	action: &str,
+	users: Vec<(String, T)>,
+	operation: F,
+	is_aborted: impl Fn() -> bool,
+) -> BatchSummary
+where
+	T: Send + 'static,
+	F: Fn(T) -> Fut + Clone + Send + 'static,
+	Fut: Future<Output = Result<()>> + Send + 'static,
+{
+	let mut summary = BatchSummary::default();
+	if users.is_empty() {
+		return summary;
+	}
+
+	let started = Instant::now();
+	let mut groups = users.into_iter().into_group_map().into_iter();
+	let mut tasks = JoinSet::new();
+
+	loop {
+		while tasks.len() < concurrency.max(1) && !is_aborted() {
+			let Some((key, users)) = groups.next() else {
+				break;
+			};
+
+			let operation = operation.clone();
+			tasks.spawn(async move {
+				let mut results = Vec::new();
+				for user in users {
+					results.push(operation(user).await);
+				}
+				(key, results)
+			});
+		}
+
+		let Some(joined) = tasks.join_next().await else {
+			summary.skipped = groups.map(|(_, users)| users.len()).sum();
+			break;
+		};
+
+		match joined {
+			Ok((key, results)) => {
+				for result in results {
+					match result {
+						Ok(()) => summary.succeeded += 1,
+						Err(error) => {
+							summary.failed += 1;
+							summary.failed_users.push(key.clone());
+							tracing::error!("Failed to {} user `{}`: {:?}", action, key, error);
+						}
+					}
+				}
+			}
+			Err(error) => {
+				summary.failed += 1;
+				tracing::error!("Task to {} a user failed: {:?}", action, error);
+			}
+		}
+	}
+
+	let elapsed = started.elapsed();
+	#[allow(clippy::cast_precision_loss)]
+	let rate = (summary.succeeded + summary.failed) as f64 / elapsed.as_secs_f64().max(f64::EPSILON);
+	tracing::info!(
+		"Tried to {} {} users in {:.1?} ({:.1} users/s), {} failed, {} skipped",
+		action,
+		summary.succeeded + summary.failed,
+		elapsed,
+		rate,
+		summary.failed,
+		summary.skipped
+	);
+
+	summary
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		sync::{
+			atomic::{AtomicUsize, Ordering},
+			Arc, Mutex, PoisonError,
+		},
+		time::Duration,
+	};
+
+	use anyhow::bail;
+
+	use super::*;
+
+	#[tokio::test]
+	async fn test_for_each_concurrent() {
+		let in_flight = Arc::new(AtomicUsize::new(0));
+		let max_in_flight = Arc::new(AtomicUsize::new(0));
+		let order = Arc::new(Mutex::new(Vec::new()));
+
+		let users = (0..20)
+			.map(|index| (format!("user{}", index % 10), index))
+			.collect::<Vec<(String, usize)>>();
+
+		let summary = for_each_concurrent(
+			4,
+			"test",
+			users,
+			{
+				let in_flight = in_flight.clone();
+				let max_in_flight = max_in_flight.clone();
+				let order = order.clone();
+				move |index| {
+					let in_flight = in_flight.clone();
+					let max_in_flight = max_in_flight.clone();
+					let order = order.clone();
+					async move {
+						let current = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+						max_in_flight.fetch_max(current, Ordering::SeqCst);
+						tokio::time::sleep(Duration::from_millis(5)).await;
+						order.lock().unwrap_or_else(PoisonError::into_inner).push(index);
+						in_flight.fetch_sub(1, Ordering::SeqCst);
+
+						if index == 7 {
+							bail!("failed");
+						}
+						Ok(())
+					}
+				}
+			},
+			|| false,
+		)
+		.await;
+
+		assert_eq!(
+			summary,
+			BatchSummary {
+				succeeded: 19,
+				failed: 1,
+				failed_users: vec!["user7".to_owned()],
+				skipped: 0
+			}
+		);
+		assert!(max_in_flight.load(Ordering::SeqCst) <= 4);
+		assert!(max_in_flight.load(Ordering::SeqCst) > 1);
+
+		// Operations on the same user keep their order
+		let order = order.lock().unwrap_or_else(PoisonError::into_inner).clone();
+		for index in 0..10 {
+			let first = order.iter().position(|&other| other == index);
+			let second = order.iter().position(|&other| other == index + 10);
+			assert!(first < second);
+		}
+	}
+
+	#[tokio::test]
+	async fn test_for_each_concurrent_abort() {
+		let started = Arc::new(AtomicUsize::new(0));
+		let users = (0..10).map(|index| (format!("user{index}"), index)).collect();
+
+		let summary = for_each_concurrent(
+			2,
+			"test",
+			users,
+			{
+				let started = started.clone();
+				move |_| {
+					let started = started.clone();
+					async move {
+						started.fetch_add(1, Ordering::SeqCst);
+						bail!("unreachable")
+					}
+				}
+			},
+			{
+				let started = started.clone();
+				move || started.load(Ordering::SeqCst) >= 4
+			},
+		)
+		.await;
+
+		assert_eq!((summary.succeeded, summary.failed, summary.skipped), (0, 4, 6));
+		assert_eq!(summary.failed_users.len(), 4);
+	}
+}
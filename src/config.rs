@@ -1,22 +1,37 @@
 //! All sync client configuration structs and logic
 use std::{
+	collections::HashMap,
 	ops::{Deref, DerefMut},
-	path::Path,
+	path::{Path, PathBuf},
+	sync::Arc,
 };
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
+use chrono::Utc;
 use serde::Deserialize;
 use tracing::{error, warn};
 use url::Url;
 
 use crate::{
+	audit,
+	localpart::{self, LocalpartStrategy},
+	lock::SyncLock,
+	phone::{PhoneConfig, PhoneNormalizer},
+	report::SyncReport,
+	script::{Script, ScriptConfig},
 	sources::{
 		csv::{CsvSource, CsvSourceConfig},
-		ldap::{LdapSource, LdapSourceConfig},
+		ldap::{CacheCipher, CacheCommand, LdapSource, LdapSourceConfig},
 		ukt::{UktSource, UktSourceConfig},
 		Source,
 	},
-	zitadel::{Zitadel, ZitadelConfig},
+	state::{PendingChange, QueuedChange, RunRecord, SqliteStateStore, StateChanges, StateStore},
+	transform::{TransformationsConfig, Transformer},
+	user::User,
+	validation::{ValidationConfig, Validator},
+	zitadel::{
+		LoginNameSource, SourceDiff, UserId, Zitadel, ZitadelConfig, RESERVED_METADATA_KEYS,
+	},
 };
 
 /// App prefix for env var configuration
@@ -36,6 +51,23 @@ pub struct Config {
 	/// Opt-in features
 	#[serde(default)]
 	pub feature_flags: FeatureFlags,
+	/// Transformations applied to the users of every source before
+	/// they are compared against Zitadel
+	#[serde(default)]
+	pub transformations: TransformationsConfig,
+	/// Optional script applied to the users of every source after the
+	/// transformations
+	pub script: Option<ScriptConfig>,
+	/// Optional normalisation of phone numbers to E.164, applied after
+	/// the script
+	pub phone: Option<PhoneConfig>,
+	/// What to do with users that fail validation before they are
+	/// written to Zitadel
+	#[serde(default)]
+	pub validation: ValidationConfig,
+	/// Optional SQLite database keeping the state of the sync, instead
+	/// of the LDAP cache and user ID cache files
+	pub state_path: Option<PathBuf>,
 }
 
 /// Configuration for sources
@@ -59,6 +91,7 @@ impl Config {
 					.separator("__")
 					.list_separator(ENV_VAR_LIST_SEP)
 					.with_list_parse_key("sources.ldap.attributes.disable_bitmasks")
+					.with_list_parse_key("sources.ldap.attributes.additional")
 					.with_list_parse_key("feature_flags")
 					.try_parsing(true),
 			);
@@ -74,6 +107,39 @@ impl Config {
 	fn validate(mut self) -> Result<Self> {
 		self.zitadel.url = validate_zitadel_url(self.zitadel.url)?;
 
+		let metadata_keys = self
+			.sources
+			.ldap
+			.iter()
+			.flat_map(|ldap| ldap.attributes.metadata.keys())
+			.chain(self.sources.csv.iter().flat_map(|csv| csv.metadata.keys()));
+		for key in metadata_keys {
+			if RESERVED_METADATA_KEYS.contains(&key.as_str()) {
+				bail!("metadata key `{}` is reserved for the sync", key);
+			}
+		}
+
+		Transformer::new(&self.transformations).context("invalid transformations")?;
+		self.validation.check().context("invalid validation policies")?;
+
+		// With a state store, the cache file is at most imported once;
+		// the cache is checked when it is read at the start of a sync
+		if let Some(ldap) = self.sources.ldap.as_ref().filter(|_| self.state_path.is_none()) {
+			ldap.check_cache_encryption().context("unusable LDAP cache")?;
+		}
+
+		if self.zitadel.concurrency == 0 {
+			bail!("zitadel concurrency must be at least 1");
+		}
+		if self.zitadel.circuit_breaker_threshold == 0 {
+			bail!("zitadel circuit_breaker_threshold must be at least 1");
+		}
+
+		// The UKT source only knows the email addresses of deleted users
+		if self.sources.ukt.is_some() && self.zitadel.login_name != LoginNameSource::Email {
+			bail!("the UKT source requires email addresses as login names");
+		}
+
 		Ok(self)
 	}
 
@@ -83,32 +149,79 @@ impl Config {
 			anyhow::bail!("Non-SSO configuration is currently not supported");
 		}
 
-		let mut sources: Vec<Box<dyn Source + Send + Sync>> = Vec::new();
+		let _lock = self.lock_path().map(|path| SyncLock::acquire(&path)).transpose()?;
+		let state = self.open_state()?;
 
-		if let Some(ldap_config) = &self.sources.ldap {
-			let ldap = LdapSource::new(
-				ldap_config.clone(),
-				self.feature_flags.is_enabled(FeatureFlag::DryRun),
-			);
-			sources.push(Box::new(ldap));
+		if let Some(state) = &state {
+			if let Some(last_run) = state.runs(1).await?.first() {
+				tracing::info!(
+					"Last sync run finished at {}{}",
+					last_run.finished_at,
+					if last_run.error.is_some() { " with errors" } else { "" }
+				);
+			}
 		}
 
-		if let Some(ukt_config) = &self.sources.ukt {
-			let ukt = UktSource::new(ukt_config.clone());
-			sources.push(Box::new(ukt));
+		let started_at = Utc::now();
+		let result = self.sync_sources(state.as_ref()).await;
+
+		// Record the run, even if it failed
+		if let Some(state) = state.filter(|_| !self.feature_flags.is_enabled(FeatureFlag::DryRun)) {
+			let mut changes = StateChanges::default();
+			changes.add_run(RunRecord {
+				started_at,
+				finished_at: Utc::now(),
+				error: result.as_ref().err().map(|error| format!("{error:#}")),
+			});
+			if let Err(e) = state.commit(changes).await {
+				warn!("Failed to record the sync run: {:?}", e);
+			}
 		}
 
-		if let Some(csv_config) = &self.sources.csv {
-			let csv = CsvSource::new(csv_config.clone());
-			sources.push(Box::new(csv));
-		}
+		result
+	}
+
+	/// Sync the changes of all sources to Zitadel
+	///
+	/// With a state store, changes that failed to be written are retried
+	/// next run, and the state of each source is committed along with
+	/// the Zitadel IDs of users once its changes were applied.
+	async fn sync_sources(&self, state: Option<&Arc<dyn StateStore>>) -> Result<()> {
+		let sources = self.get_sources(state)?;
+		let report = SyncReport::default();
+		let transformer = Transformer::new(&self.transformations)?;
+		let script =
+			self.script.as_ref().map(|config| Script::new(config, report.clone())).transpose()?;
+		let phone = self
+			.phone
+			.as_ref()
+			.map(|config| PhoneNormalizer::new(config, report.clone()))
+			.transpose()?;
+		let validator = Validator::new(&self.validation, &self.zitadel, report.clone())?;
 
 		// Setup Zitadel client
-		let zitadel = Zitadel::new(self).await?;
+		let zitadel = Zitadel::new(self, report.clone(), state.map(AsRef::as_ref)).await?;
 
-		// Sync from each available source
+		let mut diffs = Vec::new();
+		let mut attempts = HashMap::new();
+		let mut unmapped = HashMap::new();
 		for source in sources.iter() {
-			let diff = match source.get_diff().await {
+			let mut queue = match state {
+				Some(state) => state.retry_queue(source.get_name()).await?,
+				None => Vec::new(),
+			};
+
+			let (mut diff, mut source_attempts, skipped) = match self
+				.get_source_diff(
+					source.as_ref(),
+					&zitadel,
+					&transformer,
+					script.as_ref(),
+					phone.as_ref(),
+					PendingChange::take_unmapped(&mut queue),
+				)
+				.await
+			{
 				Ok(diff) => diff,
 				Err(e) => {
 					error!("Failed to get diff from {}: {:?}", source.get_name(), e);
@@ -116,22 +229,473 @@ impl Config {
 				}
 			};
 
-			if !self.feature_flags.is_enabled(FeatureFlag::DeactivateOnly) {
-				if let Err(e) = zitadel.import_new_users(diff.new_users).await {
-					warn!("Failed to import new users from {}: {:?}", source.get_name(), e);
+			source_attempts.extend(PendingChange::add_to_diff(queue, &mut diff));
+			attempts.insert(source.get_name(), source_attempts);
+			unmapped.insert(source.get_name(), skipped);
+
+			diffs.push((source.as_ref(), diff));
+		}
+
+		// Validate the changes of all sources before writing anything, so
+		// invalid users can abort the run
+		let diffs = match self.validate_diffs(&zitadel, &validator, diffs).await {
+			Ok(diffs) => diffs,
+			Err(e) => {
+				report.log_summary();
+				return Err(e.context("aborting sync"));
+			}
+		};
+
+		// Sync from each available source
+		for (source, diff, skipped) in diffs {
+			if zitadel.is_aborted() {
+				break;
+			}
+
+			// Skipped users are retried along with the failed changes,
+			// since the source will not report them again
+			let mut failed = self.apply_source_diff(&zitadel, source.get_name(), diff).await;
+			failed.extend(skipped);
+			failed.extend(unmapped.remove(source.get_name()).unwrap_or_default());
+
+			// Only record the source's progress if its changes made it to
+			// Zitadel, so they are retried next run otherwise
+			if zitadel.is_aborted() {
+				error!("Sync was aborted, not committing the state of {}", source.get_name());
+				continue;
+			}
+
+			let mut changes = StateChanges::default();
+			if let Err(e) = source.commit(&mut changes).await {
+				warn!("Failed to commit the state of {}: {:?}", source.get_name(), e);
+			}
+
+			if let Some(state) =
+				state.filter(|_| !self.feature_flags.is_enabled(FeatureFlag::DryRun))
+			{
+				let queue = PendingChange::requeue(
+					failed,
+					&attempts.remove(source.get_name()).unwrap_or_default(),
+					source.get_name(),
+					&report,
+				);
+				changes.set_retry_queue(source.get_name(), queue);
+				changes.set_user_refs(zitadel.user_refs());
+				if let Some(localparts) = zitadel.localparts() {
+					changes.set_localparts(localparts);
 				}
-				if let Err(e) = zitadel.delete_users_by_id(diff.deleted_user_ids).await {
-					warn!("Failed to delete users from {}: {:?}", source.get_name(), e);
+				if let Err(e) = state.commit(changes).await {
+					warn!("Failed to commit the state of {}: {:?}", source.get_name(), e);
 				}
 			}
+		}
+
+		if let Err(e) = zitadel.persist_user_ids().await {
+			warn!("Failed to persist user ID cache: {:?}", e);
+		}
+
+		self.record_journal_head(&zitadel, state).await;
+
+		report.log_summary();
+
+		if zitadel.is_journal_broken() {
+			bail!("aborted sync, the audit journal cannot be written");
+		}
+
+		if zitadel.is_unreachable() {
+			bail!("aborted sync, Zitadel is unreachable");
+		}
 
-			if let Err(e) = zitadel.update_users(diff.changed_users).await {
-				warn!("Failed to update users from {}: {:?}", source.get_name(), e);
+		Ok(())
+	}
+
+	/// Validate the changes of each source, against each other and the
+	/// users already in Zitadel, along with the changes of each source
+	/// that were skipped
+	///
+	/// Zitadel's users are only listed if any users are to be imported
+	/// or updated.
+	#[allow(clippy::type_complexity)]
+	async fn validate_diffs<'s>(
+		&self,
+		zitadel: &Zitadel,
+		validator: &Validator<'_>,
+		diffs: Vec<(&'s (dyn Source + Send + Sync), SourceDiff)>,
+	) -> Result<Vec<(&'s (dyn Source + Send + Sync), SourceDiff, Vec<PendingChange>)>> {
+		let existing = if diffs
+			.iter()
+			.any(|(_, diff)| !diff.new_users.is_empty() || !diff.changed_users.is_empty())
+		{
+			zitadel.get_existing_users().await.context("failed to list the existing users")?
+		} else {
+			Vec::new()
+		};
+
+		diffs
+			.into_iter()
+			.map(|(source, diff)| {
+				let (diff, skipped) = validator
+					.validate_diff(source.get_name(), diff, &existing)
+					.context("a user failed validation")?;
+				Ok((source, diff, skipped))
+			})
+			.collect()
+	}
+
+	/// Open the state store, if one is configured
+	///
+	/// The retry queue is encrypted with the keys of the LDAP cache, if
+	/// its encryption is configured, as are the snapshots of the CSV and
+	/// UKT sources.
+	fn open_state(&self) -> Result<Option<Arc<dyn StateStore>>> {
+		let Some(path) = &self.state_path else {
+			return Ok(None);
+		};
+
+		let state: Arc<dyn StateStore> =
+			Arc::new(SqliteStateStore::open(path, self.state_cipher()?)?);
+		Ok(Some(state))
+	}
+
+	/// The cipher encrypting the users' data in the state store, from the
+	/// keys of the LDAP cache if its encryption is configured
+	fn state_cipher(&self) -> Result<Option<CacheCipher>> {
+		match &self.sources.ldap {
+			Some(ldap) => ldap.cache_cipher(),
+			None => Ok(None),
+		}
+	}
+
+	/// The lock file preventing concurrent runs, next to the state store,
+	/// the LDAP cache or else the user ID cache; without any of them,
+	/// runs are not locked
+	fn lock_path(&self) -> Option<PathBuf> {
+		let state_path = self
+			.state_path
+			.as_ref()
+			.or(self.sources.ldap.as_ref().map(|ldap| &ldap.cache_path))
+			.or(self.zitadel.user_id_cache_path.as_ref())?;
+
+		let mut path = state_path.clone().into_os_string();
+		path.push(".lock");
+		Some(path.into())
+	}
+
+	/// Write the changes of a source to Zitadel, logging failures and
+	/// returning the changes that failed
+	async fn apply_source_diff(
+		&self,
+		zitadel: &Zitadel,
+		source_name: &str,
+		diff: SourceDiff,
+	) -> Vec<PendingChange> {
+		let mut failed = Vec::new();
+
+		if !self.feature_flags.is_enabled(FeatureFlag::DeactivateOnly) {
+			failed.extend(import_users(zitadel, source_name, diff.new_users).await);
+			failed.extend(delete_users(zitadel, source_name, diff.deleted_user_ids).await);
+		}
+
+		let result = zitadel.update_users(source_name, diff.changed_users.clone()).await;
+		if let Err(e) = &result {
+			warn!("Failed to update users from {}: {:?}", source_name, e);
+		}
+		failed.extend(
+			failed_changes(diff.changed_users, result, |user| {
+				user.new.external_user_id.to_string()
+			})
+			.map(|user| PendingChange::Update(Box::new(user))),
+		);
+
+		failed
+	}
+
+	/// Mark existing Zitadel users, identified by their login names, as
+	/// managed by the given source, so the sync may update and delete
+	/// them
+	///
+	/// Adopting holds the sync's lock, so it cannot interfere with a run.
+	pub async fn adopt_users(&self, source_name: &str, login_names: &[String]) -> Result<()> {
+		let Some(source) =
+			self.get_sources(None)?.into_iter().find(|source| source.get_name() == source_name)
+		else {
+			bail!("source `{}` is not configured", source_name);
+		};
+
+		let _lock = self.lock_path().map(|path| SyncLock::acquire(&path)).transpose()?;
+		let state = self.open_state()?;
+		let zitadel = Zitadel::new(self, SyncReport::default(), state.as_deref()).await?;
+
+		for login_name in login_names {
+			if let Err(e) = zitadel.adopt_user(source.get_name(), login_name).await {
+				error!("Failed to adopt user `{}`: {:?}", login_name, e);
 			}
 		}
 
+		self.record_journal_head(&zitadel, state.as_ref()).await;
 		Ok(())
 	}
+
+	/// Mark all existing Zitadel users whose nick names match the
+	/// external ID of a user of the given source as managed by the
+	/// source, returning how many users were adopted
+	///
+	/// This migrates deployments from before the sync marked the users
+	/// it manages, which would otherwise refuse to touch any user. Like
+	/// [`Self::adopt_users`], this holds the sync's lock.
+	pub async fn adopt_all_users(&self, source_name: &str) -> Result<usize> {
+		let Some(source) =
+			self.get_sources(None)?.into_iter().find(|source| source.get_name() == source_name)
+		else {
+			bail!("source `{}` is not configured", source_name);
+		};
+
+		let _lock = self.lock_path().map(|path| SyncLock::acquire(&path)).transpose()?;
+
+		let external_ids = source
+			.get_all_users()
+			.await?
+			.into_iter()
+			.map(|user| user.external_user_id.to_string())
+			.collect();
+
+		let state = self.open_state()?;
+		let zitadel = Zitadel::new(self, SyncReport::default(), state.as_deref()).await?;
+		let result = zitadel.adopt_users_by_external_id(source.get_name(), external_ids).await;
+		self.record_journal_head(&zitadel, state.as_ref()).await;
+		result
+	}
+
+	/// Record the head of the audit journal in the state store, if both
+	/// are kept, so that removing records from the end of the journal or
+	/// rewriting it is detected
+	async fn record_journal_head(&self, zitadel: &Zitadel, state: Option<&Arc<dyn StateStore>>) {
+		let Some(state) = state.filter(|_| !self.feature_flags.is_enabled(FeatureFlag::DryRun))
+		else {
+			return;
+		};
+
+		let result = match zitadel.journal_head().await {
+			Ok(Some(head)) => {
+				let mut changes = StateChanges::default();
+				changes.set_journal_head(head);
+				state.commit(changes).await
+			}
+			Ok(None) => Ok(()),
+			Err(e) => Err(e),
+		};
+		if let Err(e) = result {
+			warn!("Failed to record the head of the audit journal: {:?}", e);
+		}
+	}
+
+	/// Check that the audit journal has not been tampered with,
+	/// returning the number of records it holds
+	///
+	/// Records removed from the end of the journal, or a rewritten
+	/// journal, are only detected if a state store is kept, which holds
+	/// the head of the journal as of the last run.
+	pub async fn verify_audit_journal(&self) -> Result<usize> {
+		let Some(path) = &self.zitadel.audit_journal_path else {
+			bail!("no audit journal is configured");
+		};
+
+		let head = match self.open_state()? {
+			Some(state) => state.journal_head().await?,
+			None => {
+				warn!("No state store is configured, only the chain of the journal is checked");
+				None
+			}
+		};
+
+		audit::verify_journal(path, head.as_ref())
+	}
+
+	/// Map the users of sample records of a source like a sync would,
+	/// describing each resulting user as a JSON document
+	///
+	/// Samples for the LDAP source are a JSON array of objects mapping
+	/// attribute names to a value or an array of values, and samples
+	/// for the CSV source are a CSV file.
+	pub async fn test_mapping(&self, source_name: &str, path: &Path) -> Result<Vec<String>> {
+		let users = match (source_name, &self.sources.ldap, &self.sources.csv) {
+			("LDAP", Some(ldap_config), _) => {
+				let records = tokio::fs::read_to_string(path)
+					.await
+					.context(format!("failed to read sample records {}", path.display()))?;
+				LdapSource::new(ldap_config.clone(), true).parse_sample_records(&records)?
+			}
+			("CSV", _, Some(csv_config)) => {
+				CsvSource::new(CsvSourceConfig { file_path: path.to_owned(), ..csv_config.clone() })
+					.get_all_users()
+					.await?
+			}
+			_ => bail!("source `{}` is not configured or cannot be tested", source_name),
+		};
+
+		let users = Transformer::new(&self.transformations)?.transform_users(users);
+		let script = self
+			.script
+			.as_ref()
+			.map(|config| Script::new(config, SyncReport::default()))
+			.transpose()?;
+		let users = match script {
+			Some(script) => script.map_users(users)?,
+			None => users,
+		};
+		let users = match &self.phone {
+			Some(config) => PhoneNormalizer::new(config, SyncReport::default())?
+				.normalize_users(source_name, users),
+			None => users,
+		};
+		let users = localpart::assign(&self.zitadel.localpart, users)?;
+
+		Ok(users.iter().map(|user| user.describe(&self.zitadel)).collect())
+	}
+
+	/// Inspect or edit the LDAP sync cache, describing the outcome as
+	/// JSON documents
+	///
+	/// Edits hold the sync's lock, so they cannot interfere with a run.
+	pub async fn run_cache_command(&self, command: &CacheCommand) -> Result<Vec<String>> {
+		let Some(ldap_config) = &self.sources.ldap else {
+			bail!("the LDAP source is not configured");
+		};
+
+		let _lock = match command {
+			CacheCommand::Remove { .. } => {
+				self.lock_path().map(|path| SyncLock::acquire(&path)).transpose()?
+			}
+			_ => None,
+		};
+
+		let ldap = LdapSource::new(ldap_config.clone(), false);
+		let ldap = match self.open_state()? {
+			Some(state) => ldap.with_state(state),
+			None => ldap,
+		};
+		ldap.run_cache_command(command).await
+	}
+
+	/// Get all configured sources, keeping their state in the given state
+	/// store if any
+	fn get_sources(
+		&self,
+		state: Option<&Arc<dyn StateStore>>,
+	) -> Result<Vec<Box<dyn Source + Send + Sync>>> {
+		let mut sources: Vec<Box<dyn Source + Send + Sync>> = Vec::new();
+
+		if let Some(ldap_config) = &self.sources.ldap {
+			// Transformations are applied after changes are detected, so
+			// the attributes they use must be tracked for changes too
+			let mut ldap_config = ldap_config.clone();
+			for attribute in self.transformations.referenced_attributes() {
+				if !ldap_config.attributes.additional.contains(&attribute) {
+					ldap_config.attributes.additional.push(attribute);
+				}
+			}
+
+			let ldap =
+				LdapSource::new(ldap_config, self.feature_flags.is_enabled(FeatureFlag::DryRun));
+			let ldap = match state {
+				Some(state) => ldap.with_state(state.clone()),
+				None => ldap,
+			};
+			sources.push(Box::new(ldap));
+		}
+
+		if let Some(ukt_config) = &self.sources.ukt {
+			let ukt = UktSource::new(ukt_config.clone());
+			let ukt = match state {
+				Some(state) => ukt.with_state(state.clone(), self.state_cipher()?),
+				None => ukt,
+			};
+			sources.push(Box::new(ukt));
+		}
+
+		if let Some(csv_config) = &self.sources.csv {
+			let csv = CsvSource::new(csv_config.clone());
+			let csv = match state {
+				Some(state) => csv.with_state(state.clone(), self.state_cipher()?),
+				None => csv,
+			};
+			sources.push(Box::new(csv));
+		}
+
+		Ok(sources)
+	}
+
+	/// Get the changes to apply for a source, either incrementally or by
+	/// reconciling all of the source's users against Zitadel, with the
+	/// configured transformations, mapping script and phone number
+	/// normalisation applied to the source's users
+	///
+	/// Incremental changes include the given retried changes the mapping
+	/// script failed to map before. Along with the changes, this returns
+	/// how often those were attempted, and the changes of the users the
+	/// script fails to map, to be retried next run.
+	///
+	/// The Matrix localparts of the users to import are resolved before
+	/// anything is written, failing on collisions with each other or
+	/// with the localparts already taken in Zitadel unless they are
+	/// configured to be resolved.
+	async fn get_source_diff(
+		&self,
+		source: &(dyn Source + Send + Sync),
+		zitadel: &Zitadel,
+		transformer: &Transformer,
+		script: Option<&Script>,
+		phone: Option<&PhoneNormalizer>,
+		unmapped: Vec<QueuedChange>,
+	) -> Result<(SourceDiff, HashMap<String, u32>, Vec<PendingChange>)> {
+		if self.feature_flags.is_enabled(FeatureFlag::FullReconcile) {
+			let users = transformer.transform_users(source.get_all_users().await?);
+			let users = match script {
+				Some(script) => script.map_users(users)?,
+				None => users,
+			};
+			let users = match phone {
+				Some(phone) => phone.normalize_users(source.get_name(), users),
+				None => users,
+			};
+			let diff = zitadel
+				.get_reconcile_diff(source.get_name(), source.metadata_keys(), users)
+				.await?;
+			// All users are mapped again, so earlier mapping failures need
+			// no retry
+			Ok((self.assign_localparts(zitadel, diff).await?, HashMap::new(), Vec::new()))
+		} else {
+			let mut diff = transformer.transform_diff(source.get_diff().await?);
+			let attempts = PendingChange::add_to_diff(unmapped, &mut diff);
+			let (diff, skipped) = match script {
+				Some(script) => script.map_diff(source.get_name(), diff),
+				None => (diff, Vec::new()),
+			};
+			let diff = match phone {
+				Some(phone) => phone.normalize_diff(source.get_name(), diff),
+				None => diff,
+			};
+			Ok((self.assign_localparts(zitadel, diff).await?, attempts, skipped))
+		}
+	}
+
+	/// Assign localparts to the new users of a diff, checking them
+	/// against the localparts already taken in Zitadel if any users are
+	/// to be imported
+	///
+	/// UUIDs are derived from the unique external IDs, so they are not
+	/// checked.
+	async fn assign_localparts(&self, zitadel: &Zitadel, diff: SourceDiff) -> Result<SourceDiff> {
+		let existing = if self.zitadel.localpart.strategy != LocalpartStrategy::Uuid
+			&& diff.new_users.iter().any(|user| user.enabled)
+		{
+			zitadel.get_localparts().await?
+		} else {
+			HashMap::new()
+		};
+
+		localpart::assign_diff(&self.zitadel.localpart, diff, &existing)
+	}
 }
 
 /// Opt-in features
@@ -149,6 +713,9 @@ pub enum FeatureFlag {
 	DryRun,
 	/// If only deactivated users should be synced
 	DeactivateOnly,
+	/// If all users of the source should be compared against Zitadel
+	/// directly, instead of relying on cached source state
+	FullReconcile,
 }
 
 #[derive(Debug, Clone, Deserialize, PartialEq, Default)]
@@ -175,6 +742,50 @@ impl FeatureFlags {
 	}
 }
 
+/// Import new users of a source, returning the imports that failed
+async fn import_users(
+	zitadel: &Zitadel,
+	source_name: &str,
+	users: Vec<User>,
+) -> Vec<PendingChange> {
+	let result = zitadel.import_new_users(source_name, users.clone()).await;
+	if let Err(e) = &result {
+		warn!("Failed to import new users from {}: {:?}", source_name, e);
+	}
+	failed_changes(users, result, |user| user.external_user_id.to_string())
+		.map(|user| PendingChange::Import(Box::new(user)))
+		.collect()
+}
+
+/// Delete users removed from a source, returning the deletions that
+/// failed
+async fn delete_users(
+	zitadel: &Zitadel,
+	source_name: &str,
+	user_ids: Vec<UserId>,
+) -> Vec<PendingChange> {
+	let result = zitadel.delete_users_by_id(user_ids.clone()).await;
+	if let Err(e) = &result {
+		warn!("Failed to delete users from {}: {:?}", source_name, e);
+	}
+	failed_changes(user_ids, result, |user_id| user_id.key().to_owned())
+		.map(PendingChange::Delete)
+		.collect()
+}
+
+/// The changes whose users failed to be written to Zitadel, given the
+/// keys of the users that failed, or all changes if the whole batch did
+fn failed_changes<T>(
+	changes: Vec<T>,
+	failed_users: Result<Vec<String>>,
+	key: impl Fn(&T) -> String,
+) -> impl Iterator<Item = T> {
+	let failed_users = failed_users.ok();
+	changes.into_iter().filter(move |change| {
+		failed_users.as_ref().map_or(true, |failed_users| failed_users.contains(&key(change)))
+	})
+}
+
 /// Validate the Zitadel URL provided by Famedly
 fn validate_zitadel_url(url: Url) -> Result<Url> {
 	// If a URL contains a port, the domain name may appear as a
@@ -190,7 +801,7 @@ fn validate_zitadel_url(url: Url) -> Result<Url> {
 // RUST_TEST_THREADS=1 cargo test --lib
 #[cfg(test)]
 mod tests {
-	use std::{env, fs::File, io::Write, path::PathBuf};
+	use std::{collections::BTreeMap, env, fs::File, io::Write, path::PathBuf};
 
 	use indoc::indoc;
 	use tempfile::TempDir;
@@ -297,6 +908,17 @@ mod tests {
 		assert!(validate_zitadel_url(url).is_err());
 	}
 
+	#[test]
+	fn test_reserved_metadata_key() {
+		let mut config = load_config();
+		config.sources.csv = Some(CsvSourceConfig {
+			file_path: PathBuf::from("users.csv"),
+			metadata: BTreeMap::from([("localpart".to_owned(), "uid".to_owned())]),
+		});
+
+		assert!(config.validate().is_err());
+	}
+
 	#[tokio::test]
 	async fn test_sample_config() {
 		let config = Config::new(Path::new("./config.sample.yaml"));
@@ -304,6 +926,22 @@ mod tests {
 		assert!(config.is_ok(), "Invalid config: {:?}", config);
 	}
 
+	#[test]
+	fn test_state_store_cache_check() {
+		let tempdir = TempDir::new().expect("failed to initialize cache dir");
+		let cache_path = tempdir.path().join("famedly-sync.cache");
+		std::fs::write(&cache_path, b"FLSCRYPT").expect("failed to write cache");
+
+		let mut config =
+			Config::new(Path::new("./config.sample.yaml")).expect("invalid sample config");
+		config.sources.ldap.as_mut().expect("the sample config has an LDAP source").cache_path =
+			cache_path;
+		assert!(config.clone().validate().is_err());
+
+		config.state_path = Some(tempdir.path().join("state.db"));
+		assert!(config.validate().is_ok());
+	}
+
 	#[test]
 	fn test_config_from_file() {
 		let tempdir = TempDir::new().expect("failed to initialize cache dir");
@@ -0,0 +1,147 @@
+//! Configuration and evaluation of the project grants given to users
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::Deserialize;
+
+use crate::user::{AttributeCondition, User};
+
+/// The roles granted to a user, keyed by project ID
+pub(crate) type Grants = BTreeMap<String, BTreeSet<String>>;
+
+/// Roles to grant to users in a Zitadel project
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct GrantConfig {
+	/// The project to grant the roles in
+	pub project_id: String,
+	/// The role keys to grant
+	pub roles: Vec<String>,
+	/// Only grant the roles to users matching this condition
+	pub condition: Option<AttributeCondition>,
+}
+
+/// Compute the roles a user should be granted, keyed by project ID
+///
+/// Every configured project is present in the result, with an empty
+/// set of roles if the user should not be granted anything in it, so
+/// that grants which no longer apply can be removed. The user's extra
+/// grants are added on top.
+pub(crate) fn desired_grants(grant_configs: &[GrantConfig], user: &User) -> Grants {
+	let mut grants = Grants::new();
+
+	for grant_config in grant_configs {
+		let roles = grants.entry(grant_config.project_id.clone()).or_default();
+
+		if grant_config.condition.as_ref().map_or(true, |condition| condition.matches(user)) {
+			roles.extend(grant_config.roles.iter().cloned());
+		}
+	}
+
+	for (project_id, roles) in &user.extra_grants {
+		grants.entry(project_id.clone()).or_default().extend(roles.iter().cloned());
+	}
+
+	grants
+}
+
+/// Whether the current grants of a user match the desired ones in all
+/// of the desired projects
+pub(crate) fn grants_in_sync(current: &Grants, desired: &Grants) -> bool {
+	desired.iter().all(|(project_id, roles)| {
+		current.get(project_id).map_or(roles.is_empty(), |current_roles| current_roles == roles)
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::user::test_helpers::{attributes, user};
+
+	fn grant_configs() -> Vec<GrantConfig> {
+		vec![
+			GrantConfig {
+				project_id: "messenger".to_owned(),
+				roles: vec!["User".to_owned()],
+				condition: None,
+			},
+			GrantConfig {
+				project_id: "admin".to_owned(),
+				roles: vec!["Admin".to_owned()],
+				condition: Some(AttributeCondition {
+					attribute: "memberOf".to_owned(),
+					value: "cn=admins,dc=example,dc=org".to_owned(),
+				}),
+			},
+			GrantConfig {
+				project_id: "messenger".to_owned(),
+				roles: vec!["Moderator".to_owned()],
+				condition: Some(AttributeCondition {
+					attribute: "memberOf".to_owned(),
+					value: "cn=moderators,dc=example,dc=org".to_owned(),
+				}),
+			},
+		]
+	}
+
+	#[test]
+	fn test_desired_grants_unconditional() {
+		let grants = desired_grants(&grant_configs(), &user("test", "test@example.com"));
+
+		assert_eq!(grants["messenger"], BTreeSet::from(["User".to_owned()]));
+		assert!(grants["admin"].is_empty());
+	}
+
+	#[test]
+	fn test_desired_grants_conditional() {
+		let grants = desired_grants(
+			&grant_configs(),
+			&User {
+				attributes: attributes(&[
+					("memberOf", "CN=Admins,DC=example,DC=org"),
+					("memberOf", "cn=moderators,dc=example,dc=org"),
+				]),
+				..user("test", "test@example.com")
+			},
+		);
+
+		assert_eq!(
+			grants["messenger"],
+			BTreeSet::from(["User".to_owned(), "Moderator".to_owned()])
+		);
+		assert_eq!(grants["admin"], BTreeSet::from(["Admin".to_owned()]));
+	}
+
+	#[test]
+	fn test_desired_grants_extra() {
+		let mut user = user("test", "test@example.com");
+		user.extra_grants = Grants::from([
+			("messenger".to_owned(), BTreeSet::from(["Moderator".to_owned()])),
+			("other".to_owned(), BTreeSet::from(["Viewer".to_owned()])),
+		]);
+
+		let grants = desired_grants(&grant_configs(), &user);
+
+		assert_eq!(
+			grants["messenger"],
+			BTreeSet::from(["User".to_owned(), "Moderator".to_owned()])
+		);
+		assert_eq!(grants["other"], BTreeSet::from(["Viewer".to_owned()]));
+	}
+
+	#[test]
+	fn test_grants_in_sync() {
+		let desired = desired_grants(&grant_configs(), &user("test", "test@example.com"));
+
+		let mut current = Grants::from([
+			("messenger".to_owned(), BTreeSet::from(["User".to_owned()])),
+			("unrelated".to_owned(), BTreeSet::from(["Whatever".to_owned()])),
+		]);
+		assert!(grants_in_sync(&current, &desired));
+
+		current.insert("admin".to_owned(), BTreeSet::from(["Admin".to_owned()]));
+		assert!(!grants_in_sync(&current, &desired));
+
+		current.remove("admin");
+		current.remove("messenger");
+		assert!(!grants_in_sync(&current, &desired));
+	}
+}
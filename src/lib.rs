@@ -1,12 +1,39 @@
 //! Sync tool between other sources and our infrastructure based on Zitadel.
 
+mod audit;
+mod circuit_breaker;
+mod concurrency;
 mod config;
+mod grants;
+mod localpart;
+mod lock;
+mod organizations;
+mod phone;
+mod rate_limit;
+mod reconcile;
+mod report;
+mod script;
 mod sources;
+mod state;
+mod transform;
 mod user;
+mod user_ids;
+mod validation;
 mod zitadel;
 
 pub use config::{Config, FeatureFlag};
+pub use grants::GrantConfig;
+pub use localpart::{CollisionPolicy, LocalpartConfig, LocalpartStrategy};
+pub use organizations::OrganizationRule;
+pub use phone::PhoneConfig;
+pub use rate_limit::RateLimitConfig;
+pub use script::ScriptConfig;
 pub use sources::{
-	csv::test_helpers as csv_test_helpers, ldap::AttributeMapping,
+	csv::test_helpers as csv_test_helpers,
+	ldap::{AttributeMapping, CacheCommand, CacheEncryptionConfig, CacheKey},
 	ukt::test_helpers as ukt_test_helpers,
 };
+pub use transform::{Transformation, TransformationsConfig};
+pub use user::AttributeCondition;
+pub use validation::{ValidationConfig, ValidationPolicy};
+pub use zitadel::{ImportConflictPolicy, LoginNameSource};
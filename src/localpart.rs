@@ -0,0 +1,320 @@
+//! Derivation of the Matrix localparts of users
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{bail, Result};
+use itertools::Itertools;
+use serde::Deserialize;
+use uuid::{uuid, Uuid};
+
+use crate::{
+	user::{StringOrBytes, User},
+	zitadel::SourceDiff,
+};
+
+/// The Famedly UUID namespace to use to generate v5 UUIDs.
+const FAMEDLY_NAMESPACE: Uuid = uuid!("d9979cff-abee-4666-bc88-1ec45a843fb8");
+
+/// How to derive the Matrix localparts of users
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+pub struct LocalpartConfig {
+	/// Where localparts are derived from
+	#[serde(default)]
+	pub strategy: LocalpartStrategy,
+	/// What to do if several users would get the same localpart
+	#[serde(default)]
+	pub on_collision: CollisionPolicy,
+}
+
+/// Where localparts are derived from
+///
+/// Anything but a UUID is sanitised to the Matrix user ID grammar;
+/// users for whom this yields an empty localpart get a UUID instead.
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LocalpartStrategy {
+	/// A UUIDv5 of the external user ID
+	#[default]
+	Uuid,
+	/// The external user ID, e.g. the LDAP `uid`
+	ExternalUserId,
+	/// The part of the email address before the `@`
+	EmailLocalpart,
+	/// The preferred username
+	PreferredUsername,
+	/// The first value of the named source attribute
+	Attribute(String),
+}
+
+/// What to do if several users would get the same localpart
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CollisionPolicy {
+	/// Refuse to sync the source
+	#[default]
+	Fail,
+	/// Append a suffix derived from the external user ID to the
+	/// localparts of all colliding users
+	Suffix,
+}
+
+/// The UUIDv5 of a user's external ID
+fn uuid(user: &User) -> Uuid {
+	let id = match &user.external_user_id {
+		StringOrBytes::String(value) => value.as_bytes(),
+		StringOrBytes::Bytes(value) => value,
+	};
+
+	Uuid::new_v5(&FAMEDLY_NAMESPACE, id)
+}
+
+/// Derive the localpart of a single user, without regard for other
+/// users
+pub(crate) fn derive(strategy: &LocalpartStrategy, user: &User) -> String {
+	let value = match strategy {
+		LocalpartStrategy::Uuid => return uuid(user).to_string(),
+		LocalpartStrategy::ExternalUserId => Some(user.external_user_id.to_string()),
+		LocalpartStrategy::EmailLocalpart => {
+			user.email.to_string().split('@').next().map(str::to_owned)
+		}
+		LocalpartStrategy::PreferredUsername => Some(user.preferred_username.to_string()),
+		LocalpartStrategy::Attribute(attribute) => {
+			user.attribute_values(attribute).first().map(ToString::to_string)
+		}
+	};
+
+	match value.as_deref().map(sanitize) {
+		Some(localpart) if !localpart.is_empty() => localpart,
+		_ => uuid(user).to_string(),
+	}
+}
+
+/// Sanitise a value to the Matrix user ID grammar, lowercasing it and
+/// replacing other characters with `_`
+fn sanitize(value: &str) -> String {
+	value
+		.trim()
+		.to_lowercase()
+		.chars()
+		.map(|character| match character {
+			'a'..='z' | '0'..='9' | '.' | '_' | '=' | '-' | '/' => character,
+			_ => '_',
+		})
+		.collect()
+}
+
+/// Resolve the localparts of a set of users to import, checking for
+/// collisions with each other and with the given localparts already
+/// taken, mapped to the external IDs of their holders, before anything
+/// is written
+///
+/// Returns the localparts keyed by external user ID. Disabled users
+/// are not imported, so they are skipped.
+fn resolve<'a>(
+	config: &LocalpartConfig,
+	users: impl Iterator<Item = &'a User>,
+	existing: &HashMap<String, String>,
+) -> Result<HashMap<String, String>> {
+	let users: Vec<&User> = users.filter(|user| user.enabled).collect();
+	let mut localparts: HashMap<String, String> = users
+		.iter()
+		.map(|user| (user.external_user_id.to_string(), derive(&config.strategy, user)))
+		.collect();
+
+	let collisions = find_collisions(&localparts, existing);
+	if collisions.is_empty() {
+		return Ok(localparts);
+	}
+
+	if config.on_collision == CollisionPolicy::Fail {
+		bail!("users would get the same Matrix localpart: {}", describe(&collisions));
+	}
+
+	for user in users {
+		let external_user_id = user.external_user_id.to_string();
+		if let Some(localpart) = localparts.get_mut(&external_user_id) {
+			if collisions.contains_key(localpart.as_str()) {
+				let suffix = uuid(user).simple().to_string();
+				*localpart = format!("{localpart}-{}", &suffix[..8]);
+			}
+		}
+	}
+
+	let collisions = find_collisions(&localparts, existing);
+	if !collisions.is_empty() {
+		bail!("users would still get the same Matrix localpart: {}", describe(&collisions));
+	}
+
+	Ok(localparts)
+}
+
+/// Find the localparts shared by several users, including the holders
+/// of localparts already taken, along with the sorted external IDs of
+/// those users
+fn find_collisions(
+	localparts: &HashMap<String, String>,
+	existing: &HashMap<String, String>,
+) -> BTreeMap<String, Vec<String>> {
+	let holders = localparts.values().filter_map(|localpart| {
+		existing.get(localpart).map(|holder| (localpart.clone(), holder.clone()))
+	});
+
+	localparts
+		.iter()
+		.map(|(external_user_id, localpart)| (localpart.clone(), external_user_id.clone()))
+		.chain(holders)
+		.into_group_map()
+		.into_iter()
+		.map(|(localpart, external_user_ids)| {
+			(localpart, external_user_ids.into_iter().sorted().dedup().collect::<Vec<_>>())
+		})
+		.filter(|(_, external_user_ids)| external_user_ids.len() > 1)
+		.collect()
+}
+
+/// Describe collisions for an error message
+fn describe(collisions: &BTreeMap<String, Vec<String>>) -> String {
+	collisions
+		.iter()
+		.map(|(localpart, external_user_ids)| {
+			format!("`{localpart}` ({})", external_user_ids.join(", "))
+		})
+		.join("; ")
+}
+
+/// Assign localparts to a list of users, checking them against each
+/// other only
+pub(crate) fn assign(config: &LocalpartConfig, mut users: Vec<User>) -> Result<Vec<User>> {
+	let mut localparts = resolve(config, users.iter(), &HashMap::new())?;
+
+	for user in &mut users {
+		user.localpart = localparts.remove(&user.external_user_id.to_string());
+	}
+
+	Ok(users)
+}
+
+/// Assign localparts to the new users of a diff, checking them against
+/// each other and the given localparts already taken
+///
+/// Localparts are only written when users are imported, so changed
+/// users keep theirs.
+pub(crate) fn assign_diff(
+	config: &LocalpartConfig,
+	mut diff: SourceDiff,
+	existing: &HashMap<String, String>,
+) -> Result<SourceDiff> {
+	let mut localparts = resolve(config, diff.new_users.iter(), existing)?;
+
+	for user in &mut diff.new_users {
+		user.localpart = localparts.remove(&user.external_user_id.to_string());
+	}
+
+	Ok(diff)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::HashMap;
+
+	use super::*;
+	use crate::user::test_helpers::{attributes, user};
+
+	#[test]
+	fn test_derive_localpart() {
+		let user = User {
+			preferred_username: "Test User".to_owned().into(),
+			attributes: attributes(&[("sAMAccountName", "T.User")]),
+			..user("test", "Test.User+sync@example.com")
+		};
+
+		assert_eq!(
+			derive(&LocalpartStrategy::Uuid, &user),
+			Uuid::new_v5(&FAMEDLY_NAMESPACE, b"test").to_string()
+		);
+		assert_eq!(derive(&LocalpartStrategy::ExternalUserId, &user), "test");
+		assert_eq!(derive(&LocalpartStrategy::EmailLocalpart, &user), "test.user_sync");
+		assert_eq!(derive(&LocalpartStrategy::PreferredUsername, &user), "test_user");
+		assert_eq!(
+			derive(&LocalpartStrategy::Attribute("sAMAccountName".to_owned()), &user),
+			"t.user"
+		);
+
+		// Fall back to a UUID if nothing is left
+		assert_eq!(
+			derive(&LocalpartStrategy::Attribute("missing".to_owned()), &user),
+			Uuid::new_v5(&FAMEDLY_NAMESPACE, b"test").to_string()
+		);
+	}
+
+	#[test]
+	fn test_localpart_collision_fail() {
+		let config = LocalpartConfig {
+			strategy: LocalpartStrategy::EmailLocalpart,
+			on_collision: CollisionPolicy::Fail,
+		};
+
+		let users = vec![user("alice", "alice@example.com"), user("bob", "bob@example.com")];
+		let users = assign(&config, users).expect("no collision expected");
+		assert_eq!(users[0].localpart.as_deref(), Some("alice"));
+		assert_eq!(users[1].localpart.as_deref(), Some("bob"));
+
+		let users = vec![user("alice", "alice@example.com"), user("alice2", "Alice@example.org")];
+		assert!(assign(&config, users).is_err());
+
+		// Disabled users are not imported and cannot collide
+		let mut disabled = user("alice2", "alice@example.org");
+		disabled.enabled = false;
+		assert!(assign(&config, vec![user("alice", "alice@example.com"), disabled]).is_ok());
+	}
+
+	#[test]
+	fn test_localpart_collision_suffix() {
+		let config = LocalpartConfig {
+			strategy: LocalpartStrategy::EmailLocalpart,
+			on_collision: CollisionPolicy::Suffix,
+		};
+
+		let users = vec![
+			user("alice", "alice@example.com"),
+			user("alice2", "alice@example.org"),
+			user("bob", "bob@example.com"),
+		];
+		let users = assign(&config, users).expect("collisions should be resolved");
+
+		let suffix = |id: &str| {
+			Uuid::new_v5(&FAMEDLY_NAMESPACE, id.as_bytes()).simple().to_string()[..8].to_owned()
+		};
+		assert_eq!(users[0].localpart, Some(format!("alice-{}", suffix("alice"))));
+		assert_eq!(users[1].localpart, Some(format!("alice-{}", suffix("alice2"))));
+		assert_eq!(users[2].localpart.as_deref(), Some("bob"));
+	}
+
+	#[test]
+	fn test_localpart_collision_existing() {
+		let config = LocalpartConfig {
+			strategy: LocalpartStrategy::EmailLocalpart,
+			on_collision: CollisionPolicy::Fail,
+		};
+		let existing = HashMap::from([("alice".to_owned(), "alice".to_owned())]);
+		let diff = |users| SourceDiff {
+			new_users: users,
+			changed_users: vec![],
+			deleted_user_ids: vec![],
+		};
+
+		// A user keeps a localpart they already hold
+		let assigned =
+			assign_diff(&config, diff(vec![user("alice", "alice@example.com")]), &existing)
+				.expect("no collision expected");
+		assert_eq!(assigned.new_users[0].localpart.as_deref(), Some("alice"));
+
+		let new = diff(vec![user("alice2", "alice@example.org")]);
+		assert!(assign_diff(&config, new, &existing).is_err());
+
+		let config = LocalpartConfig { on_collision: CollisionPolicy::Suffix, ..config };
+		let new = diff(vec![user("alice2", "alice@example.org")]);
+		let assigned = assign_diff(&config, new, &existing).expect("collision should be resolved");
+		let suffix = Uuid::new_v5(&FAMEDLY_NAMESPACE, b"alice2").simple().to_string();
+		assert_eq!(assigned.new_users[0].localpart, Some(format!("alice-{}", &suffix[..8])));
+	}
+}
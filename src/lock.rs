@@ -0,0 +1,143 @@
+//! Lock file preventing concurrent sync runs on the same state
+use std::{
+	fs::{File, OpenOptions, TryLockError},
+	io::{Seek, Write},
+	path::Path,
+	time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// Who holds a lock, as written to the lock file for information
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct LockOwner {
+	/// The process ID of the run holding the lock
+	pid: u32,
+	/// The host the run holding the lock runs on
+	host: String,
+	/// When the lock was acquired, in seconds since the Unix epoch
+	acquired_at: u64,
+}
+
+impl LockOwner {
+	/// Describe the current process
+	fn current() -> Self {
+		let acquired_at =
+			SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+		Self { pid: std::process::id(), host: hostname(), acquired_at }
+	}
+}
+
+/// An exclusive lock on the state of the sync, released when dropped
+///
+/// The lock is an advisory lock of the operating system on the lock
+/// file, so it is released when the run holding it ends in any way.
+/// The file itself is left in place, as removing it would let two runs
+/// lock different files.
+#[derive(Debug)]
+pub(crate) struct SyncLock {
+	/// The locked lock file
+	file: File,
+}
+
+impl SyncLock {
+	/// Acquire the lock, failing if another run holds it
+	pub(crate) fn acquire(path: &Path) -> Result<Self> {
+		let mut file = OpenOptions::new()
+			.read(true)
+			.write(true)
+			.create(true)
+			.truncate(false)
+			.open(path)
+			.context(format!("failed to open lock file {}", path.display()))?;
+
+		match file.try_lock() {
+			Ok(()) => {}
+			Err(TryLockError::WouldBlock) => {
+				let holder = std::fs::read(path)
+					.ok()
+					.and_then(|data| serde_json::from_slice::<LockOwner>(&data).ok());
+				match holder {
+					Some(holder) => bail!(
+						"another sync run (PID {} on host `{}`) holds the lock {}",
+						holder.pid,
+						holder.host,
+						path.display()
+					),
+					None => bail!("another sync run holds the lock {}", path.display()),
+				}
+			}
+			Err(TryLockError::Error(error)) => {
+				return Err(error).context(format!("failed to lock {}", path.display()));
+			}
+		}
+
+		let owner = serde_json::to_vec(&LockOwner::current())?;
+		file.set_len(0)
+			.and_then(|()| file.rewind())
+			.and_then(|()| file.write_all(&owner))
+			.context(format!("failed to write lock file {}", path.display()))?;
+
+		Ok(Self { file })
+	}
+}
+
+impl Drop for SyncLock {
+	fn drop(&mut self) {
+		// The lock itself is released along with the file
+		if let Err(error) = self.file.set_len(0) {
+			tracing::warn!("Failed to clear lock file: {:?}", error);
+		}
+	}
+}
+
+/// The name of the host the sync runs on
+fn hostname() -> String {
+	std::env::var("HOSTNAME")
+		.ok()
+		.or_else(|| std::fs::read_to_string("/proc/sys/kernel/hostname").ok())
+		.map(|host| host.trim().to_owned())
+		.filter(|host| !host.is_empty())
+		.unwrap_or_else(|| "unknown".to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+	use tempfile::TempDir;
+
+	use super::*;
+
+	#[test]
+	fn test_lock_exclusive() {
+		let tempdir = TempDir::new().expect("failed to create temp dir");
+		let path = tempdir.path().join("sync.lock");
+
+		let lock = SyncLock::acquire(&path).expect("failed to acquire lock");
+		let owner: LockOwner =
+			serde_json::from_slice(&std::fs::read(&path).expect("failed to read lock"))
+				.expect("lock must describe its owner");
+		assert_eq!(owner.pid, std::process::id());
+
+		let error = SyncLock::acquire(&path).expect_err("lock must be exclusive");
+		assert!(error.to_string().contains(&format!("PID {}", owner.pid)));
+
+		drop(lock);
+		SyncLock::acquire(&path).expect("failed to acquire released lock");
+	}
+
+	#[test]
+	fn test_lock_left_behind() {
+		let tempdir = TempDir::new().expect("failed to create temp dir");
+		let path = tempdir.path().join("sync.lock");
+
+		// A file left behind by a run that is gone is not locked
+		let owner = LockOwner { host: "elsewhere".to_owned(), ..LockOwner::current() };
+		std::fs::write(&path, serde_json::to_vec(&owner).expect("failed to serialize owner"))
+			.expect("failed to write lock");
+		drop(SyncLock::acquire(&path).expect("failed to acquire left behind lock"));
+
+		std::fs::write(&path, "garbage").expect("failed to write lock");
+		SyncLock::acquire(&path).expect("failed to acquire unreadable lock");
+	}
+}
@@ -1,13 +1,17 @@
 //! Tool for syncing different sources to Famedly's Zitadel
-use std::{path::Path, process::ExitCode, str::FromStr};
+use std::{
+	path::{Path, PathBuf},
+	process::ExitCode,
+	str::FromStr,
+};
 
-use anyhow::{Context, Result};
-use ldap_sync::Config;
+use anyhow::{bail, Context, Result};
+use ldap_sync::{CacheCommand, Config};
 use tracing::level_filters::LevelFilter;
 
 #[tokio::main]
 async fn main() -> ExitCode {
-	match run_sync().await {
+	match run().await {
 		Ok(_) => ExitCode::SUCCESS,
 		Err(e) => {
 			tracing::error!("{:?}", e);
@@ -16,9 +20,104 @@ async fn main() -> ExitCode {
 	}
 }
 
+/// The commands supported by the tool
+enum Command {
+	/// Perform a sync, the default if no command is given
+	Sync,
+	/// Mark existing Zitadel users as managed by a source
+	Adopt {
+		/// The name of the source to adopt the users for, e.g. `LDAP`
+		source: String,
+		/// The login names of the users to adopt
+		login_names: Vec<String>,
+	},
+	/// Mark all existing Zitadel users known to a source as managed by it
+	AdoptAll {
+		/// The name of the source to adopt the users for, e.g. `LDAP`
+		source: String,
+	},
+	/// Map sample records of a source and print the resulting users
+	TestMapping {
+		/// The name of the source the records are from, e.g. `LDAP`
+		source: String,
+		/// The file containing the sample records
+		path: PathBuf,
+	},
+	/// Inspect or edit the LDAP sync cache
+	Cache(CacheCommand),
+	/// Check that the audit journal has not been tampered with
+	VerifyJournal,
+}
+
+impl Command {
+	/// Parse the command from the command line arguments
+	fn from_args(mut args: impl Iterator<Item = String>) -> Result<Self> {
+		match args.next().as_deref() {
+			None | Some("sync") => Ok(Self::Sync),
+			Some("adopt") => {
+				let Some(source) = args.next() else {
+					bail!("Usage: adopt <source> <login name>...");
+				};
+				let login_names: Vec<String> = args.collect();
+				if login_names.is_empty() {
+					bail!("Usage: adopt <source> <login name>...");
+				}
+				Ok(Self::Adopt { source, login_names })
+			}
+			Some("adopt-all") => {
+				let (Some(source), None) = (args.next(), args.next()) else {
+					bail!("Usage: adopt-all <source>");
+				};
+				Ok(Self::AdoptAll { source })
+			}
+			Some("test-mapping") => {
+				let (Some(source), Some(path), None) = (args.next(), args.next(), args.next())
+				else {
+					bail!("Usage: test-mapping <source> <sample records file>");
+				};
+				Ok(Self::TestMapping { source, path: path.into() })
+			}
+			Some("cache") => {
+				let command = match (args.next().as_deref(), args.next(), args.next(), args.next())
+				{
+					(Some("dump"), None, None, None) => CacheCommand::Dump,
+					(Some("show"), Some(query), None, None) => CacheCommand::Show(query),
+					(Some("remove"), Some(query), None, None) => {
+						CacheCommand::Remove { query, all: false }
+					}
+					(Some("remove"), Some(query), Some(flag), None) if flag == "--all" => {
+						CacheCommand::Remove { query, all: true }
+					}
+					(Some("stats"), None, None, None) => CacheCommand::Stats,
+					_ => bail!(
+						"Usage: cache dump | cache show <ID or DN> | cache remove <ID or DN> \
+						 [--all] | cache stats"
+					),
+				};
+				Ok(Self::Cache(command))
+			}
+			Some("verify-journal") => {
+				if args.next().is_some() {
+					bail!("Usage: verify-journal");
+				}
+				Ok(Self::VerifyJournal)
+			}
+			Some(command) => bail!("Unknown command `{}`", command),
+		}
+	}
+}
+
 /// Simple entrypoint without any bells or whistles
-#[allow(clippy::print_stderr)]
-async fn run_sync() -> Result<()> {
+#[allow(clippy::print_stderr, clippy::print_stdout)]
+async fn run() -> Result<()> {
+	let command = match Command::from_args(std::env::args().skip(1)) {
+		Ok(command) => command,
+		Err(error) => {
+			eprintln!("{}", error);
+			bail!(error);
+		}
+	};
+
 	let config = {
 		let config_path = std::env::var("FAMEDLY_LDAP_SYNC_CONFIG").unwrap_or("config.yaml".into());
 		let config_path = Path::new(&config_path);
@@ -44,5 +143,30 @@ async fn run_sync() -> Result<()> {
 	tracing::subscriber::set_global_default(subscriber)
 		.context("Setting default tracing subscriber failed")?;
 
-	config.perform_sync().await
+	match command {
+		Command::Sync => config.perform_sync().await,
+		Command::Adopt { source, login_names } => config.adopt_users(&source, &login_names).await,
+		Command::AdoptAll { source } => {
+			let adopted = config.adopt_all_users(&source).await?;
+			println!("Adopted {adopted} users for source {source}");
+			Ok(())
+		}
+		Command::TestMapping { source, path } => {
+			for user in config.test_mapping(&source, &path).await? {
+				println!("{user}");
+			}
+			Ok(())
+		}
+		Command::Cache(command) => {
+			for output in config.run_cache_command(&command).await? {
+				println!("{output}");
+			}
+			Ok(())
+		}
+		Command::VerifyJournal => {
+			let records = config.verify_audit_journal().await?;
+			println!("Audit journal is intact, {records} records");
+			Ok(())
+		}
+	}
 }
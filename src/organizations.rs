@@ -0,0 +1,129 @@
+//! Routing of users to Zitadel organizations
+use serde::Deserialize;
+
+use crate::user::{AttributeCondition, User};
+
+/// A rule routing matching users to a Zitadel organization
+///
+/// All criteria that are set must match; a rule without criteria
+/// matches every user.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct OrganizationRule {
+	/// The organization to create matching users in
+	pub organization_id: String,
+	/// Match LDAP users whose entry is located below this DN
+	pub base_dn: Option<String>,
+	/// Match users whose email address is in this domain
+	pub email_domain: Option<String>,
+	/// Match users whose source attributes match this condition
+	pub condition: Option<AttributeCondition>,
+}
+
+impl OrganizationRule {
+	/// Whether a user matches the rule
+	fn matches(&self, user: &User) -> bool {
+		self.base_dn.as_ref().map_or(true, |base_dn| is_below_dn(user, base_dn))
+			&& self.email_domain.as_ref().map_or(true, |domain| has_email_domain(user, domain))
+			&& self.condition.as_ref().map_or(true, |condition| condition.matches(user))
+	}
+}
+
+/// Whether the user's LDAP entry is located at or below the given DN
+fn is_below_dn(user: &User, base_dn: &str) -> bool {
+	let base_dn = base_dn.to_lowercase();
+
+	user.attribute_values("dn").iter().any(|dn| {
+		let dn = dn.to_string().to_lowercase();
+		dn == base_dn || dn.ends_with(&format!(",{base_dn}"))
+	})
+}
+
+/// Whether the user's email address is in the given domain
+fn has_email_domain(user: &User, domain: &str) -> bool {
+	user.email
+		.to_string()
+		.rsplit_once('@')
+		.is_some_and(|(_, email_domain)| email_domain.eq_ignore_ascii_case(domain))
+}
+
+/// Choose the organization a user belongs in: that of the first
+/// matching rule, or the default organization if no rule matches
+pub(crate) fn route<'a>(
+	rules: &'a [OrganizationRule],
+	default_organization_id: &'a str,
+	user: &User,
+) -> &'a str {
+	rules
+		.iter()
+		.find(|rule| rule.matches(user))
+		.map_or(default_organization_id, |rule| rule.organization_id.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::user::test_helpers::{attributes, user};
+
+	fn rules() -> Vec<OrganizationRule> {
+		vec![
+			OrganizationRule {
+				organization_id: "north".to_owned(),
+				base_dn: Some("ou=North,dc=example,dc=org".to_owned()),
+				email_domain: None,
+				condition: None,
+			},
+			OrganizationRule {
+				organization_id: "south".to_owned(),
+				base_dn: None,
+				email_domain: Some("south.example.org".to_owned()),
+				condition: None,
+			},
+			OrganizationRule {
+				organization_id: "east".to_owned(),
+				base_dn: None,
+				email_domain: None,
+				condition: Some(AttributeCondition {
+					attribute: "company".to_owned(),
+					value: "East".to_owned(),
+				}),
+			},
+		]
+	}
+
+	#[test]
+	fn test_route_base_dn() {
+		let user = User {
+			attributes: attributes(&[
+				("dn", "uid=alice,ou=north,dc=example,dc=org"),
+				("company", "East"),
+			]),
+			..user("alice", "alice@example.org")
+		};
+		assert_eq!(route(&rules(), "default", &user), "north");
+
+		// Only whole RDNs may match
+		let user = User {
+			attributes: attributes(&[("dn", "uid=bob,ou=farnorth,dc=example,dc=org")]),
+			..user("bob", "bob@example.org")
+		};
+		assert_eq!(route(&rules(), "default", &user), "default");
+	}
+
+	#[test]
+	fn test_route_email_domain() {
+		let user = user("carol", "carol@South.example.org");
+		assert_eq!(route(&rules(), "default", &user), "south");
+
+		let user = user("dave", "dave@notsouth.example.org");
+		assert_eq!(route(&rules(), "default", &user), "default");
+	}
+
+	#[test]
+	fn test_route_condition() {
+		let user = User {
+			attributes: attributes(&[("company", "east")]),
+			..user("erin", "erin@example.org")
+		};
+		assert_eq!(route(&rules(), "default", &user), "east");
+	}
+}
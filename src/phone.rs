@@ -0,0 +1,160 @@
+//! Normalisation of phone numbers to E.164
+use anyhow::Result;
+use phonenumber::{country, Mode};
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::{
+	report::{ReportEntry, SyncReport},
+	user::{StringOrBytes, User},
+	zitadel::{ChangedUser, SourceDiff},
+};
+
+/// Matches extensions at the end of phone numbers, e.g. `ext. 12`
+const EXTENSION_PATTERN: &str = r"(?i)\s*(?:ext\.?|extension|x|durchwahl|dw\.?|#)\s*\d+\s*$";
+
+/// Configuration of phone number normalisation
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+pub struct PhoneConfig {
+	/// The country of phone numbers without an international prefix,
+	/// as an ISO 3166-1 alpha-2 code, e.g. `DE`
+	pub default_country: Option<country::Id>,
+}
+
+/// Normalises the phone numbers of users to E.164, dropping those that
+/// cannot be normalised
+#[derive(Debug, Clone)]
+pub(crate) struct PhoneNormalizer {
+	/// The country of phone numbers without an international prefix
+	default_country: Option<country::Id>,
+	/// Matches extensions to strip
+	extension: Regex,
+	/// Where to report phone numbers that cannot be normalised
+	report: SyncReport,
+}
+
+impl PhoneNormalizer {
+	/// Create a normaliser for the given configuration
+	pub(crate) fn new(config: &PhoneConfig, report: SyncReport) -> Result<Self> {
+		Ok(Self {
+			default_country: config.default_country,
+			extension: Regex::new(EXTENSION_PATTERN)?,
+			report,
+		})
+	}
+
+	/// Normalise a phone number to E.164, if it is valid
+	///
+	/// Extensions, formatting characters and the `(0)` some numbers
+	/// carry after their country code are stripped.
+	pub(crate) fn normalize(&self, phone: &str) -> Option<String> {
+		let phone = self.extension.replace(phone, "").replace("(0)", "");
+		let number = phonenumber::parse(self.default_country, phone).ok()?;
+
+		number.is_valid().then(|| number.format().mode(Mode::E164).to_string())
+	}
+
+	/// Normalise the phone numbers of all users of a source diff
+	pub(crate) fn normalize_diff(&self, source_name: &str, diff: SourceDiff) -> SourceDiff {
+		SourceDiff {
+			new_users: self.normalize_users(source_name, diff.new_users),
+			changed_users: diff
+				.changed_users
+				.into_iter()
+				.map(|ChangedUser { old, new }| ChangedUser {
+					old: self.normalize_user(None, old),
+					new: self.normalize_user(Some(source_name), new),
+				})
+				.collect(),
+			deleted_user_ids: diff.deleted_user_ids,
+		}
+	}
+
+	/// Normalise the phone numbers of a list of users
+	pub(crate) fn normalize_users(&self, source_name: &str, users: Vec<User>) -> Vec<User> {
+		users.into_iter().map(|user| self.normalize_user(Some(source_name), user)).collect()
+	}
+
+	/// Normalise the phone number of a user, reporting it for the given
+	/// source if it cannot be normalised
+	fn normalize_user(&self, source_name: Option<&str>, user: User) -> User {
+		let Some(phone) = user.phone.as_ref().map(ToString::to_string) else {
+			return user;
+		};
+
+		let normalized = self.normalize(&phone);
+		if normalized.is_none() {
+			tracing::warn!(
+				"Phone number `{}` of user `{}` could not be normalised",
+				phone,
+				user.external_user_id
+			);
+			if let Some(source_name) = source_name {
+				self.report.record(ReportEntry::InvalidPhoneNumber {
+					source: source_name.to_owned(),
+					external_user_id: user.external_user_id.to_string(),
+					phone,
+				});
+			}
+		}
+
+		User { phone: normalized.map(StringOrBytes::String), ..user }
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::user::test_helpers::user;
+
+	fn normalizer(default_country: Option<country::Id>) -> PhoneNormalizer {
+		PhoneNormalizer::new(&PhoneConfig { default_country }, SyncReport::default())
+			.expect("failed to create normaliser")
+	}
+
+	#[test]
+	fn test_normalize_phone_numbers() {
+		let normalizer = normalizer(Some(country::Id::DE));
+
+		assert_eq!(normalizer.normalize("0221 / 478-1234"), Some("+492214781234".to_owned()));
+		assert_eq!(normalizer.normalize("+49 (0) 221 4781234"), Some("+492214781234".to_owned()));
+		assert_eq!(
+			normalizer.normalize("(0221) 478-1234 ext. 56"),
+			Some("+492214781234".to_owned())
+		);
+		assert_eq!(normalizer.normalize("+1 201-555-0123"), Some("+12015550123".to_owned()));
+		assert_eq!(normalizer.normalize("call me"), None);
+		assert_eq!(normalizer.normalize("12"), None);
+	}
+
+	#[test]
+	fn test_normalize_without_default_country() {
+		let normalizer = normalizer(None);
+
+		assert_eq!(normalizer.normalize("+49 221 4781234"), Some("+492214781234".to_owned()));
+		assert_eq!(normalizer.normalize("0221 4781234"), None);
+	}
+
+	#[test]
+	fn test_normalize_reports_invalid_numbers() {
+		let report = SyncReport::default();
+		let normalizer = PhoneNormalizer::new(&PhoneConfig::default(), report.clone())
+			.expect("failed to create normaliser");
+
+		let user = User {
+			phone: Some("0221 4781234".to_owned().into()),
+			..user("test", "test@example.com")
+		};
+
+		let users = normalizer.normalize_users("LDAP", vec![user]);
+		assert_eq!(users[0].phone, None);
+		assert_eq!(
+			report.entries(),
+			vec![ReportEntry::InvalidPhoneNumber {
+				source: "LDAP".to_owned(),
+				external_user_id: "test".to_owned(),
+				phone: "0221 4781234".to_owned(),
+			}]
+		);
+	}
+}
@@ -0,0 +1,242 @@
+//! Client-side rate limiting and retries of requests to Zitadel
+use std::{
+	fmt::Debug,
+	future::Future,
+	sync::{Arc, Mutex, PoisonError},
+	time::{Duration, Instant},
+};
+
+use serde::Deserialize;
+
+/// How fast requests are sent to Zitadel, and how requests Zitadel
+/// rejects as overloaded or unavailable are retried
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct RateLimitConfig {
+	/// How many requests may be sent per second on average, or 0 to not
+	/// limit requests
+	pub requests_per_second: u32,
+	/// How many requests may be sent in quick succession after a quiet
+	/// period
+	pub burst: u32,
+	/// How often a request is retried before giving up
+	pub max_retries: u32,
+	/// The delay before the first retry in milliseconds, doubling with
+	/// each further retry
+	pub initial_backoff_ms: u64,
+	/// The maximum delay between retries in milliseconds
+	pub max_backoff_ms: u64,
+}
+
+impl Default for RateLimitConfig {
+	fn default() -> Self {
+		Self {
+			requests_per_second: 50,
+			burst: 50,
+			max_retries: 5,
+			initial_backoff_ms: 500,
+			max_backoff_ms: 30_000,
+		}
+	}
+}
+
+/// The tokens available to send requests
+#[derive(Debug)]
+struct Bucket {
+	/// How many requests may be sent right away
+	tokens: f64,
+	/// When tokens were last added
+	refilled: Instant,
+}
+
+/// A token bucket rate limiter that retries failed requests with
+/// exponential backoff
+///
+/// Clones share the same bucket, so concurrent requests are limited
+/// together.
+#[derive(Debug, Clone)]
+pub(crate) struct RateLimiter {
+	/// The limits to apply
+	config: RateLimitConfig,
+	/// The tokens available to send requests
+	bucket: Arc<Mutex<Bucket>>,
+}
+
+impl RateLimiter {
+	/// Create a rate limiter, starting with a full bucket
+	pub(crate) fn new(config: &RateLimitConfig) -> Self {
+		Self {
+			config: config.clone(),
+			bucket: Arc::new(Mutex::new(Bucket {
+				tokens: f64::from(config.burst.max(1)),
+				refilled: Instant::now(),
+			})),
+		}
+	}
+
+	/// Send a request once the rate limit allows it, retrying it while
+	/// it fails with errors considered retryable
+	pub(crate) async fn call<T, E, F, Fut>(
+		&self,
+		request: F,
+		is_retryable: impl Fn(&E) -> bool,
+	) -> Result<T, E>
+	where
+		E: Debug,
+		F: Fn() -> Fut,
+		Fut: Future<Output = Result<T, E>>,
+	{
+		let mut attempt = 0;
+
+		loop {
+			self.acquire().await;
+
+			match request().await {
+				Err(error) if attempt < self.config.max_retries && is_retryable(&error) => {
+					let delay = self.backoff(attempt);
+					tracing::warn!("Zitadel request failed, retrying in {:?}: {:?}", delay, error);
+					tokio::time::sleep(delay).await;
+					attempt += 1;
+				}
+				result => return result,
+			}
+		}
+	}
+
+	/// Wait until a request may be sent
+	async fn acquire(&self) {
+		if self.config.requests_per_second == 0 {
+			return;
+		}
+
+		let rate = f64::from(self.config.requests_per_second);
+		let capacity = f64::from(self.config.burst.max(1));
+
+		loop {
+			let wait = {
+				let mut bucket = self.bucket.lock().unwrap_or_else(PoisonError::into_inner);
+				let now = Instant::now();
+				let refill = now.duration_since(bucket.refilled).as_secs_f64() * rate;
+				bucket.tokens = (bucket.tokens + refill).min(capacity);
+				bucket.refilled = now;
+
+				if bucket.tokens >= 1.0 {
+					bucket.tokens -= 1.0;
+					return;
+				}
+
+				Duration::from_secs_f64((1.0 - bucket.tokens) / rate)
+			};
+
+			tokio::time::sleep(wait).await;
+		}
+	}
+
+	/// The delay before the given retry, growing exponentially up to the
+	/// maximum, with jitter so concurrent requests spread out
+	fn backoff(&self, attempt: u32) -> Duration {
+		let delay = self
+			.config
+			.initial_backoff_ms
+			.saturating_mul(2_u64.saturating_pow(attempt))
+			.min(self.config.max_backoff_ms);
+
+		Duration::from_millis(fastrand::u64(delay / 2..=delay))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::atomic::{AtomicU32, Ordering};
+
+	use super::*;
+
+	fn config() -> RateLimitConfig {
+		RateLimitConfig {
+			requests_per_second: 100,
+			burst: 2,
+			max_retries: 3,
+			initial_backoff_ms: 1,
+			max_backoff_ms: 4,
+		}
+	}
+
+	#[tokio::test]
+	async fn test_rate_limit() {
+		let limiter = RateLimiter::new(&config());
+		let started = Instant::now();
+
+		// The burst passes right away, the rest at the configured rate
+		for _ in 0..7 {
+			limiter.acquire().await;
+		}
+
+		assert!(started.elapsed() >= Duration::from_millis(45));
+
+		let unlimited = RateLimiter::new(&RateLimitConfig { requests_per_second: 0, ..config() });
+		let started = Instant::now();
+		for _ in 0..100 {
+			unlimited.acquire().await;
+		}
+		assert!(started.elapsed() < Duration::from_millis(45));
+	}
+
+	#[tokio::test]
+	async fn test_retry() {
+		let limiter = RateLimiter::new(&config());
+		let attempts = AtomicU32::new(0);
+
+		let result: Result<(), &str> = limiter
+			.call(
+				|| async {
+					attempts.fetch_add(1, Ordering::SeqCst);
+					Err("unavailable")
+				},
+				|error| *error == "unavailable",
+			)
+			.await;
+		assert!(result.is_err());
+		assert_eq!(attempts.load(Ordering::SeqCst), 4);
+
+		let attempts = AtomicU32::new(0);
+		let result: Result<(), &str> = limiter
+			.call(
+				|| async {
+					attempts.fetch_add(1, Ordering::SeqCst);
+					Err("not found")
+				},
+				|error| *error == "unavailable",
+			)
+			.await;
+		assert!(result.is_err());
+		assert_eq!(attempts.load(Ordering::SeqCst), 1);
+
+		let attempts = AtomicU32::new(0);
+		let result: Result<u32, &str> = limiter
+			.call(
+				|| async {
+					match attempts.fetch_add(1, Ordering::SeqCst) {
+						0 => Err("unavailable"),
+						attempt => Ok(attempt),
+					}
+				},
+				|error| *error == "unavailable",
+			)
+			.await;
+		assert_eq!(result, Ok(1));
+	}
+
+	#[test]
+	fn test_backoff() {
+		let limiter = RateLimiter::new(&RateLimitConfig {
+			initial_backoff_ms: 100,
+			max_backoff_ms: 1000,
+			..config()
+		});
+
+		for (attempt, max) in [(0, 100), (1, 200), (2, 400), (3, 800), (4, 1000), (40, 1000)] {
+			let delay = limiter.backoff(attempt);
+			assert!(delay >= Duration::from_millis(max / 2) && delay <= Duration::from_millis(max));
+		}
+	}
+}
@@ -0,0 +1,294 @@
+//! Stateless reconciliation of a source's users against Zitadel
+use std::collections::HashMap;
+
+use crate::{
+	grants,
+	user::User,
+	zitadel::{ChangedUser, ManagedUser, SourceDiff, UserId, ZitadelConfig},
+};
+
+/// Compute the changes needed to bring the users managed in Zitadel
+/// in line with the full list of users of a source.
+///
+/// Users are matched by their external ID, which Zitadel stores as the
+/// nick name. Users that only exist in Zitadel are deleted, and users
+/// that were deactivated in Zitadel are reported as re-enabled, which
+/// reactivates their accounts. Users in the wrong organization are
+/// reported as changed, which moves them.
+pub(crate) fn compute_diff(
+	source_users: Vec<User>,
+	managed_users: Vec<ManagedUser>,
+	zitadel_config: &ZitadelConfig,
+) -> SourceDiff {
+	let grant_configs = zitadel_config.grant_configs();
+
+	let mut managed_users: HashMap<String, ManagedUser> = managed_users
+		.into_iter()
+		.map(|managed| (managed.user.external_user_id.to_string(), managed))
+		.collect();
+
+	let mut diff =
+		SourceDiff { new_users: vec![], changed_users: vec![], deleted_user_ids: vec![] };
+
+	for user in source_users {
+		match managed_users.remove(&user.external_user_id.to_string()) {
+			None => {
+				if user.enabled {
+					diff.new_users.push(user);
+				}
+			}
+			Some(existing) => {
+				if !user.enabled {
+					diff.deleted_user_ids.push(UserId::ZitadelId(existing.zitadel_id));
+				} else if !existing.user.enabled {
+					diff.changed_users.push(ChangedUser { old: existing.user, new: user });
+				} else if existing.organization_id != zitadel_config.organization_for(&user)
+					|| !grants::grants_in_sync(
+						&existing.grants,
+						&grants::desired_grants(&grant_configs, &user),
+					) || !is_in_sync(&existing.user, &user, zitadel_config)
+				{
+					diff.changed_users.push(ChangedUser { old: existing.user, new: user });
+				}
+			}
+		}
+	}
+
+	diff.deleted_user_ids
+		.extend(managed_users.into_values().map(|managed| UserId::ZitadelId(managed.zitadel_id)));
+
+	diff
+}
+
+/// Whether the user data stored in Zitadel matches the source
+///
+/// Values are compared in the form they are written to Zitadel, so
+/// binary attributes match their base64-encoded counterparts.
+fn is_in_sync(zitadel: &User, source: &User, zitadel_config: &ZitadelConfig) -> bool {
+	zitadel
+		.display_name
+		.as_ref()
+		.map_or(true, |display_name| *display_name == zitadel_config.display_name(source))
+		&& zitadel
+			.login_name
+			.as_ref()
+			.map_or(true, |login_name| *login_name == zitadel_config.login_name(source))
+		&& zitadel.first_name.to_string() == source.first_name.to_string()
+		&& zitadel.last_name.to_string() == source.last_name.to_string()
+		// Users without email addresses are reported by the validation,
+		// rather than updated every run to no effect
+		&& (!source.has_email() || zitadel.email.to_string() == source.email.to_string())
+		&& zitadel.preferred_username.to_string() == source.preferred_username.to_string()
+		&& zitadel.phone.as_ref().map(ToString::to_string)
+			== source.phone.as_ref().map(ToString::to_string)
+		&& zitadel.metadata.len() == source.metadata.len()
+		&& zitadel.metadata.iter().all(|(key, value)| {
+			source.metadata.get(key).map(ToString::to_string) == Some(value.to_string())
+		})
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::BTreeSet;
+
+	use super::*;
+	use crate::{
+		grants::Grants,
+		user::{
+			test_helpers::{user, zitadel_config},
+			StringOrBytes,
+		},
+		zitadel::LoginNameSource,
+	};
+
+	fn managed(id: &str, email: &str, enabled: bool) -> ManagedUser {
+		ManagedUser {
+			zitadel_id: format!("zitadel-{id}"),
+			organization_id: "org".to_owned(),
+			user: User { enabled, ..user(id, email) },
+			grants: Grants::from([("project".to_owned(), BTreeSet::from(["User".to_owned()]))]),
+		}
+	}
+
+	fn deleted_ids(diff: &SourceDiff) -> Vec<String> {
+		diff.deleted_user_ids
+			.iter()
+			.map(|id| match id {
+				UserId::ZitadelId(id) => id.clone(),
+				other => panic!("unexpected user ID {other:?}"),
+			})
+			.collect()
+	}
+
+	#[test]
+	fn test_reconcile_in_sync() {
+		let diff = compute_diff(
+			vec![user("alice", "alice@example.com")],
+			vec![managed("alice", "alice@example.com", true)],
+			&zitadel_config(),
+		);
+
+		assert!(diff.new_users.is_empty());
+		assert!(diff.changed_users.is_empty());
+		assert!(diff.deleted_user_ids.is_empty());
+	}
+
+	#[test]
+	fn test_reconcile_new_and_deleted() {
+		let diff = compute_diff(
+			vec![
+				user("alice", "alice@example.com"),
+				User { enabled: false, ..user("carol", "carol@example.com") },
+			],
+			vec![managed("bob", "bob@example.com", true)],
+			&zitadel_config(),
+		);
+
+		assert_eq!(diff.new_users.len(), 1);
+		assert_eq!(diff.new_users[0].external_user_id, StringOrBytes::String("alice".to_owned()));
+		assert!(diff.changed_users.is_empty());
+		assert_eq!(deleted_ids(&diff), vec!["zitadel-bob"]);
+	}
+
+	#[test]
+	fn test_reconcile_changed() {
+		let mut missing_grant = managed("bob", "bob@example.com", true);
+		missing_grant.grants.clear();
+
+		let diff = compute_diff(
+			vec![user("alice", "alice@example.com"), user("bob", "bob@example.com")],
+			vec![managed("alice", "manually-changed@example.com", true), missing_grant],
+			&zitadel_config(),
+		);
+
+		assert!(diff.new_users.is_empty());
+		assert!(diff.deleted_user_ids.is_empty());
+		assert_eq!(diff.changed_users.len(), 2);
+		assert_eq!(
+			diff.changed_users[0].old.email,
+			StringOrBytes::String("manually-changed@example.com".to_owned())
+		);
+		assert_eq!(
+			diff.changed_users[0].new.email,
+			StringOrBytes::String("alice@example.com".to_owned())
+		);
+	}
+
+	#[test]
+	fn test_reconcile_state() {
+		let diff = compute_diff(
+			vec![
+				User { enabled: false, ..user("alice", "alice@example.com") },
+				user("bob", "bob@example.com"),
+			],
+			vec![
+				managed("alice", "alice@example.com", true),
+				managed("bob", "bob@example.com", false),
+			],
+			&zitadel_config(),
+		);
+
+		// Bob's deactivated account is reactivated rather than re-created
+		assert!(diff.new_users.is_empty());
+		assert_eq!(diff.changed_users.len(), 1);
+		assert!(!diff.changed_users[0].old.enabled);
+		assert!(diff.changed_users[0].new.enabled);
+		assert_eq!(deleted_ids(&diff), vec!["zitadel-alice"]);
+	}
+
+	#[test]
+	fn test_reconcile_missing_email() {
+		let diff = compute_diff(
+			vec![user("alice", "")],
+			vec![managed("alice", "alice@example.com", true)],
+			&zitadel_config(),
+		);
+
+		assert!(diff.changed_users.is_empty());
+	}
+
+	#[test]
+	fn test_reconcile_binary_values() {
+		let mut source = user("alice", "alice@example.com");
+		source.preferred_username = StringOrBytes::Bytes(vec![0xA0, 0xA1]);
+
+		let mut existing = managed("alice", "alice@example.com", true);
+		existing.user.preferred_username = StringOrBytes::String("oKE=".to_owned());
+
+		let diff = compute_diff(vec![source], vec![existing], &zitadel_config());
+
+		assert!(diff.changed_users.is_empty());
+	}
+
+	#[test]
+	fn test_reconcile_organization_change() {
+		let diff = compute_diff(
+			vec![user("alice", "alice@other.example.com")],
+			vec![managed("alice", "alice@other.example.com", true)],
+			&zitadel_config(),
+		);
+
+		assert_eq!(diff.changed_users.len(), 1);
+	}
+
+	#[test]
+	fn test_reconcile_display_name() {
+		let mut existing = managed("alice", "alice@example.com", true);
+		existing.user.display_name = Some("User, Test".to_owned());
+
+		let diff = compute_diff(
+			vec![user("alice", "alice@example.com")],
+			vec![existing],
+			&zitadel_config(),
+		);
+		assert!(diff.changed_users.is_empty());
+
+		let mut existing = managed("alice", "alice@example.com", true);
+		existing.user.display_name = Some("Test User".to_owned());
+
+		let diff = compute_diff(
+			vec![user("alice", "alice@example.com")],
+			vec![existing],
+			&zitadel_config(),
+		);
+		assert_eq!(diff.changed_users.len(), 1);
+	}
+
+	#[test]
+	fn test_reconcile_login_name() {
+		let mut zitadel_config = zitadel_config();
+		zitadel_config.login_name = LoginNameSource::ExternalUserId;
+
+		let mut existing = managed("alice", "alice@example.com", true);
+		existing.user.login_name = Some("alice".to_owned());
+
+		let diff =
+			compute_diff(vec![user("alice", "alice@example.com")], vec![existing], &zitadel_config);
+		assert!(diff.changed_users.is_empty());
+
+		let mut existing = managed("alice", "alice@example.com", true);
+		existing.user.login_name = Some("alice@example.com".to_owned());
+
+		let diff =
+			compute_diff(vec![user("alice", "alice@example.com")], vec![existing], &zitadel_config);
+		assert_eq!(diff.changed_users.len(), 1);
+	}
+
+	#[test]
+	fn test_reconcile_metadata() {
+		let mut source = user("alice", "alice@example.com");
+		source.metadata.insert("employee_id".to_owned(), StringOrBytes::Bytes(vec![0xA0, 0xA1]));
+
+		let mut existing = managed("alice", "alice@example.com", true);
+		existing.user.metadata.insert("employee_id".to_owned(), "oKE=".to_owned().into());
+
+		let diff = compute_diff(vec![source.clone()], vec![existing], &zitadel_config());
+		assert!(diff.changed_users.is_empty());
+
+		let mut existing = managed("alice", "alice@example.com", true);
+		existing.user.metadata.insert("department".to_owned(), "Radiology".to_owned().into());
+
+		let diff = compute_diff(vec![source], vec![existing], &zitadel_config());
+		assert_eq!(diff.changed_users.len(), 1);
+	}
+}
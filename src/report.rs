@@ -0,0 +1,127 @@
+//! Summary of noteworthy events during a sync run
+use std::{
+	fmt::Display,
+	sync::{Arc, Mutex, PoisonError},
+};
+
+use crate::validation::ValidationPolicy;
+
+/// A noteworthy event during a sync run
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum ReportEntry {
+	/// A user could not be imported, because a user with the same login
+	/// name already exists in Zitadel
+	ImportConflict {
+		/// The source the user was imported from
+		source: String,
+		/// The conflicting login name
+		login_name: String,
+	},
+	/// A user's phone number could not be normalised, so it is not
+	/// synced
+	InvalidPhoneNumber {
+		/// The source the user was imported from
+		source: String,
+		/// The external ID of the user
+		external_user_id: String,
+		/// The phone number as found in the source
+		phone: String,
+	},
+	/// A user failed validation before being written to Zitadel
+	InvalidUser {
+		/// The source the user was imported from
+		source: String,
+		/// The external ID of the user
+		external_user_id: String,
+		/// What is wrong with the user
+		problem: String,
+		/// What was done about it
+		policy: ValidationPolicy,
+	},
+	/// The mapping script failed for a user, so the user's changes are
+	/// not synced
+	MappingFailed {
+		/// The source the user was imported from
+		source: String,
+		/// The external ID of the user
+		external_user_id: String,
+		/// Why the mapping failed
+		error: String,
+	},
+	/// A user's change failed in too many runs, so it is no longer
+	/// retried
+	RetriesExhausted {
+		/// The source the user was imported from
+		source: String,
+		/// The external ID of the user
+		external_user_id: String,
+		/// How many runs the change failed in
+		attempts: u32,
+	},
+}
+
+impl Display for ReportEntry {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::ImportConflict { source, login_name } => {
+				write!(f, "{source}: user `{login_name}` already exists in Zitadel")
+			}
+			Self::InvalidPhoneNumber { source, external_user_id, phone } => {
+				write!(
+					f,
+					"{source}: phone number `{phone}` of user `{external_user_id}` is invalid"
+				)
+			}
+			Self::InvalidUser { source, external_user_id, problem, policy } => {
+				write!(f, "{source}: user `{external_user_id}` {problem}, {policy}")
+			}
+			Self::MappingFailed { source, external_user_id, error } => {
+				write!(f, "{source}: user `{external_user_id}` was skipped: {error}")
+			}
+			Self::RetriesExhausted { source, external_user_id, attempts } => {
+				write!(
+					f,
+					"{source}: gave up on the change of user `{external_user_id}` after \
+					 {attempts} attempts"
+				)
+			}
+		}
+	}
+}
+
+/// Collects noteworthy events of a sync run, so they can be summarized
+/// once the run is done
+///
+/// Clones share the same underlying list of entries.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SyncReport {
+	/// The events recorded so far
+	entries: Arc<Mutex<Vec<ReportEntry>>>,
+}
+
+impl SyncReport {
+	/// Record an event
+	pub(crate) fn record(&self, entry: ReportEntry) {
+		self.entries.lock().unwrap_or_else(PoisonError::into_inner).push(entry);
+	}
+
+	/// Get all events recorded so far
+	pub(crate) fn entries(&self) -> Vec<ReportEntry> {
+		self.entries.lock().unwrap_or_else(PoisonError::into_inner).clone()
+	}
+
+	/// Log a summary of all recorded events
+	pub(crate) fn log_summary(&self) {
+		let entries = self.entries();
+
+		if entries.is_empty() {
+			tracing::info!("Sync finished without noteworthy events");
+			return;
+		}
+
+		tracing::warn!("Sync finished with {} noteworthy events:", entries.len());
+		for entry in entries {
+			tracing::warn!("  {}", entry);
+		}
+	}
+}
@@ -0,0 +1,503 @@
+//! Custom user mapping through an embedded script
+use std::{
+	collections::{BTreeMap, BTreeSet},
+	path::PathBuf,
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Arc,
+	},
+	time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use rhai::{module_resolvers::DummyModuleResolver, Array, Dynamic, Engine, Map, Scope, AST};
+use serde::Deserialize;
+
+use crate::{
+	grants::Grants,
+	report::{ReportEntry, SyncReport},
+	state::PendingChange,
+	user::{StringOrBytes, User},
+	zitadel::{ChangedUser, SourceDiff, RESERVED_METADATA_KEYS},
+};
+
+/// The function every mapping script must define
+const MAP_USER_FUNCTION: &str = "map_user";
+
+/// Configuration of the mapping script
+///
+/// The script is written in [Rhai](https://rhai.rs) and must define a
+/// `map_user(record, user)` function. `record` maps the lowercase names
+/// of the user's source attributes to arrays of their values, and
+/// `user` holds the user as mapped by the sync. The function returns
+/// the user, possibly modified, or `()` to drop it, which is treated
+/// like a disabled user.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct ScriptConfig {
+	/// The path to the script
+	pub path: PathBuf,
+	/// The time the script may take to map a single user, in
+	/// milliseconds
+	#[serde(default = "default_timeout_ms")]
+	pub timeout_ms: u64,
+	/// The number of operations the script may perform to map a single
+	/// user
+	#[serde(default = "default_max_operations")]
+	pub max_operations: u64,
+}
+
+/// The default for [`ScriptConfig::timeout_ms`]
+const fn default_timeout_ms() -> u64 {
+	100
+}
+
+/// The default for [`ScriptConfig::max_operations`]
+const fn default_max_operations() -> u64 {
+	100_000
+}
+
+/// A compiled mapping script
+#[derive(Debug)]
+pub(crate) struct Script {
+	/// The sandboxed engine running the script
+	engine: Engine,
+	/// The compiled script
+	ast: AST,
+	/// The time the script may take to map a single user
+	timeout: Duration,
+	/// The deadline of the current invocation, in milliseconds since
+	/// the engine was created
+	deadline: Arc<AtomicU64>,
+	/// When the engine was created
+	created: Instant,
+	/// Where to report users the script fails to map
+	report: SyncReport,
+}
+
+impl Script {
+	/// Load and compile the configured script
+	pub(crate) fn new(config: &ScriptConfig, report: SyncReport) -> Result<Self> {
+		let source = std::fs::read_to_string(&config.path)
+			.context(format!("failed to read mapping script {}", config.path.display()))?;
+		Self::compile(&source, config, report)
+	}
+
+	/// Compile a script in a sandboxed engine
+	fn compile(source: &str, config: &ScriptConfig, report: SyncReport) -> Result<Self> {
+		let created = Instant::now();
+		let deadline = Arc::new(AtomicU64::new(0));
+
+		let mut engine = Engine::new();
+		// Scripts must not reach outside of the sandbox
+		engine.set_module_resolver(DummyModuleResolver::new());
+		engine.disable_symbol("eval");
+		engine.set_max_operations(config.max_operations);
+		engine.on_print(|text| tracing::info!("Mapping script: {text}"));
+		engine.on_debug(|text, _, position| tracing::debug!("Mapping script {position}: {text}"));
+
+		let progress_deadline = deadline.clone();
+		engine.on_progress(move |_| {
+			let elapsed = u64::try_from(created.elapsed().as_millis()).unwrap_or(u64::MAX);
+			(elapsed > progress_deadline.load(Ordering::Relaxed))
+				.then(|| Dynamic::from("mapping script timed out"))
+		});
+
+		let ast =
+			engine.compile(source).map_err(|error| anyhow!("invalid mapping script: {error}"))?;
+		if !ast
+			.iter_functions()
+			.any(|function| function.name == MAP_USER_FUNCTION && function.params.len() == 2)
+		{
+			bail!("mapping script does not define `{MAP_USER_FUNCTION}(record, user)`");
+		}
+
+		Ok(Self {
+			engine,
+			ast,
+			timeout: Duration::from_millis(config.timeout_ms),
+			deadline,
+			created,
+			report,
+		})
+	}
+
+	/// Map all users of a source diff, along with the changes of the
+	/// users the script failed to map
+	///
+	/// Users the script fails to map are reported and left out of the
+	/// diff, so the rest of the source is still synced. Their changes
+	/// are returned unmapped, to be mapped again when they are retried.
+	pub(crate) fn map_diff(
+		&self,
+		source_name: &str,
+		diff: SourceDiff,
+	) -> (SourceDiff, Vec<PendingChange>) {
+		let mut skipped = Vec::new();
+
+		let mut new_users = Vec::new();
+		for user in diff.new_users {
+			match self.map_or_report(source_name, &user) {
+				Some(mapped) => new_users.push(mapped),
+				None => skipped.push(PendingChange::Import(Box::new(user))),
+			}
+		}
+
+		let mut changed_users = Vec::new();
+		for user in diff.changed_users {
+			let mapped = self.map_or_report(source_name, &user.old).and_then(|old| {
+				Some(ChangedUser { old, new: self.map_or_report(source_name, &user.new)? })
+			});
+			match mapped {
+				Some(mapped) => changed_users.push(mapped),
+				None => skipped.push(PendingChange::Update(Box::new(user))),
+			}
+		}
+
+		let skipped =
+			skipped.into_iter().map(|change| PendingChange::Unmapped(Box::new(change))).collect();
+		(SourceDiff { new_users, changed_users, deleted_user_ids: diff.deleted_user_ids }, skipped)
+	}
+
+	/// Map a single user, reporting the user if the script fails
+	fn map_or_report(&self, source_name: &str, user: &User) -> Option<User> {
+		let external_user_id = user.external_user_id.to_string();
+		match self.map_user(user.clone()) {
+			Ok(user) => Some(user),
+			Err(error) => {
+				tracing::warn!("Skipping user `{external_user_id}`: {error:#}");
+				self.report.record(ReportEntry::MappingFailed {
+					source: source_name.to_owned(),
+					external_user_id,
+					error: format!("{error:#}"),
+				});
+				None
+			}
+		}
+	}
+
+	/// Map a list of users
+	///
+	/// Fails if the script fails for any user, as leaving a user out of
+	/// a full reconciliation would delete it from Zitadel.
+	pub(crate) fn map_users(&self, users: Vec<User>) -> Result<Vec<User>> {
+		users.into_iter().map(|user| self.map_user(user)).collect()
+	}
+
+	/// Run the script for a single user
+	pub(crate) fn map_user(&self, user: User) -> Result<User> {
+		let deadline = self.created.elapsed().saturating_add(self.timeout);
+		self.deadline
+			.store(u64::try_from(deadline.as_millis()).unwrap_or(u64::MAX), Ordering::Relaxed);
+
+		let result: Dynamic = self
+			.engine
+			.call_fn(
+				&mut Scope::new(),
+				&self.ast,
+				MAP_USER_FUNCTION,
+				(record_to_map(&user), user_to_map(&user)),
+			)
+			.map_err(|error| {
+				anyhow!("mapping script failed for user `{}`: {error}", user.external_user_id)
+			})?;
+
+		if result.is_unit() {
+			return Ok(User { enabled: false, ..user });
+		}
+
+		let external_user_id = user.external_user_id.to_string();
+		let Some(map) = result.try_cast::<Map>() else {
+			bail!("mapping script must return the user or `()` for user `{external_user_id}`");
+		};
+
+		apply_map(user, &map)
+			.context(format!("mapping script returned an invalid user for `{external_user_id}`"))
+	}
+}
+
+/// Convert the raw attributes of a user's source record to a script map
+fn record_to_map(user: &User) -> Map {
+	user.attributes
+		.iter()
+		.map(|(name, values)| {
+			let values: Array =
+				values.iter().map(|value| Dynamic::from(value.to_string())).collect();
+			(name.as_str().into(), Dynamic::from(values))
+		})
+		.collect()
+}
+
+/// Convert a user to a script map
+fn user_to_map(user: &User) -> Map {
+	let metadata: Map = user
+		.metadata
+		.iter()
+		.map(|(key, value)| (key.as_str().into(), Dynamic::from(value.to_string())))
+		.collect();
+	let roles: Map = user
+		.extra_grants
+		.iter()
+		.map(|(project_id, roles)| {
+			let roles: Array = roles.iter().cloned().map(Dynamic::from).collect();
+			(project_id.as_str().into(), Dynamic::from(roles))
+		})
+		.collect();
+
+	Map::from([
+		("first_name".into(), Dynamic::from(user.first_name.to_string())),
+		("last_name".into(), Dynamic::from(user.last_name.to_string())),
+		("email".into(), Dynamic::from(user.email.to_string())),
+		(
+			"phone".into(),
+			user.phone.as_ref().map_or(Dynamic::UNIT, |phone| phone.to_string().into()),
+		),
+		("preferred_username".into(), Dynamic::from(user.preferred_username.to_string())),
+		("external_user_id".into(), Dynamic::from(user.external_user_id.to_string())),
+		("enabled".into(), Dynamic::from(user.enabled)),
+		("metadata".into(), Dynamic::from(metadata)),
+		("roles".into(), Dynamic::from(roles)),
+	])
+}
+
+/// Apply the fields of a user map returned by the script
+///
+/// Values the script did not change are kept as they are, so binary
+/// values are preserved. The external user ID cannot be changed.
+fn apply_map(user: User, map: &Map) -> Result<User> {
+	/// Take over a changed value, keeping the original otherwise
+	fn merge(original: StringOrBytes, value: String) -> StringOrBytes {
+		if original.to_string() == value {
+			original
+		} else {
+			StringOrBytes::String(value)
+		}
+	}
+
+	/// Read a required string field
+	fn string(map: &Map, original: StringOrBytes, field: &str) -> Result<StringOrBytes> {
+		match map.get(field) {
+			None => Ok(original),
+			Some(value) => Ok(merge(original, to_string(value, field)?)),
+		}
+	}
+
+	let phone = match map.get("phone") {
+		None => user.phone,
+		Some(value) if value.is_unit() => None,
+		Some(value) => {
+			let phone = to_string(value, "phone")?;
+			Some(match user.phone {
+				Some(original) => merge(original, phone),
+				None => StringOrBytes::String(phone),
+			})
+		}
+	};
+
+	let enabled = match map.get("enabled") {
+		None => user.enabled,
+		Some(value) => value.as_bool().map_err(|_| anyhow!("`enabled` must be a boolean"))?,
+	};
+
+	let metadata = match map.get("metadata") {
+		None => user.metadata,
+		Some(value) => {
+			let mut original = user.metadata;
+			to_map(value, "metadata")?
+				.into_iter()
+				.map(|(key, value)| {
+					if RESERVED_METADATA_KEYS.contains(&key.as_str()) {
+						bail!("metadata key `{key}` is reserved for the sync");
+					}
+					let value = to_string(&value, &format!("metadata.{key}"))?;
+					let value = match original.remove(key.as_str()) {
+						Some(original) => merge(original, value),
+						None => StringOrBytes::String(value),
+					};
+					Ok((key.to_string(), value))
+				})
+				.collect::<Result<BTreeMap<_, _>>>()?
+		}
+	};
+
+	let extra_grants = match map.get("roles") {
+		None => user.extra_grants,
+		Some(value) => to_map(value, "roles")?
+			.into_iter()
+			.map(|(project_id, roles)| {
+				let field = format!("roles.{project_id}");
+				let roles: BTreeSet<String> = roles
+					.into_array()
+					.map_err(|_| anyhow!("`{field}` must be an array"))?
+					.iter()
+					.map(|role| to_string(role, &field))
+					.collect::<Result<_>>()?;
+				Ok((project_id.to_string(), roles))
+			})
+			.collect::<Result<Grants>>()?,
+	};
+
+	Ok(User {
+		first_name: string(map, user.first_name, "first_name")?,
+		last_name: string(map, user.last_name, "last_name")?,
+		email: string(map, user.email, "email")?,
+		preferred_username: string(map, user.preferred_username, "preferred_username")?,
+		phone,
+		enabled,
+		metadata,
+		extra_grants,
+		..user
+	})
+}
+
+/// Read a string value of the given field
+fn to_string(value: &Dynamic, field: &str) -> Result<String> {
+	value.clone().into_string().map_err(|_| anyhow!("`{field}` must be a string"))
+}
+
+/// Read a map value of the given field
+fn to_map(value: &Dynamic, field: &str) -> Result<Map> {
+	value.clone().try_cast::<Map>().ok_or_else(|| anyhow!("`{field}` must be a map"))
+}
+
+#[cfg(test)]
+mod tests {
+	use indoc::indoc;
+
+	use super::*;
+	use crate::user::test_helpers::{attributes, user};
+
+	fn config() -> ScriptConfig {
+		ScriptConfig {
+			path: PathBuf::from("mapping.rhai"),
+			timeout_ms: default_timeout_ms(),
+			max_operations: default_max_operations(),
+		}
+	}
+
+	fn compile(source: &str) -> Script {
+		Script::compile(source, &config(), SyncReport::default()).expect("failed to compile script")
+	}
+
+	#[test]
+	fn test_script_modifies_user() {
+		let script = compile(indoc! {r#"
+            fn map_user(record, user) {
+                user.first_name = record.title[0] + " " + user.first_name;
+                user.metadata.title = record.title[0];
+                user.roles.project = ["Admin"];
+                user
+            }
+        "#});
+
+		let user = script
+			.map_user(User {
+				first_name: "Alice".to_owned().into(),
+				preferred_username: StringOrBytes::Bytes(vec![0xA0, 0xA1]),
+				attributes: attributes(&[("title", "Dr.")]),
+				..user("alice", "alice@example.com")
+			})
+			.expect("failed to map user");
+		assert_eq!(user.first_name, StringOrBytes::String("Dr. Alice".to_owned()));
+		assert_eq!(user.metadata["title"], StringOrBytes::String("Dr.".to_owned()));
+		assert_eq!(
+			user.extra_grants,
+			Grants::from([("project".to_owned(), BTreeSet::from(["Admin".to_owned()]))])
+		);
+		// Unchanged binary values are preserved
+		assert_eq!(user.preferred_username, StringOrBytes::Bytes(vec![0xA0, 0xA1]));
+	}
+
+	#[test]
+	fn test_script_drops_user() {
+		let script = compile(indoc! {r#"
+            fn map_user(record, user) {
+                if user.email.ends_with("example.com") { () } else { user }
+            }
+        "#});
+
+		let user = script.map_user(user("alice", "alice@example.com")).expect("failed to map user");
+		assert!(!user.enabled);
+	}
+
+	#[test]
+	fn test_script_invalid_result() {
+		let script = compile("fn map_user(record, user) { user.email = 42; user }");
+		assert!(script.map_user(user("alice", "alice@example.com")).is_err());
+
+		let script = compile("fn map_user(record, user) { 42 }");
+		assert!(script.map_user(user("alice", "alice@example.com")).is_err());
+
+		let script =
+			compile("fn map_user(record, user) { user.metadata.managed_by = \"x\"; user }");
+		assert!(script.map_user(user("alice", "alice@example.com")).is_err());
+	}
+
+	#[test]
+	fn test_script_skips_failing_users() {
+		let report = SyncReport::default();
+		let script = Script::compile(
+			indoc! {r#"
+                fn map_user(record, user) {
+                    if user.first_name == "Bob" { throw "no Bobs"; }
+                    user
+                }
+            "#},
+			&config(),
+			report.clone(),
+		)
+		.expect("failed to compile script");
+
+		let alice = user("alice", "alice@example.com");
+		let bob = User { first_name: "Bob".to_owned().into(), ..user("bob", "bob@example.com") };
+		let (diff, skipped) = script.map_diff(
+			"test",
+			SourceDiff {
+				new_users: vec![alice.clone(), bob.clone()],
+				changed_users: vec![ChangedUser {
+					old: user("bob", "bob@example.com"),
+					new: bob.clone(),
+				}],
+				deleted_user_ids: vec![],
+			},
+		);
+
+		assert_eq!(diff.new_users.len(), 1);
+		assert_eq!(diff.new_users[0].external_user_id, alice.external_user_id);
+		assert!(diff.changed_users.is_empty());
+		assert_eq!(report.entries().len(), 2);
+
+		// Skipped users are retried later, unmapped
+		assert!(matches!(
+			skipped.as_slice(),
+			[PendingChange::Unmapped(import), PendingChange::Unmapped(update)]
+				if matches!(import.as_ref(), PendingChange::Import(user) if user.first_name == bob.first_name)
+					&& matches!(update.as_ref(), PendingChange::Update(_))
+		));
+		assert!(matches!(
+			&report.entries()[0],
+			ReportEntry::MappingFailed { external_user_id, .. } if external_user_id == "bob"
+		));
+	}
+
+	#[test]
+	fn test_script_limits() {
+		let script = compile("fn map_user(record, user) { loop {} }");
+		assert!(script.map_user(user("alice", "alice@example.com")).is_err());
+	}
+
+	#[test]
+	fn test_script_sandbox() {
+		assert!(Script::compile(
+			"fn something_else(user) { user }",
+			&config(),
+			SyncReport::default()
+		)
+		.is_err());
+
+		let script = compile(indoc! {r#"
+            import "secrets" as secrets;
+            fn map_user(record, user) { user }
+        "#});
+		assert!(script.map_user(user("alice", "alice@example.com")).is_err());
+	}
+}
@@ -1,9 +1,11 @@
 //! Sources of data we want to sync from.
 
-use anyhow::Result;
+use std::collections::BTreeSet;
+
+use anyhow::{bail, Result};
 use async_trait::async_trait;
 
-use crate::zitadel::SourceDiff;
+use crate::{state::StateChanges, user::User, zitadel::SourceDiff};
 
 pub mod csv;
 pub mod ldap;
@@ -17,4 +19,26 @@ pub trait Source {
 
 	/// Get changes from the source.
 	async fn get_diff(&self) -> Result<SourceDiff>;
+
+	/// Get the full list of users currently known to the source, used
+	/// for full reconciliation against Zitadel.
+	async fn get_all_users(&self) -> Result<Vec<User>> {
+		bail!("{} source does not support full reconciliation", self.get_name())
+	}
+
+	/// The Zitadel metadata keys the source is configured to maintain,
+	/// which a full reconciliation compares against Zitadel
+	fn metadata_keys(&self) -> BTreeSet<String> {
+		BTreeSet::new()
+	}
+
+	/// Persist the state of the source after the changes it last
+	/// reported were applied, so they are not reported again
+	///
+	/// State kept in the state store is added to the given changes, to
+	/// be committed along with the rest of the run's state. Sources that
+	/// keep no state have nothing to do.
+	async fn commit(&self, _changes: &mut StateChanges) -> Result<()> {
+		Ok(())
+	}
 }
@@ -1,19 +1,32 @@
 //! CSV source for syncing with Famedly's Zitadel.
 
-use std::{fs, path::PathBuf};
+use std::{
+	collections::{BTreeMap, BTreeSet, HashMap},
+	fs,
+	path::PathBuf,
+	sync::Arc,
+};
 
 use anyhow::{Context, Result};
 use async_trait::async_trait;
-use csv::Reader;
+use csv::{Reader, StringRecord};
 use serde::Deserialize;
 
 use super::Source;
-use crate::{user::User, zitadel::SourceDiff};
+use crate::{
+	grants::Grants,
+	sources::ldap::CacheCipher,
+	state::{SourceSnapshot, StateChanges, StateStore},
+	user::{StringOrBytes, User},
+	zitadel::{ChangedUser, SourceDiff, UserId},
+};
 
 /// CSV Source
 pub struct CsvSource {
 	/// CSV Source configuration
 	csv_config: CsvSourceConfig,
+	/// The users of the last sync, if they are kept in a state store
+	snapshot: Option<SourceSnapshot<Vec<User>>>,
 }
 
 #[async_trait]
@@ -23,18 +36,58 @@ impl Source for CsvSource {
 	}
 
 	async fn get_diff(&self) -> Result<SourceDiff> {
-		let new_users = self.read_csv()?;
-		// TODO: Implement changed and deleted users
-		// Holding off on this until we get rid of the cache concept
-		// https://github.com/famedly/ldap-sync/issues/53
-		return Ok(SourceDiff { new_users, changed_users: vec![], deleted_user_ids: vec![] });
+		let users = self.read_csv()?;
+
+		// Without a snapshot of the last sync, every user is new
+		let Some(snapshot) = &self.snapshot else {
+			return Ok(SourceDiff {
+				new_users: users,
+				changed_users: vec![],
+				deleted_user_ids: vec![],
+			});
+		};
+		let previous = snapshot.read().await?.unwrap_or_default();
+		snapshot.set_pending(users.clone());
+
+		Ok(diff_users(previous, users))
+	}
+
+	async fn get_all_users(&self) -> Result<Vec<User>> {
+		let users = self.read_csv()?;
+		if let Some(snapshot) = &self.snapshot {
+			snapshot.set_pending(users.clone());
+		}
+		Ok(users)
+	}
+
+	fn metadata_keys(&self) -> BTreeSet<String> {
+		self.csv_config.metadata.keys().cloned().collect()
+	}
+
+	async fn commit(&self, changes: &mut StateChanges) -> Result<()> {
+		match &self.snapshot {
+			Some(snapshot) => snapshot.commit(changes),
+			None => Ok(()),
+		}
 	}
 }
 
 impl CsvSource {
 	/// Create a new CSV source
 	pub fn new(csv_config: CsvSourceConfig) -> Self {
-		Self { csv_config }
+		Self { csv_config, snapshot: None }
+	}
+
+	/// Keep a snapshot of the synced users in the given state store, to
+	/// only report the users that changed since the last sync
+	#[must_use]
+	pub(crate) fn with_state(
+		self,
+		state: Arc<dyn StateStore>,
+		cipher: Option<CacheCipher>,
+	) -> Self {
+		let snapshot = SourceSnapshot::new(self.get_name(), state, cipher);
+		Self { snapshot: Some(snapshot), ..self }
 	}
 
 	/// Get list of users from CSV file
@@ -43,13 +96,40 @@ impl CsvSource {
 		let file = fs::File::open(&self.csv_config.file_path)
 			.context(format!("Failed to open CSV file {}", file_path.to_string_lossy()))?;
 		let mut reader = Reader::from_reader(file);
+		let headers = reader.headers().context("Failed to read CSV headers")?.clone();
 		Ok(reader
-			.deserialize()
-			.map(|r| r.inspect_err(|x| tracing::error!("Failed to deserialize: {x}")))
+			.records()
+			.map(|r| {
+				r.and_then(|record| {
+					let csv_data: CsvData = record.deserialize(Some(&headers))?;
+					let attributes = read_all_attributes(&headers, &record);
+					let metadata = self.read_metadata(&attributes);
+					Ok(csv_data.to_user(attributes, metadata))
+				})
+				.inspect_err(|x| tracing::error!("Failed to deserialize: {x}"))
+			})
 			.filter_map(Result::ok)
-			.map(CsvData::to_user)
 			.collect())
 	}
+
+	/// Collect the configured metadata from a record's columns, skipping
+	/// empty values
+	fn read_metadata(
+		&self,
+		attributes: &HashMap<String, Vec<StringOrBytes>>,
+	) -> BTreeMap<String, StringOrBytes> {
+		self.csv_config
+			.metadata
+			.iter()
+			.filter_map(|(key, column)| {
+				attributes
+					.get(&column.to_lowercase())
+					.and_then(|values| values.first())
+					.filter(|value| !value.to_string().is_empty())
+					.map(|value| (key.clone(), value.clone()))
+			})
+			.collect()
+	}
 }
 
 /// Configuration to get a list of users from a CSV file
@@ -57,6 +137,10 @@ impl CsvSource {
 pub struct CsvSourceConfig {
 	/// The path to the CSV file
 	pub file_path: PathBuf,
+	/// Columns to maintain as Zitadel user metadata, keyed by metadata
+	/// key
+	#[serde(default)]
+	pub metadata: BTreeMap<String, String>,
 }
 
 /// CSV data structure
@@ -74,7 +158,12 @@ struct CsvData {
 
 impl CsvData {
 	/// Convert CsvData to User data
-	fn to_user(csv_data: CsvData) -> User {
+	fn to_user(
+		self,
+		attributes: HashMap<String, Vec<StringOrBytes>>,
+		metadata: BTreeMap<String, StringOrBytes>,
+	) -> User {
+		let csv_data = self;
 		User {
 			email: csv_data.email.clone().into(),
 			first_name: csv_data.first_name.into(),
@@ -83,8 +172,46 @@ impl CsvData {
 			preferred_username: csv_data.email.clone().into(),
 			external_user_id: csv_data.email.into(),
 			enabled: true,
+			attributes,
+			metadata,
+			extra_grants: Grants::new(),
+			display_name: None,
+			localpart: None,
+			login_name: None,
+		}
+	}
+}
+
+/// Compare the users of the last sync with the current ones, keyed by
+/// external user ID
+fn diff_users(previous: Vec<User>, users: Vec<User>) -> SourceDiff {
+	let mut previous: BTreeMap<String, User> =
+		previous.into_iter().map(|user| (user.external_user_id.to_string(), user)).collect();
+
+	let mut diff =
+		SourceDiff { new_users: vec![], changed_users: vec![], deleted_user_ids: vec![] };
+	for user in users {
+		match previous.remove(&user.external_user_id.to_string()) {
+			None => diff.new_users.push(user),
+			Some(old) if old != user => diff.changed_users.push(ChangedUser { old, new: user }),
+			Some(_) => {}
 		}
 	}
+	diff.deleted_user_ids = previous.into_keys().map(UserId::Nick).collect();
+
+	diff
+}
+
+/// Collect all columns of a record, keyed by lowercase column name
+fn read_all_attributes(
+	headers: &StringRecord,
+	record: &StringRecord,
+) -> HashMap<String, Vec<StringOrBytes>> {
+	headers
+		.iter()
+		.zip(record.iter())
+		.map(|(name, value)| (name.to_lowercase(), vec![StringOrBytes::String(value.to_owned())]))
+		.collect()
 }
 
 /// Helper module for unit and e2e tests
@@ -114,9 +241,10 @@ pub mod test_helpers {
 mod tests {
 
 	use indoc::indoc;
+	use tempfile::TempDir;
 
 	use super::*;
-	use crate::{user::StringOrBytes, Config};
+	use crate::{state::SqliteStateStore, user::StringOrBytes, Config};
 
 	const EXAMPLE_CONFIG: &str = indoc! {r#"
         zitadel:
@@ -180,6 +308,91 @@ mod tests {
 		);
 	}
 
+	#[tokio::test]
+	async fn test_get_diff() {
+		let mut config = load_config();
+		let tempdir = TempDir::new().expect("failed to create temp dir");
+		let state: Arc<dyn StateStore> = Arc::new(
+			SqliteStateStore::open(&tempdir.path().join("state.db"), None)
+				.expect("failed to open state"),
+		);
+
+		let file = test_helpers::temp_csv_file(
+			&mut config,
+			indoc! {r#"
+              email,first_name,last_name,phone
+              john.doe@example.com,John,Doe,+1111111111
+              jane.smith@example.com,Jane,Smith,+2222222222
+            "#},
+		)
+		.expect("failed to write CSV file");
+
+		let csv_config = config.sources.csv.expect("CsvSource configuration is missing");
+		let csv = CsvSource::new(csv_config).with_state(state.clone(), None);
+
+		// Every user is new to a sync without a snapshot
+		let diff = csv.get_diff().await.expect("failed to get diff");
+		assert_eq!(diff.new_users.len(), 2);
+
+		// Until the snapshot is committed, the same users are reported
+		let diff = csv.get_diff().await.expect("failed to get diff");
+		assert_eq!(diff.new_users.len(), 2);
+
+		let mut changes = StateChanges::default();
+		csv.commit(&mut changes).await.expect("failed to commit snapshot");
+		state.commit(changes).await.expect("failed to commit state");
+
+		std::fs::write(
+			file.path(),
+			indoc! {r#"
+              email,first_name,last_name,phone
+              jane.smith@example.com,Jane,Doe,+2222222222
+              alice.johnson@example.com,Alice,Johnson,
+            "#},
+		)
+		.expect("failed to write CSV file");
+
+		let diff = csv.get_diff().await.expect("failed to get diff");
+		assert_eq!(diff.new_users.len(), 1);
+		assert_eq!(
+			diff.new_users[0].external_user_id,
+			StringOrBytes::String("alice.johnson@example.com".to_owned())
+		);
+		assert_eq!(diff.changed_users.len(), 1);
+		assert_eq!(diff.changed_users[0].old.last_name, StringOrBytes::String("Smith".to_owned()));
+		assert_eq!(diff.changed_users[0].new.last_name, StringOrBytes::String("Doe".to_owned()));
+		assert!(matches!(
+			diff.deleted_user_ids.as_slice(),
+			[UserId::Nick(nick)] if nick == "john.doe@example.com"
+		));
+	}
+
+	#[test]
+	fn test_get_users_metadata() {
+		let mut config = load_config();
+		let csv_content = indoc! {r#"
+          email,first_name,last_name,phone,Department
+          john.doe@example.com,John,Doe,+1111111111,Radiology
+          jane.smith@example.com,Jane,Smith,+2222222222,
+        "#};
+		let _file = test_helpers::temp_csv_file(&mut config, csv_content);
+
+		let mut csv_config = config.sources.csv.expect("CsvSource configuration is missing");
+		csv_config.metadata = BTreeMap::from([("department".to_owned(), "Department".to_owned())]);
+		let csv = CsvSource::new(csv_config);
+
+		let users = csv.read_csv().expect("Failed to get users");
+		assert_eq!(
+			users[0].metadata,
+			BTreeMap::from([(
+				"department".to_owned(),
+				StringOrBytes::String("Radiology".to_owned())
+			)])
+		);
+		assert!(users[1].metadata.is_empty(), "Empty columns must not become metadata");
+		assert_eq!(csv.metadata_keys(), BTreeSet::from(["department".to_owned()]));
+	}
+
 	#[test]
 	fn test_get_users_empty_file() {
 		let mut config = load_config();
@@ -1,8 +1,10 @@
 //! LDAP source for syncing with Famedly's Zitadel.
 
 use std::{
+	collections::{BTreeMap, BTreeSet, HashMap},
 	fmt::Display,
-	path::{Path, PathBuf},
+	path::PathBuf,
+	sync::{Arc, Mutex, PoisonError},
 };
 
 use anyhow::{anyhow, bail, Context, Result};
@@ -16,18 +18,39 @@ use tokio::sync::mpsc::Receiver;
 use tokio_stream::{wrappers::ReceiverStream, StreamExt};
 use url::Url;
 
+pub(crate) use self::encryption::CacheCipher;
+use self::{
+	cache::{decode_cache, encode_cache, read_cache, write_cache, CacheOrigin},
+	inspect::CacheView,
+};
+pub use self::{
+	encryption::{CacheEncryptionConfig, CacheKey},
+	inspect::CacheCommand,
+};
 use super::Source;
 use crate::{
+	grants::Grants,
+	state::{StateChanges, StateStore},
 	user::{StringOrBytes, User},
 	zitadel::{ChangedUser, SourceDiff, UserId},
 };
 
+mod cache;
+mod encryption;
+mod inspect;
+
 /// LDAP sync source
 pub struct LdapSource {
 	/// LDAP configuration
 	ldap_config: LdapSourceConfig,
 	/// Dry run flag (prevents writing cache)
 	is_dry_run: bool,
+	/// The cache resulting from the last sync, written once its changes
+	/// were applied
+	pending_cache: Mutex<Option<Cache>>,
+	/// The state store keeping the cache, if the cache is not kept in its
+	/// own file
+	state: Option<Arc<dyn StateStore>>,
 }
 
 #[async_trait]
@@ -37,35 +60,8 @@ impl Source for LdapSource {
 	}
 
 	async fn get_diff(&self) -> Result<SourceDiff> {
-		let cache = read_cache(&self.ldap_config.cache_path).await?;
-		let (mut ldap_client, ldap_receiver) = Ldap::new(self.ldap_config.clone().into(), cache);
-
-		let is_dry_run = self.is_dry_run;
-		let cache_path = self.ldap_config.cache_path.clone();
-
-		let sync_handle: tokio::task::JoinHandle<Result<_>> = tokio::spawn(async move {
-			ldap_client.sync_once(None).await.context("failed to sync/fetch data from LDAP")?;
-
-			if is_dry_run {
-				tracing::warn!("Not writing ldap cache during a dry run");
-			} else {
-				let cache = ldap_client.persist_cache().await;
-				tokio::fs::write(
-					&cache_path,
-					bincode::serialize(&cache).context("failed to serialize cache")?,
-				)
-				.await
-				.context("failed to write cache")?;
-			}
-
-			tracing::info!("Finished syncing LDAP data");
-
-			Ok(())
-		});
-
-		let (added, changed, removed) = self.get_user_changes(ldap_receiver).await?;
-
-		sync_handle.await??;
+		let cache = self.read_cache().await?;
+		let (added, changed, removed) = self.sync(cache).await?;
 
 		Ok(SourceDiff {
 			new_users: added,
@@ -73,12 +69,159 @@ impl Source for LdapSource {
 			deleted_user_ids: removed,
 		})
 	}
+
+	async fn get_all_users(&self) -> Result<Vec<User>> {
+		// Without a cache, every entry the LDAP server knows about is
+		// reported as new
+		let (added, _, _) = self.sync(None).await?;
+		Ok(added)
+	}
+
+	fn metadata_keys(&self) -> BTreeSet<String> {
+		self.ldap_config.attributes.metadata.keys().cloned().collect()
+	}
+
+	async fn commit(&self, changes: &mut StateChanges) -> Result<()> {
+		let Some(cache) = self.pending_cache.lock().unwrap_or_else(PoisonError::into_inner).take()
+		else {
+			return Ok(());
+		};
+
+		if self.is_dry_run {
+			tracing::warn!("Not writing ldap cache during a dry run");
+			return Ok(());
+		}
+
+		self.write_cache(&cache, changes).await
+	}
 }
 
 impl LdapSource {
 	/// Create a new LDAP source
 	pub fn new(ldap_config: LdapSourceConfig, is_dry_run: bool) -> Self {
-		Self { ldap_config, is_dry_run }
+		Self { ldap_config, is_dry_run, pending_cache: Mutex::new(None), state: None }
+	}
+
+	/// Keep the cache in the given state store rather than in its own
+	/// file
+	#[must_use]
+	pub(crate) fn with_state(self, state: Arc<dyn StateStore>) -> Self {
+		Self { state: Some(state), ..self }
+	}
+
+	/// Read the cache, from the state store if one is used
+	///
+	/// A cache file left from before the state store was used is
+	/// imported into it.
+	async fn read_cache(&self) -> Result<Option<Cache>> {
+		let cipher = self.ldap_config.cache_cipher()?;
+
+		if let Some(state) = &self.state {
+			if let Some(data) = state.snapshot(self.get_name()).await? {
+				return decode_cache(&data, &self.cache_origin(), cipher.as_ref()).map(Some);
+			}
+			tracing::info!("No LDAP sync state stored yet, importing the cache file if any");
+		}
+
+		read_cache(&self.ldap_config.cache_path, &self.cache_origin(), cipher.as_ref()).await
+	}
+
+	/// Write the cache, adding it to the given changes if it is kept in
+	/// the state store
+	async fn write_cache(&self, cache: &Cache, changes: &mut StateChanges) -> Result<()> {
+		let cipher = self.ldap_config.cache_cipher()?;
+
+		if self.state.is_some() {
+			let data = encode_cache(&self.cache_origin(), cache, cipher.as_ref())?;
+			changes.set_snapshot(self.get_name(), data);
+			return Ok(());
+		}
+
+		write_cache(&self.ldap_config.cache_path, &self.cache_origin(), cache, cipher.as_ref())
+			.await
+	}
+
+	/// The LDAP directory this source syncs from, as recorded in its cache
+	fn cache_origin(&self) -> CacheOrigin {
+		CacheOrigin {
+			url: self.ldap_config.url.to_string(),
+			base_dn: self.ldap_config.base_dn.clone(),
+		}
+	}
+
+	/// Inspect or edit the cache, describing the outcome as JSON
+	/// documents
+	pub(crate) async fn run_cache_command(&self, command: &CacheCommand) -> Result<Vec<String>> {
+		let path = &self.ldap_config.cache_path;
+		let cipher = self.ldap_config.cache_cipher()?;
+		let Some(cache) = self.read_cache().await? else {
+			bail!("there is no LDAP sync cache");
+		};
+		let mut view = CacheView::new(&cache)?;
+
+		let output = match command {
+			CacheCommand::Dump => vec![view.to_json()],
+			CacheCommand::Show(query) => view.find(query),
+			CacheCommand::Remove { query, all } => {
+				let removed = view.remove(query, *all)?;
+				if removed > 0 {
+					let cache: Cache = view.into_cache()?;
+					let mut changes = StateChanges::default();
+					self.write_cache(&cache, &mut changes).await?;
+					if let Some(state) = &self.state {
+						state.commit(changes).await?;
+					}
+				}
+				vec![serde_json::json!({ "removed": removed })]
+			}
+			CacheCommand::Stats => {
+				let (location, size) = match &self.state {
+					Some(state) => (
+						"state store".to_owned(),
+						state.snapshot(self.get_name()).await?.map_or(0, |data| data.len() as u64),
+					),
+					None => (path.display().to_string(), tokio::fs::metadata(path).await?.len()),
+				};
+				vec![serde_json::json!({
+					"path": location,
+					"size_bytes": size,
+					"encrypted": cipher.is_some(),
+					"url": self.ldap_config.url.as_str(),
+					"base_dn": self.ldap_config.base_dn,
+					"entries": view.entry_count(),
+				})]
+			}
+		};
+
+		output
+			.iter()
+			.map(|value| serde_json::to_string_pretty(value).context("failed to serialize output"))
+			.collect()
+	}
+
+	/// Sync with the LDAP server, starting from the given cache, keeping
+	/// the resulting cache to be written on [`Source::commit`]
+	async fn sync(
+		&self,
+		cache: Option<Cache>,
+	) -> Result<(Vec<User>, Vec<(User, User)>, Vec<UserId>)> {
+		let (mut ldap_client, ldap_receiver) = Ldap::new(self.ldap_config.clone().into(), cache);
+
+		let sync_handle: tokio::task::JoinHandle<Result<_>> = tokio::spawn(async move {
+			ldap_client.sync_once(None).await.context("failed to sync/fetch data from LDAP")?;
+			let cache = ldap_client.persist_cache().await;
+
+			tracing::info!("Finished syncing LDAP data");
+
+			Ok(cache)
+		});
+
+		let changes = self.get_user_changes(ldap_receiver).await?;
+
+		let cache = sync_handle.await??;
+		*self.pending_cache.lock().unwrap_or_else(PoisonError::into_inner) = Some(cache);
+
+		Ok(changes)
 	}
 
 	/// Get user changes from an ldap receiver
@@ -100,7 +243,7 @@ impl LdapSource {
 					}
 					EntryStatus::Removed(entry) => {
 						tracing::debug!("Deleted user {}", String::from_utf8_lossy(&entry));
-						removed.push(UserId::Nick(String::from_utf8(entry.clone())?));
+						removed.push(UserId::Nick(self.parse_removed_user_id(entry)?));
 					}
 				};
 				Ok((added, changed, removed))
@@ -108,6 +251,23 @@ impl LdapSource {
 			.await
 	}
 
+	/// Convert the ID of a removed entry to the external user ID as
+	/// stored in Zitadel
+	///
+	/// This must match how the `user_id` attribute of entries is read,
+	/// so binary IDs end up in the same base64 encoding.
+	fn parse_removed_user_id(&self, id: Vec<u8>) -> Result<String> {
+		let id = if self.ldap_config.attributes.user_id.is_binary() {
+			StringOrBytes::Bytes(id)
+		} else {
+			StringOrBytes::String(String::from_utf8(id).context(
+				"removed entry has a non-UTF-8 ID, the `user_id` attribute may need to be marked as binary",
+			)?)
+		};
+
+		Ok(id.to_string())
+	}
+
 	/// Construct a user from an LDAP SearchEntry
 	pub(crate) fn parse_user(&self, entry: SearchEntry) -> Result<User> {
 		let status_as_int = match read_search_entry(&entry, &self.ldap_config.attributes.status)? {
@@ -130,9 +290,21 @@ impl LdapSource {
 		let last_name = read_search_entry(&entry, &self.ldap_config.attributes.last_name)?;
 		let preferred_username =
 			read_search_entry(&entry, &self.ldap_config.attributes.preferred_username)?;
-		let email = read_search_entry(&entry, &self.ldap_config.attributes.email)?;
+		// Whether users may lack an email address depends on where their
+		// login names come from, which is left to the validation
+		let email = read_search_entry(&entry, &self.ldap_config.attributes.email)
+			.unwrap_or_else(|_| StringOrBytes::String(String::new()));
 		let ldap_user_id = read_search_entry(&entry, &self.ldap_config.attributes.user_id)?;
 		let phone = read_search_entry(&entry, &self.ldap_config.attributes.phone).ok();
+		let metadata = self
+			.ldap_config
+			.attributes
+			.metadata
+			.iter()
+			.filter_map(|(key, attribute)| {
+				read_search_entry(&entry, attribute).ok().map(|value| (key.clone(), value))
+			})
+			.collect();
 
 		Ok(User {
 			first_name,
@@ -142,8 +314,72 @@ impl LdapSource {
 			external_user_id: ldap_user_id,
 			phone,
 			enabled,
+			attributes: read_all_attributes(entry),
+			metadata,
+			extra_grants: Grants::new(),
+			display_name: None,
+			localpart: None,
+			login_name: None,
 		})
 	}
+
+	/// Construct users from sample records, given as a JSON array of
+	/// objects mapping attribute names to a value or an array of values
+	pub(crate) fn parse_sample_records(&self, records: &str) -> Result<Vec<User>> {
+		let records: Vec<HashMap<String, serde_json::Value>> =
+			serde_json::from_str(records).context("invalid sample records")?;
+
+		records
+			.into_iter()
+			.map(|mut record| {
+				let dn = match record.remove("dn") {
+					Some(serde_json::Value::String(dn)) => dn,
+					_ => String::new(),
+				};
+				let attrs = record
+					.into_iter()
+					.map(|(name, value)| {
+						let values = match value {
+							serde_json::Value::Array(values) => values,
+							value => vec![value],
+						};
+						let values = values
+							.into_iter()
+							.map(|value| match value {
+								serde_json::Value::String(value) => value,
+								value => value.to_string(),
+							})
+							.collect();
+						(name, values)
+					})
+					.collect();
+
+				self.parse_user(SearchEntry { dn, attrs, bin_attrs: HashMap::new() })
+			})
+			.collect()
+	}
+}
+
+/// Collect all attributes of the entry, keyed by lowercase name, along
+/// with the entry's DN as `dn`
+fn read_all_attributes(entry: SearchEntry) -> HashMap<String, Vec<StringOrBytes>> {
+	let mut attributes: HashMap<String, Vec<StringOrBytes>> =
+		HashMap::from([("dn".to_owned(), vec![StringOrBytes::String(entry.dn)])]);
+
+	for (name, values) in entry.attrs {
+		attributes
+			.entry(name.to_lowercase())
+			.or_default()
+			.extend(values.into_iter().map(StringOrBytes::String));
+	}
+	for (name, values) in entry.bin_attrs {
+		attributes
+			.entry(name.to_lowercase())
+			.or_default()
+			.extend(values.into_iter().map(StringOrBytes::Bytes));
+	}
+
+	attributes
 }
 
 /// Read an attribute from the entry
@@ -171,21 +407,6 @@ fn read_search_entry(entry: &SearchEntry, attribute: &AttributeMapping) -> Resul
 	bail!("missing `{}` values for `{}`", attribute, entry.dn)
 }
 
-/// Read the ldap sync cache
-pub async fn read_cache(path: &Path) -> Result<Option<Cache>> {
-	Ok(match tokio::fs::read(path).await {
-		Ok(data) => Some(bincode::deserialize(&data).context("cache deserialization failed")?),
-		Err(err) => {
-			if err.kind() == std::io::ErrorKind::NotFound {
-				tracing::info!("LDAP sync cache missing");
-				None
-			} else {
-				bail!(err)
-			}
-		}
-	})
-}
-
 /// LDAP-specific configuration
 #[derive(Debug, Clone, Deserialize, PartialEq)]
 pub struct LdapSourceConfig {
@@ -215,6 +436,22 @@ pub struct LdapSourceConfig {
 	pub tls: Option<LdapTlsConfig>,
 	/// Where to cache the last known LDAP state
 	pub cache_path: PathBuf,
+	/// Optional encryption of the cache
+	pub cache_encryption: Option<CacheEncryptionConfig>,
+}
+
+impl LdapSourceConfig {
+	/// Load the cache encryption keys, if encryption is configured
+	pub(crate) fn cache_cipher(&self) -> Result<Option<CacheCipher>> {
+		self.cache_encryption.as_ref().map(CacheCipher::new).transpose()
+	}
+
+	/// Check that the cache encryption keys can be loaded, and that an
+	/// existing cache fits the configured encryption
+	pub(crate) fn check_cache_encryption(&self) -> Result<()> {
+		let cipher = self.cache_cipher()?;
+		cache::check_encryption(&self.cache_path, cipher.as_ref())
+	}
 }
 
 impl From<LdapSourceConfig> for ldap_poller::Config {
@@ -253,16 +490,20 @@ impl From<LdapSourceConfig> for ldap_poller::Config {
 			attributes: AttributeConfig {
 				pid: attributes.user_id.get_name(),
 				updated: attributes.last_modified.map(AttributeMapping::get_name),
-				additional: vec![],
+				additional: attributes.additional.clone(),
 				filter_attributes: cfg.use_attribute_filter,
-				attrs_to_track: vec![
+				attrs_to_track: [
 					attributes.status.get_name(),
 					attributes.first_name.get_name(),
 					attributes.last_name.get_name(),
 					attributes.preferred_username.get_name(),
 					attributes.email.get_name(),
 					attributes.phone.get_name(),
-				],
+				]
+				.into_iter()
+				.chain(attributes.metadata.into_values().map(AttributeMapping::get_name))
+				.chain(attributes.additional)
+				.collect(),
 			},
 			cache_method: CacheMethod::ModificationTime,
 			check_for_deleted_entries: cfg.check_for_deleted_entries,
@@ -295,6 +536,14 @@ pub struct LdapAttributesMapping {
 	pub disable_bitmasks: Vec<i32>,
 	/// Last modified
 	pub last_modified: Option<AttributeMapping>,
+	/// Attributes to maintain as Zitadel user metadata, keyed by
+	/// metadata key; metadata is removed when the attribute disappears
+	#[serde(default)]
+	pub metadata: BTreeMap<String, AttributeMapping>,
+	/// Additional attributes to fetch and track changes of, e.g.
+	/// `memberOf` to use in grant conditions
+	#[serde(default)]
+	pub additional: Vec<String>,
 }
 
 /// How an attribute should be defined in config - it can either be a
@@ -325,6 +574,12 @@ impl AttributeMapping {
 			Self::OptionalBinary { name, .. } => name,
 		}
 	}
+
+	/// Whether the attribute is marked as binary
+	#[must_use]
+	pub fn is_binary(&self) -> bool {
+		matches!(self, Self::OptionalBinary { is_binary: true, .. })
+	}
 }
 
 impl Display for AttributeMapping {
@@ -365,14 +620,19 @@ pub struct LdapTlsConfig {
 
 #[cfg(test)]
 mod tests {
-	use std::collections::HashMap;
+	use std::collections::{BTreeMap, HashMap};
 
 	use indoc::indoc;
 	use ldap3::SearchEntry;
 	use ldap_poller::ldap::EntryStatus;
 	use tokio::sync::mpsc;
 
-	use crate::{sources::ldap::LdapSource, user::StringOrBytes, Config};
+	use crate::{
+		sources::ldap::{AttributeMapping, LdapSource},
+		user::StringOrBytes,
+		zitadel::UserId,
+		Config,
+	};
 
 	const EXAMPLE_CONFIG: &str = indoc! {r#"
         zitadel:
@@ -460,8 +720,7 @@ mod tests {
 	async fn test_get_user_changes_new_and_changed() {
 		let (tx, rx) = mpsc::channel(32);
 		let config = load_config();
-		let ldap_source =
-			LdapSource { ldap_config: config.sources.ldap.unwrap(), is_dry_run: false };
+		let ldap_source = LdapSource::new(config.sources.ldap.unwrap(), false);
 
 		let mut user = new_user();
 
@@ -518,8 +777,7 @@ mod tests {
 	async fn test_get_user_changes_removed() {
 		let (tx, rx) = mpsc::channel(32);
 		let config = load_config();
-		let ldap_source =
-			LdapSource { ldap_config: config.sources.ldap.unwrap(), is_dry_run: false };
+		let ldap_source = LdapSource::new(config.sources.ldap.unwrap(), false);
 
 		let user = new_user();
 
@@ -547,11 +805,45 @@ mod tests {
 		assert_eq!(removed.len(), 1, "Unexpected number of removed users");
 	}
 
+	#[tokio::test]
+	async fn test_get_user_changes_removed_binary_id() {
+		let (tx, rx) = mpsc::channel(32);
+		let mut config = load_config();
+		let ldap_config = config.sources.ldap.as_mut().expect("Expected LDAP config");
+		ldap_config.attributes.user_id =
+			AttributeMapping::OptionalBinary { name: "objectGUID".to_owned(), is_binary: true };
+		let ldap_source = LdapSource::new(config.sources.ldap.unwrap(), false);
+
+		// Not valid UTF-8, like most AD objectGUIDs
+		let object_guid = vec![0x8E, 0x3A, 0xA0, 0xA1, 0x00, 0xFF, 0x12, 0x34];
+
+		let mut attrs = new_user();
+		attrs.remove("uid");
+
+		tx.send(EntryStatus::New(SearchEntry {
+			dn: "uid=testuser,ou=testorg,dc=example,dc=org".to_owned(),
+			attrs,
+			bin_attrs: HashMap::from([("objectGUID".to_owned(), vec![object_guid.clone()])]),
+		}))
+		.await
+		.unwrap();
+		tx.send(EntryStatus::Removed(object_guid.clone())).await.unwrap();
+		drop(tx);
+
+		let (added, _, removed) =
+			ldap_source.get_user_changes(rx).await.expect("Failed to get user changes");
+
+		assert_eq!(added[0].external_user_id, StringOrBytes::Bytes(object_guid));
+		match &removed[..] {
+			[UserId::Nick(nick)] => assert_eq!(nick, &added[0].external_user_id.to_string()),
+			_ => panic!("Unexpected removed users: {:?}", removed),
+		}
+	}
+
 	#[tokio::test]
 	async fn test_parse_user() {
 		let config = load_config();
-		let ldap_source =
-			LdapSource { ldap_config: config.sources.ldap.unwrap(), is_dry_run: false };
+		let ldap_source = LdapSource::new(config.sources.ldap.unwrap(), false);
 
 		let entry = SearchEntry {
 			dn: "uid=testuser,ou=testorg,dc=example,dc=org".to_owned(),
@@ -571,4 +863,85 @@ mod tests {
 		assert_eq!(user.external_user_id, StringOrBytes::String("testuser".to_owned()));
 		assert!(user.enabled);
 	}
+
+	#[tokio::test]
+	async fn test_parse_user_without_email() {
+		let config = load_config();
+		let ldap_source = LdapSource::new(config.sources.ldap.unwrap(), false);
+
+		let mut attrs = new_user();
+		attrs.remove("mail");
+		let entry = SearchEntry {
+			dn: "uid=testuser,ou=testorg,dc=example,dc=org".to_owned(),
+			attrs,
+			bin_attrs: HashMap::new(),
+		};
+
+		let user = ldap_source.parse_user(entry).expect("Failed to parse user");
+		assert_eq!(user.email, StringOrBytes::String(String::new()));
+	}
+
+	#[tokio::test]
+	async fn test_parse_user_metadata() {
+		let config = load_config();
+		let mut ldap_config = config.sources.ldap.expect("Expected LDAP config");
+		ldap_config.attributes.metadata = BTreeMap::from([
+			(
+				"department".to_owned(),
+				AttributeMapping::NoBinaryOption("departmentNumber".to_owned()),
+			),
+			("cost_centre".to_owned(), AttributeMapping::NoBinaryOption("costCentre".to_owned())),
+			(
+				"employee_id".to_owned(),
+				AttributeMapping::OptionalBinary { name: "employeeID".to_owned(), is_binary: true },
+			),
+		]);
+		let ldap_source = LdapSource::new(ldap_config, false);
+
+		let mut attrs = new_user();
+		attrs.insert("departmentNumber".to_owned(), vec!["Radiology".to_owned()]);
+
+		let entry = SearchEntry {
+			dn: "uid=testuser,ou=testorg,dc=example,dc=org".to_owned(),
+			attrs,
+			bin_attrs: HashMap::from([("employeeID".to_owned(), vec![vec![0xA0, 0xA1]])]),
+		};
+
+		let user = ldap_source.parse_user(entry).expect("Failed to parse user");
+		assert_eq!(
+			user.metadata,
+			BTreeMap::from([
+				("department".to_owned(), StringOrBytes::String("Radiology".to_owned())),
+				("employee_id".to_owned(), StringOrBytes::Bytes(vec![0xA0, 0xA1])),
+			])
+		);
+	}
+
+	#[test]
+	fn test_parse_sample_records() {
+		let config = load_config();
+		let ldap_source = LdapSource::new(config.sources.ldap.expect("Expected LDAP config"), true);
+
+		let users = ldap_source
+			.parse_sample_records(indoc! {r#"
+                [{
+                    "dn": "uid=testuser,ou=testorg,dc=example,dc=org",
+                    "cn": "Test",
+                    "sn": ["User"],
+                    "displayName": "testuser",
+                    "mail": "testuser@example.com",
+                    "uid": "testuser",
+                    "shadowFlag": 0
+                }]
+            "#})
+			.expect("Failed to parse sample records");
+
+		assert_eq!(users.len(), 1);
+		assert_eq!(users[0].last_name, StringOrBytes::String("User".to_owned()));
+		assert!(users[0].enabled);
+		assert_eq!(
+			users[0].attribute_values("dn"),
+			[StringOrBytes::String("uid=testuser,ou=testorg,dc=example,dc=org".to_owned())]
+		);
+	}
 }
@@ -0,0 +1,445 @@
+//! Crash-safe persistence of the LDAP sync cache
+//!
+//! The cache is written to a temporary file which is synced to disk and
+//! then renamed over the cache, so a crash never leaves a truncated
+//! cache behind. The previous cache is kept as a backup, which is used
+//! if the cache turns out to be corrupt anyway.
+//!
+//! Caches start with [`MAGIC`] and their format version, followed by the
+//! LDAP directory they were created from and the cache itself. Caches
+//! written by older versions are migrated when read.
+//!
+//! If encryption is configured, the whole cache is encrypted, see
+//! [`CacheCipher`].
+//!
+//! If a state store is configured, the cache is kept there instead of in
+//! a file, encoded the same way.
+use std::{
+	borrow::Cow,
+	ffi::OsString,
+	io::ErrorKind,
+	path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use super::encryption::{CacheCipher, ENCRYPTED_MAGIC};
+
+/// Marks a file as an LDAP sync cache
+const MAGIC: &[u8; 8] = b"FLSCACHE";
+
+/// The current version of the cache format
+///
+/// Bump this whenever the format of the cache changes, and teach
+/// [`decode`] to migrate caches of the previous version.
+const CACHE_VERSION: u32 = 1;
+
+/// The LDAP directory a cache was created from
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct CacheOrigin {
+	/// The URL of the LDAP server
+	pub(crate) url: String,
+	/// The base DN users were searched in
+	pub(crate) base_dn: String,
+}
+
+/// Read the LDAP sync cache, falling back to its backup if the cache is
+/// corrupt
+///
+/// Fails if the cache was created from a different LDAP directory than
+/// the given one.
+pub(crate) async fn read_cache<T: DeserializeOwned>(
+	path: &Path,
+	origin: &CacheOrigin,
+	cipher: Option<&CacheCipher>,
+) -> Result<Option<T>> {
+	let Some(data) = read_file(path).await? else {
+		tracing::info!("LDAP sync cache missing");
+		return Ok(None);
+	};
+
+	let (found_origin, cache) = match decode(&data, cipher) {
+		Ok(decoded) => decoded,
+		Err(error) => {
+			let backup_path = with_suffix(path, ".bak");
+			let Some(backup) = read_file(&backup_path).await? else {
+				return Err(error);
+			};
+
+			let Ok(decoded) = decode(&backup, cipher) else {
+				return Err(
+					error.context(format!("backup {} is corrupt too", backup_path.display()))
+				);
+			};
+			tracing::warn!(
+				"LDAP sync cache {} is corrupt, using its backup {}: {:?}",
+				path.display(),
+				backup_path.display(),
+				error
+			);
+			decoded
+		}
+	};
+
+	check_origin(&format!("LDAP sync cache {}", path.display()), found_origin, origin)?;
+	Ok(Some(cache))
+}
+
+/// Write the LDAP sync cache, keeping the previous cache as backup
+pub(crate) async fn write_cache<T: Serialize + Sync>(
+	path: &Path,
+	origin: &CacheOrigin,
+	cache: &T,
+	cipher: Option<&CacheCipher>,
+) -> Result<()> {
+	let data = encode_cache(origin, cache, cipher)?;
+
+	let temp_path = with_suffix(path, ".tmp");
+	write_synced(&temp_path, &data).await.context("failed to write cache")?;
+
+	// Copy rather than move the previous cache, so there is a complete
+	// cache in place at all times
+	let backup_path = with_suffix(path, ".bak");
+	match read_file(path).await? {
+		// Never keep an unencrypted backup once the cache is encrypted
+		Some(previous) if cipher.is_some() && !previous.starts_with(ENCRYPTED_MAGIC) => {
+			match tokio::fs::remove_file(&backup_path).await {
+				Ok(()) => {}
+				Err(err) if err.kind() == ErrorKind::NotFound => {}
+				Err(err) => return Err(err).context("failed to remove unencrypted backup"),
+			}
+		}
+		Some(_) => {
+			let backup_temp_path = with_suffix(path, ".bak.tmp");
+			tokio::fs::copy(path, &backup_temp_path).await.context("failed to back up cache")?;
+			tokio::fs::rename(&backup_temp_path, &backup_path)
+				.await
+				.context("failed to back up cache")?;
+		}
+		None => {}
+	}
+
+	tokio::fs::rename(&temp_path, path).await.context("failed to replace cache")?;
+	sync_parent(path).await.context("failed to sync cache directory")?;
+
+	Ok(())
+}
+
+/// Encode the LDAP sync cache in the current format, as it is written to
+/// the cache file or the state store
+pub(crate) fn encode_cache<T: Serialize>(
+	origin: &CacheOrigin,
+	cache: &T,
+	cipher: Option<&CacheCipher>,
+) -> Result<Vec<u8>> {
+	let mut data = MAGIC.to_vec();
+	data.extend_from_slice(&CACHE_VERSION.to_le_bytes());
+	bincode::serialize_into(&mut data, &(origin, cache)).context("failed to serialize cache")?;
+
+	match cipher {
+		Some(cipher) => cipher.encrypt(&data),
+		None => Ok(data),
+	}
+}
+
+/// Decode the LDAP sync cache as kept in the state store
+///
+/// Fails if the cache was created from a different LDAP directory than
+/// the given one.
+pub(crate) fn decode_cache<T: DeserializeOwned>(
+	data: &[u8],
+	origin: &CacheOrigin,
+	cipher: Option<&CacheCipher>,
+) -> Result<T> {
+	let (found_origin, cache) = decode(data, cipher)?;
+	check_origin("LDAP sync state", found_origin, origin)?;
+	Ok(cache)
+}
+
+/// Fail if a cache was created from a different LDAP directory than the
+/// configured one
+fn check_origin(
+	description: &str,
+	found_origin: Option<CacheOrigin>,
+	origin: &CacheOrigin,
+) -> Result<()> {
+	match found_origin {
+		Some(found_origin) if found_origin != *origin => bail!(
+			"{} belongs to `{}` with base DN `{}`, but `{}` with base DN `{}` is configured; \
+			 remove it to sync from scratch",
+			description,
+			found_origin.url,
+			found_origin.base_dn,
+			origin.url,
+			origin.base_dn
+		),
+		Some(_) => {}
+		None => tracing::info!("Migrating {} without recorded origin", description),
+	}
+
+	Ok(())
+}
+
+/// Check that an existing cache fits the configured encryption, so the
+/// sync refuses to start otherwise
+pub(crate) fn check_encryption(path: &Path, cipher: Option<&CacheCipher>) -> Result<()> {
+	match std::fs::read(path) {
+		Ok(data) => decrypt(&data, cipher).map(drop),
+		Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+		Err(err) => Err(err).context(format!("failed to read LDAP sync cache {}", path.display())),
+	}
+}
+
+/// Decrypt a cache if it is encrypted, failing if that does not match
+/// the configured encryption
+fn decrypt<'a>(data: &'a [u8], cipher: Option<&CacheCipher>) -> Result<Cow<'a, [u8]>> {
+	match (data.strip_prefix(ENCRYPTED_MAGIC), cipher) {
+		(Some(encrypted), Some(cipher)) => Ok(Cow::Owned(cipher.decrypt(encrypted)?)),
+		(Some(_), None) => bail!("cache is encrypted, but cache encryption is not configured"),
+		(None, Some(cipher)) if !cipher.migrate_unencrypted => bail!(
+			"cache is not encrypted; set `cache_encryption.migrate_unencrypted` to encrypt it, \
+			 or remove it to sync from scratch"
+		),
+		(None, _) => Ok(Cow::Borrowed(data)),
+	}
+}
+
+/// Decode a cache of any known version, along with its origin if it was
+/// recorded
+fn decode<T: DeserializeOwned>(
+	data: &[u8],
+	cipher: Option<&CacheCipher>,
+) -> Result<(Option<CacheOrigin>, T)> {
+	let data = decrypt(data, cipher)?;
+
+	// Caches written before versioning are a plain dump of the cache
+	let Some(data) = data.strip_prefix(MAGIC) else {
+		let cache = bincode::deserialize(&data).context("cache deserialization failed")?;
+		return Ok((None, cache));
+	};
+
+	let Some((version, payload)) = data.split_first_chunk() else {
+		bail!("cache is truncated");
+	};
+
+	match u32::from_le_bytes(*version) {
+		CACHE_VERSION => {
+			let (origin, cache) =
+				bincode::deserialize(payload).context("cache deserialization failed")?;
+			Ok((Some(origin), cache))
+		}
+		version => bail!(
+			"cache version {} is not supported, this version of the sync supports up to {}",
+			version,
+			CACHE_VERSION
+		),
+	}
+}
+
+/// Read a file, if it exists
+async fn read_file(path: &Path) -> Result<Option<Vec<u8>>> {
+	match tokio::fs::read(path).await {
+		Ok(data) => Ok(Some(data)),
+		Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+		Err(err) => bail!(err),
+	}
+}
+
+/// Write a file and wait until its contents are on disk
+async fn write_synced(path: &Path, data: &[u8]) -> Result<()> {
+	tokio::fs::write(path, data).await?;
+	tokio::fs::File::open(path).await?.sync_all().await?;
+	Ok(())
+}
+
+/// Wait until renames in the directory containing a file are on disk
+async fn sync_parent(path: &Path) -> Result<()> {
+	match path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+		Some(parent) => tokio::fs::File::open(parent).await?.sync_all().await?,
+		None => tokio::fs::File::open(".").await?.sync_all().await?,
+	}
+	Ok(())
+}
+
+/// Append a suffix to the file name of a path
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+	let mut path = OsString::from(path);
+	path.push(suffix);
+	path.into()
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::BTreeMap;
+
+	use base64::{engine::general_purpose::STANDARD, Engine};
+	use tempfile::TempDir;
+
+	use super::*;
+	use crate::sources::ldap::{CacheEncryptionConfig, CacheKey};
+
+	type TestCache = BTreeMap<String, String>;
+
+	fn cache(version: &str) -> TestCache {
+		BTreeMap::from([("version".to_owned(), version.to_owned())])
+	}
+
+	fn origin() -> CacheOrigin {
+		CacheOrigin { url: "ldap://localhost:1389/".to_owned(), base_dn: "dc=example".to_owned() }
+	}
+
+	#[tokio::test]
+	async fn test_cache_roundtrip() {
+		let tempdir = TempDir::new().expect("failed to create temp dir");
+		let path = tempdir.path().join("cache.bin");
+
+		let missing: Option<TestCache> =
+			read_cache(&path, &origin(), None).await.expect("failed to read missing cache");
+		assert_eq!(missing, None);
+
+		write_cache(&path, &origin(), &cache("1"), None).await.expect("failed to write cache");
+		write_cache(&path, &origin(), &cache("2"), None).await.expect("failed to write cache");
+
+		assert!(std::fs::read(&path).expect("failed to read cache").starts_with(MAGIC));
+		assert_eq!(
+			read_cache(&path, &origin(), None).await.expect("failed to read cache"),
+			Some(cache("2"))
+		);
+		assert_eq!(
+			read_cache(&with_suffix(&path, ".bak"), &origin(), None)
+				.await
+				.expect("failed to read backup"),
+			Some(cache("1"))
+		);
+		assert!(!with_suffix(&path, ".tmp").exists());
+	}
+
+	#[tokio::test]
+	async fn test_cache_corrupt() {
+		let tempdir = TempDir::new().expect("failed to create temp dir");
+		let path = tempdir.path().join("cache.bin");
+
+		write_cache(&path, &origin(), &cache("1"), None).await.expect("failed to write cache");
+		write_cache(&path, &origin(), &cache("2"), None).await.expect("failed to write cache");
+
+		// A truncated cache falls back to the backup
+		let data = std::fs::read(&path).expect("failed to read cache");
+		std::fs::write(&path, &data[..data.len() / 2]).expect("failed to truncate cache");
+		assert_eq!(
+			read_cache(&path, &origin(), None).await.expect("failed to read cache"),
+			Some(cache("1"))
+		);
+
+		// Without a usable backup, the run must not continue
+		std::fs::write(with_suffix(&path, ".bak"), b"").expect("failed to truncate backup");
+		assert!(read_cache::<TestCache>(&path, &origin(), None).await.is_err());
+
+		std::fs::remove_file(with_suffix(&path, ".bak")).expect("failed to remove backup");
+		assert!(read_cache::<TestCache>(&path, &origin(), None).await.is_err());
+	}
+
+	#[tokio::test]
+	async fn test_cache_versions() {
+		let tempdir = TempDir::new().expect("failed to create temp dir");
+		let path = tempdir.path().join("cache.bin");
+
+		// Caches from before versioning are read as they are
+		std::fs::write(&path, bincode::serialize(&cache("0")).expect("failed to serialize"))
+			.expect("failed to write legacy cache");
+		assert_eq!(
+			read_cache(&path, &origin(), None).await.expect("failed to read legacy cache"),
+			Some(cache("0"))
+		);
+
+		// Caches from newer versions are rejected
+		let mut data = MAGIC.to_vec();
+		data.extend_from_slice(&(CACHE_VERSION + 1).to_le_bytes());
+		std::fs::write(&path, data).expect("failed to write future cache");
+		let error = read_cache::<TestCache>(&path, &origin(), None)
+			.await
+			.expect_err("future caches must be rejected");
+		assert!(error.to_string().contains("not supported"));
+	}
+
+	#[tokio::test]
+	async fn test_cache_origin_mismatch() {
+		let tempdir = TempDir::new().expect("failed to create temp dir");
+		let path = tempdir.path().join("cache.bin");
+
+		write_cache(&path, &origin(), &cache("1"), None).await.expect("failed to write cache");
+
+		let other = CacheOrigin { base_dn: "dc=other".to_owned(), ..origin() };
+		let error = read_cache::<TestCache>(&path, &other, None)
+			.await
+			.expect_err("caches of other directories must be rejected");
+		assert!(error.to_string().contains("dc=other"));
+	}
+
+	#[test]
+	fn test_cache_encoding() {
+		let data = encode_cache(&origin(), &cache("1"), None).expect("failed to encode cache");
+		assert_eq!(
+			decode_cache::<TestCache>(&data, &origin(), None).expect("failed to decode cache"),
+			cache("1")
+		);
+
+		let other = CacheOrigin { url: "ldap://other:1389/".to_owned(), ..origin() };
+		assert!(decode_cache::<TestCache>(&data, &other, None).is_err());
+	}
+
+	#[tokio::test]
+	async fn test_cache_encryption() {
+		let tempdir = TempDir::new().expect("failed to create temp dir");
+		let path = tempdir.path().join("cache.bin");
+		let key_path = tempdir.path().join("cache.key");
+		std::fs::write(&key_path, STANDARD.encode([7; 32])).expect("failed to write key");
+
+		let cipher = |migrate_unencrypted| {
+			CacheCipher::new(&CacheEncryptionConfig {
+				keys: vec![CacheKey::File(key_path.clone())],
+				migrate_unencrypted,
+			})
+			.expect("failed to load key")
+		};
+
+		write_cache(&path, &origin(), &cache("1"), None).await.expect("failed to write cache");
+		write_cache(&path, &origin(), &cache("2"), None).await.expect("failed to write cache");
+
+		// Unencrypted caches are only used if migration was asked for
+		let error = check_encryption(&path, Some(&cipher(false)))
+			.expect_err("unencrypted caches must be rejected");
+		assert!(error.to_string().contains("not encrypted"));
+		assert!(read_cache::<TestCache>(&path, &origin(), Some(&cipher(false))).await.is_err());
+		assert_eq!(
+			read_cache(&path, &origin(), Some(&cipher(true))).await.expect("failed to migrate"),
+			Some(cache("2"))
+		);
+
+		// The unencrypted backup is not kept
+		write_cache(&path, &origin(), &cache("3"), Some(&cipher(true)))
+			.await
+			.expect("failed to write cache");
+		assert!(std::fs::read(&path).expect("failed to read cache").starts_with(ENCRYPTED_MAGIC));
+		assert!(!with_suffix(&path, ".bak").exists());
+
+		write_cache(&path, &origin(), &cache("4"), Some(&cipher(false)))
+			.await
+			.expect("failed to write cache");
+		check_encryption(&path, Some(&cipher(false))).expect("encrypted cache must be accepted");
+		assert_eq!(
+			read_cache(&path, &origin(), Some(&cipher(false))).await.expect("failed to read cache"),
+			Some(cache("4"))
+		);
+		assert_eq!(
+			read_cache(&with_suffix(&path, ".bak"), &origin(), Some(&cipher(false)))
+				.await
+				.expect("failed to read backup"),
+			Some(cache("3"))
+		);
+
+		// Encrypted caches cannot be read without the key
+		assert!(check_encryption(&path, None).is_err());
+		assert!(read_cache::<TestCache>(&path, &origin(), None).await.is_err());
+	}
+}
@@ -0,0 +1,204 @@
+//! Authenticated encryption of the LDAP sync cache at rest
+use std::{fmt, path::PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chacha20poly1305::{
+	aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+	XChaCha20Poly1305, XNonce,
+};
+use serde::Deserialize;
+
+/// Marks a file as an encrypted LDAP sync cache
+pub(super) const ENCRYPTED_MAGIC: &[u8; 8] = b"FLSCRYPT";
+
+/// The length of the nonce preceding the encrypted cache
+const NONCE_LENGTH: usize = 24;
+
+/// Encryption of the LDAP sync cache
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct CacheEncryptionConfig {
+	/// The keys, each 32 random bytes encoded as base64. The cache is
+	/// encrypted with the first key, and may be decrypted with any of
+	/// them, so keys can be rotated.
+	pub keys: Vec<CacheKey>,
+	/// Whether to encrypt an existing unencrypted cache, rather than
+	/// refusing to use it
+	#[serde(default)]
+	pub migrate_unencrypted: bool,
+}
+
+/// Where to read a cache encryption key from
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CacheKey {
+	/// A file containing the key
+	File(PathBuf),
+	/// An environment variable containing the key
+	Env(String),
+}
+
+impl CacheKey {
+	/// Read the key
+	fn load(&self) -> Result<XChaCha20Poly1305> {
+		let (encoded, location) = match self {
+			Self::File(path) => (
+				std::fs::read_to_string(path)
+					.context(format!("failed to read cache key file {}", path.display()))?,
+				format!("file {}", path.display()),
+			),
+			Self::Env(name) => (
+				std::env::var(name)
+					.context(format!("failed to read cache key variable `{name}`"))?,
+				format!("variable `{name}`"),
+			),
+		};
+
+		let key = STANDARD
+			.decode(encoded.trim())
+			.context(format!("cache key in {location} is not valid base64"))?;
+		XChaCha20Poly1305::new_from_slice(&key)
+			.map_err(|_| anyhow!("cache key in {} must be 32 bytes long", location))
+	}
+}
+
+/// Encrypts and decrypts the LDAP sync cache
+pub(crate) struct CacheCipher {
+	/// The ciphers of the configured keys, the first one encrypting
+	keys: Vec<XChaCha20Poly1305>,
+	/// Whether unencrypted caches are accepted, to be encrypted when
+	/// written next
+	pub(super) migrate_unencrypted: bool,
+}
+
+impl fmt::Debug for CacheCipher {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("CacheCipher")
+			.field("keys", &self.keys.len())
+			.field("migrate_unencrypted", &self.migrate_unencrypted)
+			.finish()
+	}
+}
+
+impl CacheCipher {
+	/// Load the configured keys
+	pub(crate) fn new(config: &CacheEncryptionConfig) -> Result<Self> {
+		if config.keys.is_empty() {
+			bail!("cache encryption requires at least one key");
+		}
+
+		Ok(Self {
+			keys: config.keys.iter().map(CacheKey::load).collect::<Result<_>>()?,
+			migrate_unencrypted: config.migrate_unencrypted,
+		})
+	}
+
+	/// Encrypt a cache with the first key
+	pub(crate) fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
+		let Some(key) = self.keys.first() else {
+			bail!("cache encryption requires at least one key");
+		};
+
+		let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+		let encrypted = key
+			.encrypt(&nonce, Payload { msg: data, aad: ENCRYPTED_MAGIC })
+			.map_err(|_| anyhow!("failed to encrypt cache"))?;
+
+		Ok([ENCRYPTED_MAGIC.as_slice(), nonce.as_slice(), &encrypted].concat())
+	}
+
+	/// Decrypt a cache, without [`ENCRYPTED_MAGIC`], with any of the
+	/// keys
+	pub(super) fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
+		if data.len() < NONCE_LENGTH {
+			bail!("encrypted cache is truncated");
+		}
+		let (nonce, encrypted) = data.split_at(NONCE_LENGTH);
+		let nonce = XNonce::from_slice(nonce);
+
+		self.keys
+			.iter()
+			.find_map(|key| {
+				key.decrypt(nonce, Payload { msg: encrypted, aad: ENCRYPTED_MAGIC }).ok()
+			})
+			.ok_or_else(|| {
+				anyhow!("failed to decrypt cache, it is corrupt or none of the keys fit")
+			})
+	}
+
+	/// Decrypt data encrypted by [`Self::encrypt`], with any of the keys
+	pub(crate) fn open(&self, data: &[u8]) -> Result<Vec<u8>> {
+		match data.strip_prefix(ENCRYPTED_MAGIC) {
+			Some(encrypted) => self.decrypt(encrypted),
+			None => bail!("data is not encrypted"),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io::Write;
+
+	use tempfile::NamedTempFile;
+
+	use super::*;
+
+	fn key_file(key: &[u8]) -> NamedTempFile {
+		let mut file = NamedTempFile::new().expect("failed to create key file");
+		writeln!(file, "{}", STANDARD.encode(key)).expect("failed to write key file");
+		file
+	}
+
+	fn cipher(files: &[&NamedTempFile]) -> CacheCipher {
+		CacheCipher::new(&CacheEncryptionConfig {
+			keys: files.iter().map(|file| CacheKey::File(file.path().to_owned())).collect(),
+			migrate_unencrypted: false,
+		})
+		.expect("failed to load keys")
+	}
+
+	#[test]
+	fn test_cache_encryption() {
+		let old_key = key_file(&[1; 32]);
+		let new_key = key_file(&[2; 32]);
+
+		let encrypted = cipher(&[&old_key]).encrypt(b"cache").expect("failed to encrypt");
+		assert!(encrypted.starts_with(ENCRYPTED_MAGIC));
+		assert_eq!(cipher(&[&old_key]).open(&encrypted).expect("failed to decrypt"), b"cache");
+		let encrypted = &encrypted[ENCRYPTED_MAGIC.len()..];
+		assert!(cipher(&[&old_key]).open(encrypted).is_err());
+
+		// Caches encrypted with previous keys can still be read
+		let rotated = cipher(&[&new_key, &old_key]);
+		assert_eq!(rotated.decrypt(encrypted).expect("failed to decrypt"), b"cache");
+		assert!(cipher(&[&new_key]).decrypt(encrypted).is_err());
+
+		// Tampering is detected
+		let mut tampered = encrypted.to_vec();
+		let last = tampered.len() - 1;
+		tampered[last] ^= 1;
+		assert!(rotated.decrypt(&tampered).is_err());
+	}
+
+	#[test]
+	fn test_cache_keys() {
+		let short_key = key_file(&[1; 16]);
+		assert!(CacheCipher::new(&CacheEncryptionConfig {
+			keys: vec![CacheKey::File(short_key.path().to_owned())],
+			migrate_unencrypted: false,
+		})
+		.is_err());
+
+		assert!(CacheCipher::new(&CacheEncryptionConfig {
+			keys: vec![CacheKey::Env("FAMEDLY_LDAP_SYNC_TEST_MISSING_CACHE_KEY".to_owned())],
+			migrate_unencrypted: false,
+		})
+		.is_err());
+
+		assert!(CacheCipher::new(&CacheEncryptionConfig {
+			keys: vec![],
+			migrate_unencrypted: false
+		})
+		.is_err());
+	}
+}
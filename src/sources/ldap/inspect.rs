@@ -0,0 +1,321 @@
+//! Inspection and editing of the LDAP sync cache
+//!
+//! The layout of the cache is up to `ldap_poller`, so the cache is
+//! inspected through its serde representation. Its entries are the map
+//! entries keyed by the ID of an LDAP entry, wherever the cache keeps
+//! them.
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_value::Value;
+
+/// A command inspecting or editing the LDAP sync cache
+#[derive(Debug, Clone, PartialEq)]
+pub enum CacheCommand {
+	/// Print the whole cache as JSON
+	Dump,
+	/// Print the entries with the given ID or DN
+	Show(String),
+	/// Remove the entry with the given ID or DN, so it is synced again
+	Remove {
+		/// The ID or DN of the entry
+		query: String,
+		/// Whether to remove all entries if several match
+		all: bool,
+	},
+	/// Print statistics about the cache
+	Stats,
+}
+
+/// A cache in its serde representation
+#[derive(Debug, Clone)]
+pub(crate) struct CacheView {
+	/// The serialized cache
+	value: Value,
+}
+
+impl CacheView {
+	/// Create a view of a cache
+	pub(crate) fn new<T: Serialize>(cache: &T) -> Result<Self> {
+		Ok(Self { value: serde_value::to_value(cache).context("failed to inspect cache")? })
+	}
+
+	/// Convert the view back into a cache
+	pub(crate) fn into_cache<T: DeserializeOwned>(self) -> Result<T> {
+		self.value.deserialize_into().context("failed to restore cache")
+	}
+
+	/// The whole cache as JSON
+	pub(crate) fn to_json(&self) -> serde_json::Value {
+		to_json(&self.value)
+	}
+
+	/// The number of entries in the cache
+	pub(crate) fn entry_count(&self) -> usize {
+		let mut count = 0;
+		visit_entries(&self.value, &mut |_, _| count += 1);
+		count
+	}
+
+	/// The entries with the given ID or DN as JSON objects of their ID
+	/// and cached state
+	pub(crate) fn find(&self, query: &str) -> Vec<serde_json::Value> {
+		let mut found = Vec::new();
+		visit_entries(&self.value, &mut |id, value| {
+			if matches_query(id, value, query) {
+				found.push(serde_json::json!({ "id": bytes_to_json(id), "entry": to_json(value) }));
+			}
+		});
+		found
+	}
+
+	/// Remove the entry with the given ID or DN, returning how many
+	/// entries were removed
+	///
+	/// Fails if several entries match, unless all of them are to be
+	/// removed.
+	pub(crate) fn remove(&mut self, query: &str, all: bool) -> Result<usize> {
+		let matches = self.find(query).len();
+		if matches > 1 && !all {
+			bail!("{matches} cache entries match `{query}`, pass --all to remove all of them");
+		}
+
+		Ok(remove_entries(&mut self.value, query))
+	}
+}
+
+/// Call a function on every entry of a cache, with the ID and the
+/// cached state of the entry
+fn visit_entries(value: &Value, visit: &mut impl FnMut(&[u8], &Value)) {
+	match value {
+		Value::Map(map) => {
+			for (key, value) in map {
+				match as_bytes(key) {
+					Some(id) => visit(&id, value),
+					None => visit_entries(value, visit),
+				}
+			}
+		}
+		Value::Seq(values) => values.iter().for_each(|value| visit_entries(value, visit)),
+		Value::Option(Some(value)) | Value::Newtype(value) => visit_entries(value, visit),
+		_ => {}
+	}
+}
+
+/// Remove the entries matching a query from a cache, returning how many
+/// were removed
+fn remove_entries(value: &mut Value, query: &str) -> usize {
+	match value {
+		Value::Map(map) => {
+			let before = map.len();
+			map.retain(|key, value| {
+				!as_bytes(key).is_some_and(|id| matches_query(&id, value, query))
+			});
+			let removed = before - map.len();
+
+			removed + map.values_mut().map(|value| remove_entries(value, query)).sum::<usize>()
+		}
+		Value::Seq(values) => values.iter_mut().map(|value| remove_entries(value, query)).sum(),
+		Value::Option(Some(value)) | Value::Newtype(value) => remove_entries(value, query),
+		_ => 0,
+	}
+}
+
+/// Check whether an entry has the given ID or DN
+///
+/// IDs are compared as shown in Zitadel, i.e. binary IDs as base64. DNs
+/// are compared case-insensitively against the `dn` field of the entry.
+fn matches_query(id: &[u8], value: &Value, query: &str) -> bool {
+	if id == query.as_bytes() || STANDARD.encode(id) == query {
+		return true;
+	}
+
+	dn(value).is_some_and(|dn| dn.eq_ignore_ascii_case(query))
+}
+
+/// The DN of a cached entry, as found in its `dn` field
+fn dn(value: &Value) -> Option<&str> {
+	match value {
+		Value::Map(map) => map.iter().find_map(|(key, value)| match (key, value) {
+			(Value::String(key), Value::String(dn)) if key.eq_ignore_ascii_case("dn") => {
+				Some(dn.as_str())
+			}
+			_ => None,
+		}),
+		Value::Option(Some(value)) | Value::Newtype(value) => dn(value),
+		_ => None,
+	}
+}
+
+/// The bytes of a byte string, which serde represents as bytes or as a
+/// sequence of `u8`
+fn as_bytes(value: &Value) -> Option<Vec<u8>> {
+	match value {
+		Value::Bytes(bytes) => Some(bytes.clone()),
+		Value::Seq(values) if !values.is_empty() => values
+			.iter()
+			.map(|value| match value {
+				Value::U8(byte) => Some(*byte),
+				_ => None,
+			})
+			.collect(),
+		_ => None,
+	}
+}
+
+/// Render bytes as text if they are UTF-8, or as base64 otherwise
+fn bytes_to_json(bytes: &[u8]) -> serde_json::Value {
+	match std::str::from_utf8(bytes) {
+		Ok(text) => text.into(),
+		Err(_) => STANDARD.encode(bytes).into(),
+	}
+}
+
+/// Render a value as JSON
+fn to_json(value: &Value) -> serde_json::Value {
+	if let Some(bytes) = as_bytes(value) {
+		return bytes_to_json(&bytes);
+	}
+
+	match value {
+		Value::Bool(value) => (*value).into(),
+		Value::U8(value) => (*value).into(),
+		Value::U16(value) => (*value).into(),
+		Value::U32(value) => (*value).into(),
+		Value::U64(value) => (*value).into(),
+		Value::I8(value) => (*value).into(),
+		Value::I16(value) => (*value).into(),
+		Value::I32(value) => (*value).into(),
+		Value::I64(value) => (*value).into(),
+		Value::F32(value) => (*value).into(),
+		Value::F64(value) => (*value).into(),
+		Value::Char(value) => value.to_string().into(),
+		Value::String(value) => value.clone().into(),
+		Value::Unit | Value::Option(None) => serde_json::Value::Null,
+		Value::Option(Some(value)) | Value::Newtype(value) => to_json(value),
+		Value::Seq(values) => values.iter().map(to_json).collect(),
+		Value::Map(map) => map
+			.iter()
+			.map(|(key, value)| {
+				let key = match to_json(key) {
+					serde_json::Value::String(key) => key,
+					key => key.to_string(),
+				};
+				(key, to_json(value))
+			})
+			.collect(),
+		Value::Bytes(bytes) => bytes_to_json(bytes),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::HashMap;
+
+	use serde::Deserialize;
+
+	use super::*;
+
+	/// A cache shaped like the caches of `ldap_poller`
+	#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+	enum TestCache {
+		/// A cache tracking modification times
+		ModificationTime {
+			/// When the cache was last synced
+			last_sync_time: Option<String>,
+			/// The entries, keyed by their ID
+			entries: HashMap<Vec<u8>, TestEntry>,
+		},
+	}
+
+	/// An entry of [`TestCache`]
+	#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+	struct TestEntry {
+		/// The DN of the entry
+		dn: String,
+		/// When the entry was last modified
+		modified: String,
+	}
+
+	fn cache() -> TestCache {
+		TestCache::ModificationTime {
+			last_sync_time: Some("20240101000000Z".to_owned()),
+			entries: HashMap::from([
+				(
+					b"alice".to_vec(),
+					TestEntry {
+						dn: "uid=alice,ou=people,dc=example,dc=org".to_owned(),
+						modified: "20231201000000Z".to_owned(),
+					},
+				),
+				(
+					vec![0xA0, 0xA1],
+					TestEntry {
+						dn: "uid=bob,ou=people,dc=example,dc=org".to_owned(),
+						modified: "20231202000000Z".to_owned(),
+					},
+				),
+			]),
+		}
+	}
+
+	#[test]
+	fn test_cache_view() {
+		let view = CacheView::new(&cache()).expect("failed to inspect cache");
+		assert_eq!(view.entry_count(), 2);
+
+		let json = view.to_json();
+		assert_eq!(
+			json["ModificationTime"]["entries"]["alice"]["dn"],
+			"uid=alice,ou=people,dc=example,dc=org"
+		);
+		assert_eq!(json["ModificationTime"]["entries"]["oKE="]["modified"], "20231202000000Z");
+
+		assert_eq!(view.find("alice").len(), 1);
+		assert_eq!(view.find("oKE=").len(), 1);
+		assert_eq!(view.find("UID=bob,ou=people,dc=example,dc=org").len(), 1);
+		assert_eq!(view.find("carol").len(), 0);
+		// Other fields of the entries are not matched
+		assert_eq!(view.find("20231201000000Z").len(), 0);
+
+		let restored: TestCache = view.clone().into_cache().expect("failed to restore cache");
+		assert_eq!(restored, cache());
+	}
+
+	#[test]
+	fn test_cache_view_remove() {
+		let mut view = CacheView::new(&cache()).expect("failed to inspect cache");
+
+		let remove =
+			|view: &mut CacheView, query| view.remove(query, false).expect("failed to remove");
+		assert_eq!(remove(&mut view, "uid=bob,ou=people,dc=example,dc=org"), 1);
+		assert_eq!(remove(&mut view, "uid=bob,ou=people,dc=example,dc=org"), 0);
+
+		let TestCache::ModificationTime { last_sync_time, entries } =
+			view.into_cache().expect("failed to restore cache");
+		assert_eq!(last_sync_time.as_deref(), Some("20240101000000Z"));
+		assert_eq!(entries.keys().collect::<Vec<_>>(), vec![b"alice"]);
+	}
+
+	#[test]
+	fn test_cache_view_remove_several() {
+		let TestCache::ModificationTime { last_sync_time, mut entries } = cache();
+		entries.insert(
+			b"alice2".to_vec(),
+			TestEntry {
+				dn: "uid=alice,ou=people,dc=example,dc=org".to_owned(),
+				modified: "20231203000000Z".to_owned(),
+			},
+		);
+		let mut view = CacheView::new(&TestCache::ModificationTime { last_sync_time, entries })
+			.expect("failed to inspect cache");
+
+		assert!(view.remove("uid=alice,ou=people,dc=example,dc=org", false).is_err());
+		assert_eq!(view.entry_count(), 3);
+		assert_eq!(
+			view.remove("uid=alice,ou=people,dc=example,dc=org", true).expect("failed to remove"),
+			2
+		);
+		assert_eq!(view.entry_count(), 1);
+	}
+}
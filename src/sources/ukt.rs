@@ -1,16 +1,23 @@
 //! UKT source for syncing with Famedly's Zitadel.
 
-use std::collections::HashMap;
+use std::{
+	collections::{HashMap, HashSet},
+	sync::Arc,
+};
 
 use anyhow::{Context, Result};
 use async_trait::async_trait;
 use chrono::Utc;
 use reqwest::Client;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use url::Url;
 
 use super::Source;
-use crate::zitadel::{SourceDiff, UserId};
+use crate::{
+	sources::ldap::CacheCipher,
+	state::{SourceSnapshot, StateChanges, StateStore},
+	zitadel::{SourceDiff, UserId},
+};
 
 /// UKT Source
 pub struct UktSource {
@@ -18,6 +25,9 @@ pub struct UktSource {
 	ukt_config: UktSourceConfig,
 	/// Reqwest client
 	client: Client,
+	/// The removed users reported last, if they are kept in a state
+	/// store
+	snapshot: Option<SourceSnapshot<RemovedUsers>>,
 }
 
 #[async_trait]
@@ -27,10 +37,34 @@ impl Source for UktSource {
 	}
 
 	async fn get_diff(&self) -> Result<SourceDiff> {
-		let deleted_user_emails = self.get_removed_user_emails().await?;
+		// Taken before the list is fetched, so a list fetched after
+		// midnight is reported in full next run, rather than skipped
+		let date = current_date();
+		let mut deleted_user_emails = self.get_removed_user_emails().await?;
+
+		// The list is of the current day, so the users reported earlier
+		// that day were deleted then, or are retried
+		if let Some(snapshot) = &self.snapshot {
+			let reported: HashSet<String> = snapshot
+				.read()
+				.await?
+				.filter(|reported| reported.date == date)
+				.map(|reported| reported.emails.into_iter().collect())
+				.unwrap_or_default();
+			snapshot.set_pending(RemovedUsers { date, emails: deleted_user_emails.clone() });
+			deleted_user_emails.retain(|email| !reported.contains(email));
+		}
+
 		let deleted_user_ids = deleted_user_emails.into_iter().map(UserId::Login).collect();
 		return Ok(SourceDiff { new_users: vec![], changed_users: vec![], deleted_user_ids });
 	}
+
+	async fn commit(&self, changes: &mut StateChanges) -> Result<()> {
+		match &self.snapshot {
+			Some(snapshot) => snapshot.commit(changes),
+			None => Ok(()),
+		}
+	}
 }
 
 impl UktSource {
@@ -38,7 +72,19 @@ impl UktSource {
 	pub fn new(ukt_config: UktSourceConfig) -> Self {
 		let client = Client::new();
 
-		Self { ukt_config, client }
+		Self { ukt_config, client, snapshot: None }
+	}
+
+	/// Keep the removed users reported last in the given state store, to
+	/// not report them again
+	#[must_use]
+	pub(crate) fn with_state(
+		self,
+		state: Arc<dyn StateStore>,
+		cipher: Option<CacheCipher>,
+	) -> Self {
+		let snapshot = SourceSnapshot::new(self.get_name(), state, cipher);
+		Self { snapshot: Some(snapshot), ..self }
 	}
 
 	/// Get list of user emails that have been removed
@@ -76,7 +122,7 @@ impl UktSource {
 
 	/// Fetch the list of users
 	async fn fetch_list(&self, oauth2_token: OAuth2Token) -> Result<EmailList> {
-		let current_date = Utc::now().format("%Y%m%d").to_string();
+		let current_date = current_date();
 
 		let response = self
 			.client
@@ -102,9 +148,23 @@ impl UktSource {
 	}
 }
 
+/// The date the list of removed users is fetched for
+fn current_date() -> String {
+	Utc::now().format("%Y%m%d").to_string()
+}
+
 /// List of emails
 type EmailList = Vec<String>;
 
+/// The removed users reported on a day
+#[derive(Debug, Serialize, Deserialize)]
+struct RemovedUsers {
+	/// The day the users were listed for
+	date: String,
+	/// The email addresses of the users
+	emails: EmailList,
+}
+
 /// OAuth2 token response
 #[derive(Debug, Deserialize)]
 struct OAuth2Token {
@@ -202,10 +262,11 @@ pub mod test_helpers {
 #[cfg(test)]
 mod tests {
 	use indoc::indoc;
+	use tempfile::TempDir;
 	use wiremock::MockServer;
 
 	use super::*;
-	use crate::Config;
+	use crate::{state::SqliteStateStore, Config};
 
 	const EXAMPLE_CONFIG: &str = indoc! {r#"
         zitadel:
@@ -322,6 +383,43 @@ mod tests {
 		assert!(result.is_err(), "Didn't expect to fetch email list: {:?}", result);
 	}
 
+	#[tokio::test]
+	async fn test_get_diff_reported() {
+		let mock_server = MockServer::start().await;
+		let tempdir = TempDir::new().expect("failed to create temp dir");
+		let state: Arc<dyn StateStore> = Arc::new(
+			SqliteStateStore::open(&tempdir.path().join("state.db"), None)
+				.expect("failed to open state"),
+		);
+
+		let mut config = load_config();
+		let ukt_config = config.sources.ukt.as_mut().expect("UktSource configuration is missing");
+		ukt_config.oauth2_url =
+			test_helpers::get_mock_server_url(&mock_server, test_helpers::OAUTH2_PATH)
+				.expect("Failed to get mock server URL");
+		ukt_config.endpoint_url =
+			test_helpers::get_mock_server_url(&mock_server, test_helpers::ENDPOINT_PATH)
+				.expect("Failed to get mock server URL");
+
+		let ukt_config = config.sources.ukt.expect("UktSource configuration is missing");
+		let ukt = UktSource::new(ukt_config).with_state(state.clone(), None);
+
+		test_helpers::prepare_oauth2_mock(&mock_server).await;
+		test_helpers::prepare_endpoint_mock(&mock_server, "delete@famedly.de").await;
+		let diff = ukt.get_diff().await.expect("failed to get diff");
+		assert_eq!(diff.deleted_user_ids.len(), 1);
+
+		let mut changes = StateChanges::default();
+		ukt.commit(&mut changes).await.expect("failed to commit snapshot");
+		state.commit(changes).await.expect("failed to commit state");
+
+		// Users already reported that day are not reported again
+		test_helpers::prepare_oauth2_mock(&mock_server).await;
+		test_helpers::prepare_endpoint_mock(&mock_server, "delete@famedly.de").await;
+		let diff = ukt.get_diff().await.expect("failed to get diff");
+		assert!(diff.deleted_user_ids.is_empty());
+	}
+
 	#[tokio::test]
 	#[ignore]
 	/// Connects to the real URL in config to get the OAuth2 token
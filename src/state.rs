@@ -0,0 +1,854 @@
+//! Persistent state of the sync, shared by all sources and Zitadel
+//!
+//! The state consists of the snapshot each source keeps of what it
+//! synced last, the Zitadel IDs of synced users, the changes that failed
+//! and are retried next run, the history of runs, and the head of the
+//! audit journal. Changes to the state are committed in transactions, so
+//! it stays consistent even if a run is interrupted.
+use std::{
+	collections::{BTreeMap, HashMap, HashSet},
+	fmt::Debug,
+	path::Path,
+	sync::{Arc, Mutex, PoisonError},
+};
+
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use rusqlite::{params, types::Value, Connection, OptionalExtension};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+	audit::JournalHead,
+	report::{ReportEntry, SyncReport},
+	sources::ldap::CacheCipher,
+	user::User,
+	user_ids::UserRef,
+	zitadel::{ChangedUser, SourceDiff, UserId},
+};
+
+/// How many runs a change is attempted in before it is given up
+pub(crate) const MAX_ATTEMPTS: u32 = 5;
+
+/// A change of a source that failed to be written to Zitadel, to be
+/// retried next run
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) enum PendingChange {
+	/// A user to import
+	Import(Box<User>),
+	/// A user to update
+	Update(Box<ChangedUser>),
+	/// A user to delete
+	Delete(UserId),
+	/// A change whose users the mapping script failed to map, which is
+	/// mapped again when it is retried
+	Unmapped(Box<PendingChange>),
+}
+
+impl PendingChange {
+	/// The user the change is about
+	fn key(&self) -> String {
+		match self {
+			Self::Import(user) => user.external_user_id.to_string(),
+			Self::Update(user) => user.new.external_user_id.to_string(),
+			Self::Delete(user_id) => user_id.key().to_owned(),
+			Self::Unmapped(change) => change.key(),
+		}
+	}
+
+	/// Add the change to the changes of a source
+	fn add_to(self, diff: &mut SourceDiff) {
+		match self {
+			Self::Import(user) => diff.new_users.push(*user),
+			Self::Update(user) => diff.changed_users.push(*user),
+			Self::Delete(user_id) => diff.deleted_user_ids.push(user_id),
+			Self::Unmapped(change) => change.add_to(diff),
+		}
+	}
+
+	/// Take the changes whose users are yet to be mapped out of a retry
+	/// queue, to add them to the changes of a source before it is mapped
+	pub(crate) fn take_unmapped(queue: &mut Vec<QueuedChange>) -> Vec<QueuedChange> {
+		let (unmapped, mapped) = std::mem::take(queue)
+			.into_iter()
+			.partition(|queued| matches!(queued.change, Self::Unmapped(_)));
+		*queue = mapped;
+		unmapped
+	}
+
+	/// Add the changes of earlier runs to the changes of a source,
+	/// returning how often the added changes were attempted, keyed by
+	/// user
+	///
+	/// There is at most one change per user. Changes the source reports
+	/// again replace the earlier ones of the same user of any kind, since
+	/// they are more recent.
+	pub(crate) fn add_to_diff(
+		queue: Vec<QueuedChange>,
+		diff: &mut SourceDiff,
+	) -> HashMap<String, u32> {
+		let mut seen: HashSet<String> = diff
+			.new_users
+			.iter()
+			.map(|user| user.external_user_id.to_string())
+			.chain(diff.changed_users.iter().map(|user| user.new.external_user_id.to_string()))
+			.chain(diff.deleted_user_ids.iter().map(|user_id| user_id.key().to_owned()))
+			.collect();
+
+		let mut attempts = HashMap::new();
+		// The queue is in order, so the last change of a user is the
+		// most recent
+		for QueuedChange { change, attempts: count } in queue.into_iter().rev() {
+			let key = change.key();
+			if !seen.insert(key.clone()) {
+				continue;
+			}
+			attempts.insert(key, count);
+			change.add_to(diff);
+		}
+
+		attempts
+	}
+
+	/// Queue the changes of a source that failed to be retried next run,
+	/// given how often the retried ones were attempted before
+	///
+	/// Changes that failed [`MAX_ATTEMPTS`] times are given up and
+	/// reported.
+	pub(crate) fn requeue(
+		failed: Vec<Self>,
+		attempts: &HashMap<String, u32>,
+		source: &str,
+		report: &SyncReport,
+	) -> Vec<QueuedChange> {
+		failed
+			.into_iter()
+			.filter_map(|change| {
+				let key = change.key();
+				let attempts = attempts.get(&key).copied().unwrap_or(0) + 1;
+				if attempts >= MAX_ATTEMPTS {
+					tracing::warn!("Giving up on the change of user `{}` from {}", key, source);
+					report.record(ReportEntry::RetriesExhausted {
+						source: source.to_owned(),
+						external_user_id: key,
+						attempts,
+					});
+					return None;
+				}
+				Some(QueuedChange { change, attempts })
+			})
+			.collect()
+	}
+}
+
+/// A change in the retry queue
+#[derive(Debug, Clone)]
+pub(crate) struct QueuedChange {
+	/// The change to retry
+	pub(crate) change: PendingChange,
+	/// How many runs the change failed in
+	pub(crate) attempts: u32,
+}
+
+/// A finished sync run
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct RunRecord {
+	/// When the run started
+	pub(crate) started_at: DateTime<Utc>,
+	/// When the run finished
+	pub(crate) finished_at: DateTime<Utc>,
+	/// Why the run failed, if it did
+	pub(crate) error: Option<String>,
+}
+
+/// Changes to the state, committed together
+#[derive(Debug, Default)]
+pub(crate) struct StateChanges {
+	/// The new snapshots of sources, keyed by source name
+	snapshots: BTreeMap<String, Vec<u8>>,
+	/// The new retry queues of sources, keyed by source name
+	retry_queues: BTreeMap<String, Vec<QueuedChange>>,
+	/// The new Zitadel IDs of users, keyed by external user ID
+	user_refs: Option<HashMap<String, UserRef>>,
+	/// The new taken localparts, mapped to their holders
+	localparts: Option<HashMap<String, String>>,
+	/// Finished runs to record
+	runs: Vec<RunRecord>,
+	/// The new head of the audit journal
+	journal_head: Option<JournalHead>,
+}
+
+impl StateChanges {
+	/// Replace the snapshot of a source
+	pub(crate) fn set_snapshot(&mut self, source: &str, snapshot: Vec<u8>) {
+		self.snapshots.insert(source.to_owned(), snapshot);
+	}
+
+	/// Replace the changes of a source to retry next run
+	pub(crate) fn set_retry_queue(&mut self, source: &str, changes: Vec<QueuedChange>) {
+		self.retry_queues.insert(source.to_owned(), changes);
+	}
+
+	/// Replace the Zitadel IDs of users
+	pub(crate) fn set_user_refs(&mut self, user_refs: HashMap<String, UserRef>) {
+		self.user_refs = Some(user_refs);
+	}
+
+	/// Replace the taken localparts
+	pub(crate) fn set_localparts(&mut self, localparts: HashMap<String, String>) {
+		self.localparts = Some(localparts);
+	}
+
+	/// Record a finished run
+	pub(crate) fn add_run(&mut self, run: RunRecord) {
+		self.runs.push(run);
+	}
+
+	/// Replace the head of the audit journal
+	pub(crate) fn set_journal_head(&mut self, head: JournalHead) {
+		self.journal_head = Some(head);
+	}
+}
+
+/// The snapshot a source keeps in the state store of the users it
+/// reported last, to report only what changed since
+///
+/// Snapshots hold users' data, so they are encrypted like the LDAP cache
+/// if cache encryption is configured.
+pub(crate) struct SourceSnapshot<T> {
+	/// The name of the source
+	source: &'static str,
+	/// The state store keeping the snapshot
+	state: Arc<dyn StateStore>,
+	/// The cipher encrypting the snapshot, if any
+	cipher: Option<CacheCipher>,
+	/// The snapshot of the last read, committed once its changes were
+	/// applied
+	pending: Mutex<Option<T>>,
+}
+
+impl<T: Serialize + DeserializeOwned> SourceSnapshot<T> {
+	/// Keep the snapshot of a source in the given state store
+	pub(crate) fn new(
+		source: &'static str,
+		state: Arc<dyn StateStore>,
+		cipher: Option<CacheCipher>,
+	) -> Self {
+		Self { source, state, cipher, pending: Mutex::new(None) }
+	}
+
+	/// The snapshot committed last, if any
+	///
+	/// Unencrypted snapshots are accepted even if a cipher is given, as
+	/// they are encrypted when the snapshot is committed next.
+	pub(crate) async fn read(&self) -> Result<Option<T>> {
+		let Some(data) = self.state.snapshot(self.source).await? else {
+			return Ok(None);
+		};
+
+		let snapshot = match (serde_json::from_slice(&data), &self.cipher) {
+			(Ok(snapshot), _) => snapshot,
+			(Err(_), Some(cipher)) => serde_json::from_slice(&cipher.open(&data)?)?,
+			(Err(err), None) => {
+				return Err(err).context(format!(
+					"failed to read the snapshot of {}; it may be encrypted, but cache \
+					 encryption is not configured",
+					self.source
+				))
+			}
+		};
+		Ok(Some(snapshot))
+	}
+
+	/// Replace the snapshot once the changes reported from it were
+	/// applied
+	pub(crate) fn set_pending(&self, snapshot: T) {
+		*self.pending.lock().unwrap_or_else(PoisonError::into_inner) = Some(snapshot);
+	}
+
+	/// Add the pending snapshot to the given changes, if there is one
+	pub(crate) fn commit(&self, changes: &mut StateChanges) -> Result<()> {
+		let Some(snapshot) = self.pending.lock().unwrap_or_else(PoisonError::into_inner).take()
+		else {
+			return Ok(());
+		};
+
+		let data = serde_json::to_vec(&snapshot)?;
+		let data = match &self.cipher {
+			Some(cipher) => cipher.encrypt(&data)?,
+			None => data,
+		};
+		changes.set_snapshot(self.source, data);
+		Ok(())
+	}
+}
+
+/// Persistent state of the sync
+#[async_trait]
+pub(crate) trait StateStore: Debug + Send + Sync {
+	/// The snapshot a source committed last
+	async fn snapshot(&self, source: &str) -> Result<Option<Vec<u8>>>;
+
+	/// The Zitadel IDs of users, keyed by external user ID
+	async fn user_refs(&self) -> Result<HashMap<String, UserRef>>;
+
+	/// The Matrix localparts taken in Zitadel, mapped to the external IDs
+	/// of their holders, or their Zitadel IDs for users without one
+	async fn localparts(&self) -> Result<HashMap<String, String>>;
+
+	/// The changes of a source that failed in earlier runs
+	async fn retry_queue(&self, source: &str) -> Result<Vec<QueuedChange>>;
+
+	/// The most recent runs, newest first
+	async fn runs(&self, limit: usize) -> Result<Vec<RunRecord>>;
+
+	/// The head of the audit journal as of the last commit
+	async fn journal_head(&self) -> Result<Option<JournalHead>>;
+
+	/// Apply changes to the state, all at once or not at all
+	async fn commit(&self, changes: StateChanges) -> Result<()>;
+}
+
+/// Schema migrations, applied in order; the number of applied migrations
+/// is kept as the `user_version` of the database
+const MIGRATIONS: &[&str] = &[
+	"
+	CREATE TABLE snapshots (
+		source TEXT PRIMARY KEY,
+		data BLOB NOT NULL,
+		updated_at TEXT NOT NULL
+	);
+	CREATE TABLE user_refs (
+		external_id TEXT PRIMARY KEY,
+		user_id TEXT NOT NULL,
+		organization_id TEXT NOT NULL
+	);
+	CREATE TABLE retry_queue (
+		id INTEGER PRIMARY KEY AUTOINCREMENT,
+		source TEXT NOT NULL,
+		change TEXT NOT NULL
+	);
+	CREATE TABLE runs (
+		id INTEGER PRIMARY KEY AUTOINCREMENT,
+		started_at TEXT NOT NULL,
+		finished_at TEXT NOT NULL,
+		error TEXT
+	);
+",
+	"
+	ALTER TABLE retry_queue ADD COLUMN attempts INTEGER NOT NULL DEFAULT 1;
+",
+	"
+	CREATE TABLE journal_head (
+		id INTEGER PRIMARY KEY CHECK (id = 0),
+		count INTEGER NOT NULL,
+		hash TEXT NOT NULL
+	);
+",
+	"
+	CREATE TABLE localparts (
+		localpart TEXT PRIMARY KEY,
+		holder TEXT NOT NULL
+	);
+",
+];
+
+/// State stored in an SQLite database
+///
+/// The database is accessed on the blocking thread pool. The retry queue
+/// holds users' data, so it is encrypted like the LDAP cache if cache
+/// encryption is configured.
+#[derive(Debug)]
+pub(crate) struct SqliteStateStore {
+	/// The connection to the database
+	connection: Arc<Mutex<Connection>>,
+	/// The cipher encrypting the retry queue, if any
+	cipher: Option<Arc<CacheCipher>>,
+}
+
+impl SqliteStateStore {
+	/// Open the database at the given path, creating and migrating it as
+	/// necessary
+	pub(crate) fn open(path: &Path, cipher: Option<CacheCipher>) -> Result<Self> {
+		let mut connection = Connection::open(path)
+			.context(format!("failed to open state database {}", path.display()))?;
+		connection.busy_timeout(std::time::Duration::from_secs(10))?;
+
+		let version: usize = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+		if version > MIGRATIONS.len() {
+			bail!("state database {} was created by a newer version of the sync", path.display());
+		}
+
+		for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+			let transaction = connection.transaction()?;
+			transaction.execute_batch(migration)?;
+			transaction.pragma_update(None, "user_version", index + 1)?;
+			transaction.commit().context("failed to migrate state database")?;
+		}
+
+		Ok(Self { connection: Arc::new(Mutex::new(connection)), cipher: cipher.map(Arc::new) })
+	}
+
+	/// Run a query on the blocking thread pool
+	async fn with_connection<T: Send + 'static>(
+		&self,
+		query: impl FnOnce(&mut Connection) -> Result<T> + Send + 'static,
+	) -> Result<T> {
+		let connection = Arc::clone(&self.connection);
+		tokio::task::spawn_blocking(move || {
+			query(&mut connection.lock().unwrap_or_else(PoisonError::into_inner))
+		})
+		.await
+		.context("state database task failed")?
+	}
+}
+
+/// Serialize a queued change, encrypted if a cipher is given
+fn encode_change(change: &PendingChange, cipher: Option<&CacheCipher>) -> Result<Value> {
+	let data = serde_json::to_string(change)?;
+	Ok(match cipher {
+		Some(cipher) => Value::Blob(cipher.encrypt(data.as_bytes())?),
+		None => Value::Text(data),
+	})
+}
+
+/// Deserialize a queued change, decrypting it if it is encrypted
+///
+/// Unencrypted changes are accepted even if a cipher is given, as they
+/// are encrypted when the queue is written next.
+fn decode_change(data: Value, cipher: Option<&CacheCipher>) -> Result<PendingChange> {
+	match (data, cipher) {
+		(Value::Text(data), _) => Ok(serde_json::from_str(&data)?),
+		(Value::Blob(data), Some(cipher)) => Ok(serde_json::from_slice(&cipher.open(&data)?)?),
+		(Value::Blob(_), None) => {
+			bail!("retry queue is encrypted, but cache encryption is not configured")
+		}
+		_ => bail!("retry queue entry is malformed"),
+	}
+}
+
+#[async_trait]
+impl StateStore for SqliteStateStore {
+	async fn snapshot(&self, source: &str) -> Result<Option<Vec<u8>>> {
+		let source = source.to_owned();
+		self.with_connection(move |connection| {
+			connection
+				.query_row("SELECT data FROM snapshots WHERE source = ?1", [source], |row| {
+					row.get(0)
+				})
+				.optional()
+				.context("failed to read snapshot")
+		})
+		.await
+	}
+
+	async fn user_refs(&self) -> Result<HashMap<String, UserRef>> {
+		self.with_connection(|connection| {
+			let mut statement = connection
+				.prepare("SELECT external_id, user_id, organization_id FROM user_refs")?;
+			let user_refs = statement
+				.query_map([], |row| {
+					Ok((
+						row.get(0)?,
+						UserRef { user_id: row.get(1)?, organization_id: row.get(2)? },
+					))
+				})?
+				.collect::<Result<_, _>>()
+				.context("failed to read user IDs")?;
+			Ok(user_refs)
+		})
+		.await
+	}
+
+	async fn localparts(&self) -> Result<HashMap<String, String>> {
+		self.with_connection(|connection| {
+			let mut statement = connection.prepare("SELECT localpart, holder FROM localparts")?;
+			let localparts = statement
+				.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+				.collect::<Result<_, _>>()
+				.context("failed to read localparts")?;
+			Ok(localparts)
+		})
+		.await
+	}
+
+	async fn retry_queue(&self, source: &str) -> Result<Vec<QueuedChange>> {
+		let source = source.to_owned();
+		let cipher = self.cipher.clone();
+		self.with_connection(move |connection| {
+			let mut statement = connection.prepare(
+				"SELECT change, attempts FROM retry_queue WHERE source = ?1 ORDER BY id",
+			)?;
+			let changes = statement
+				.query_map([source], |row| Ok((row.get(0)?, row.get(1)?)))?
+				.map(|row| {
+					let (change, attempts) = row?;
+					Ok(QueuedChange { change: decode_change(change, cipher.as_deref())?, attempts })
+				})
+				.collect::<Result<_>>()
+				.context("failed to read retry queue")?;
+			Ok(changes)
+		})
+		.await
+	}
+
+	async fn runs(&self, limit: usize) -> Result<Vec<RunRecord>> {
+		self.with_connection(move |connection| {
+			let mut statement = connection.prepare(
+				"SELECT started_at, finished_at, error FROM runs ORDER BY id DESC LIMIT ?1",
+			)?;
+			let runs = statement
+				.query_map([limit], |row| {
+					Ok(RunRecord {
+						started_at: row.get(0)?,
+						finished_at: row.get(1)?,
+						error: row.get(2)?,
+					})
+				})?
+				.collect::<Result<_, _>>()
+				.context("failed to read runs")?;
+			Ok(runs)
+		})
+		.await
+	}
+
+	async fn journal_head(&self) -> Result<Option<JournalHead>> {
+		self.with_connection(|connection| {
+			connection
+				.query_row("SELECT count, hash FROM journal_head WHERE id = 0", [], |row| {
+					Ok(JournalHead { count: row.get(0)?, hash: row.get(1)? })
+				})
+				.optional()
+				.context("failed to read the head of the audit journal")
+		})
+		.await
+	}
+
+	async fn commit(&self, changes: StateChanges) -> Result<()> {
+		let cipher = self.cipher.clone();
+		self.with_connection(move |connection| {
+			let transaction = connection.transaction()?;
+			let now = Utc::now();
+
+			for (source, data) in changes.snapshots {
+				transaction.execute(
+					"INSERT OR REPLACE INTO snapshots (source, data, updated_at) \
+					 VALUES (?1, ?2, ?3)",
+					params![source, data, now],
+				)?;
+			}
+
+			for (source, queue) in changes.retry_queues {
+				transaction.execute("DELETE FROM retry_queue WHERE source = ?1", [&source])?;
+				for QueuedChange { change, attempts } in queue {
+					transaction.execute(
+						"INSERT INTO retry_queue (source, change, attempts) VALUES (?1, ?2, ?3)",
+						params![source, encode_change(&change, cipher.as_deref())?, attempts],
+					)?;
+				}
+			}
+
+			if let Some(user_refs) = changes.user_refs {
+				transaction.execute("DELETE FROM user_refs", [])?;
+				for (external_id, user_ref) in user_refs {
+					transaction.execute(
+						"INSERT INTO user_refs (external_id, user_id, organization_id) \
+						 VALUES (?1, ?2, ?3)",
+						params![external_id, user_ref.user_id, user_ref.organization_id],
+					)?;
+				}
+			}
+
+			if let Some(localparts) = changes.localparts {
+				transaction.execute("DELETE FROM localparts", [])?;
+				for (localpart, holder) in localparts {
+					transaction.execute(
+						"INSERT INTO localparts (localpart, holder) VALUES (?1, ?2)",
+						params![localpart, holder],
+					)?;
+				}
+			}
+
+			for run in changes.runs {
+				transaction.execute(
+					"INSERT INTO runs (started_at, finished_at, error) VALUES (?1, ?2, ?3)",
+					params![run.started_at, run.finished_at, run.error],
+				)?;
+			}
+
+			if let Some(head) = changes.journal_head {
+				transaction.execute(
+					"INSERT OR REPLACE INTO journal_head (id, count, hash) VALUES (0, ?1, ?2)",
+					params![head.count, head.hash],
+				)?;
+			}
+
+			transaction.commit().context("failed to commit state")
+		})
+		.await
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use base64::{engine::general_purpose::STANDARD, Engine};
+	use chrono::TimeZone;
+	use tempfile::TempDir;
+
+	use super::*;
+	use crate::{
+		sources::ldap::{CacheEncryptionConfig, CacheKey},
+		user::test_helpers,
+	};
+
+	fn user_ref(user_id: &str) -> UserRef {
+		UserRef { user_id: user_id.to_owned(), organization_id: "org".to_owned() }
+	}
+
+	fn run(hour: u32, error: Option<&str>) -> RunRecord {
+		RunRecord {
+			started_at: Utc
+				.with_ymd_and_hms(2024, 1, 1, hour, 0, 0)
+				.single()
+				.expect("invalid time"),
+			finished_at: Utc
+				.with_ymd_and_hms(2024, 1, 1, hour, 30, 0)
+				.single()
+				.expect("invalid time"),
+			error: error.map(ToOwned::to_owned),
+		}
+	}
+
+	fn delete(nick: &str) -> QueuedChange {
+		QueuedChange { change: PendingChange::Delete(UserId::Nick(nick.to_owned())), attempts: 1 }
+	}
+
+	fn empty_diff() -> SourceDiff {
+		SourceDiff { new_users: vec![], changed_users: vec![], deleted_user_ids: vec![] }
+	}
+
+	#[tokio::test]
+	async fn test_sqlite_state_store() {
+		let tempdir = TempDir::new().expect("failed to create temp dir");
+		let path = tempdir.path().join("state.db");
+
+		let state = SqliteStateStore::open(&path, None).expect("failed to open state");
+		assert_eq!(state.snapshot("LDAP").await.expect("failed to read snapshot"), None);
+		assert!(state.user_refs().await.expect("failed to read user IDs").is_empty());
+		assert!(state.localparts().await.expect("failed to read localparts").is_empty());
+		assert!(state.runs(10).await.expect("failed to read runs").is_empty());
+		assert_eq!(state.journal_head().await.expect("failed to read journal head"), None);
+
+		let mut changes = StateChanges::default();
+		changes.set_snapshot("LDAP", b"cache".to_vec());
+		changes.set_journal_head(JournalHead { count: 2, hash: "abc".to_owned() });
+		changes.set_retry_queue("LDAP", vec![delete("alice"), delete("bob")]);
+		changes.set_user_refs(HashMap::from([("alice".to_owned(), user_ref("1"))]));
+		changes.set_localparts(HashMap::from([("alice".to_owned(), "alice".to_owned())]));
+		changes.add_run(run(1, None));
+		changes.add_run(run(2, Some("failed")));
+		state.commit(changes).await.expect("failed to commit state");
+
+		// Unchanged parts of the state are kept
+		let mut changes = StateChanges::default();
+		changes.set_retry_queue("LDAP", vec![QueuedChange { attempts: 3, ..delete("bob") }]);
+		state.commit(changes).await.expect("failed to commit state");
+		drop(state);
+
+		let state = SqliteStateStore::open(&path, None).expect("failed to reopen state");
+		assert_eq!(
+			state.snapshot("LDAP").await.expect("failed to read snapshot"),
+			Some(b"cache".to_vec())
+		);
+		assert_eq!(
+			state.user_refs().await.expect("failed to read user IDs"),
+			HashMap::from([("alice".to_owned(), user_ref("1"))])
+		);
+		assert_eq!(
+			state.localparts().await.expect("failed to read localparts"),
+			HashMap::from([("alice".to_owned(), "alice".to_owned())])
+		);
+		assert_eq!(state.runs(1).await.expect("failed to read runs"), vec![run(2, Some("failed"))]);
+		assert_eq!(
+			state.journal_head().await.expect("failed to read journal head"),
+			Some(JournalHead { count: 2, hash: "abc".to_owned() })
+		);
+
+		let queue = state.retry_queue("LDAP").await.expect("failed to read retry queue");
+		assert!(matches!(
+			queue.as_slice(),
+			[QueuedChange { change: PendingChange::Delete(UserId::Nick(nick)), attempts: 3 }]
+				if nick == "bob"
+		));
+		assert!(state.retry_queue("CSV").await.expect("failed to read retry queue").is_empty());
+	}
+
+	#[tokio::test]
+	async fn test_sqlite_state_store_encrypted() {
+		let tempdir = TempDir::new().expect("failed to create temp dir");
+		let path = tempdir.path().join("state.db");
+		let key_path = tempdir.path().join("key");
+		std::fs::write(&key_path, STANDARD.encode([1; 32])).expect("failed to write key");
+		let cipher = || {
+			CacheCipher::new(&CacheEncryptionConfig {
+				keys: vec![CacheKey::File(key_path.clone())],
+				migrate_unencrypted: false,
+			})
+			.expect("failed to load key")
+		};
+
+		let state = SqliteStateStore::open(&path, Some(cipher())).expect("failed to open state");
+		let mut changes = StateChanges::default();
+		changes.set_retry_queue("LDAP", vec![delete("alice")]);
+		state.commit(changes).await.expect("failed to commit state");
+		drop(state);
+
+		let connection = Connection::open(&path).expect("failed to open database");
+		let stored: Vec<u8> = connection
+			.query_row("SELECT change FROM retry_queue", [], |row| row.get(0))
+			.expect("failed to read retry queue");
+		assert!(!String::from_utf8_lossy(&stored).contains("alice"));
+
+		let state = SqliteStateStore::open(&path, Some(cipher())).expect("failed to open state");
+		assert_eq!(state.retry_queue("LDAP").await.expect("failed to read retry queue").len(), 1);
+		let state = SqliteStateStore::open(&path, None).expect("failed to open state");
+		assert!(state.retry_queue("LDAP").await.is_err());
+	}
+
+	#[tokio::test]
+	async fn test_source_snapshot() {
+		let tempdir = TempDir::new().expect("failed to create temp dir");
+		let path = tempdir.path().join("state.db");
+		let key_path = tempdir.path().join("key");
+		std::fs::write(&key_path, STANDARD.encode([1; 32])).expect("failed to write key");
+		let cipher = || {
+			CacheCipher::new(&CacheEncryptionConfig {
+				keys: vec![CacheKey::File(key_path.clone())],
+				migrate_unencrypted: false,
+			})
+			.expect("failed to load key")
+		};
+		let state: Arc<dyn StateStore> =
+			Arc::new(SqliteStateStore::open(&path, None).expect("failed to open state"));
+
+		let snapshot = SourceSnapshot::new("CSV", state.clone(), None);
+		assert_eq!(snapshot.read().await.expect("failed to read snapshot"), None);
+
+		// Nothing is committed before a snapshot is pending
+		let mut changes = StateChanges::default();
+		snapshot.commit(&mut changes).expect("failed to commit snapshot");
+		assert!(changes.snapshots.is_empty());
+
+		snapshot.set_pending(vec!["alice".to_owned()]);
+		snapshot.commit(&mut changes).expect("failed to commit snapshot");
+		state.commit(changes).await.expect("failed to commit state");
+
+		// Unencrypted snapshots are still read once encryption is
+		// configured, and encrypted when committed next
+		let snapshot = SourceSnapshot::new("CSV", state.clone(), Some(cipher()));
+		assert_eq!(
+			snapshot.read().await.expect("failed to read snapshot"),
+			Some(vec!["alice".to_owned()])
+		);
+
+		let mut changes = StateChanges::default();
+		snapshot.set_pending(vec!["bob".to_owned()]);
+		snapshot.commit(&mut changes).expect("failed to commit snapshot");
+		state.commit(changes).await.expect("failed to commit state");
+
+		let stored = state.snapshot("CSV").await.expect("failed to read snapshot");
+		assert!(!String::from_utf8_lossy(&stored.expect("missing snapshot")).contains("bob"));
+		assert_eq!(
+			snapshot.read().await.expect("failed to read snapshot"),
+			Some(vec!["bob".to_owned()])
+		);
+
+		let snapshot = SourceSnapshot::<Vec<String>>::new("CSV", state, None);
+		assert!(snapshot.read().await.is_err());
+	}
+
+	#[test]
+	fn test_add_to_diff() {
+		let import = |id: &str| QueuedChange {
+			change: PendingChange::Import(Box::new(test_helpers::user(id, "a@example.com"))),
+			attempts: 2,
+		};
+
+		let mut diff = SourceDiff {
+			new_users: vec![test_helpers::user("alice", "alice@example.com")],
+			..empty_diff()
+		};
+		// Earlier changes of a user are replaced by later ones and those
+		// the source reports, of any kind
+		let attempts = PendingChange::add_to_diff(
+			vec![delete("alice"), import("bob"), delete("bob"), delete("carol")],
+			&mut diff,
+		);
+
+		assert_eq!(diff.new_users.len(), 1);
+		assert!(diff.changed_users.is_empty());
+		assert_eq!(
+			diff.deleted_user_ids.iter().map(UserId::key).collect::<Vec<_>>(),
+			vec!["carol", "bob"]
+		);
+		assert_eq!(attempts, HashMap::from([("bob".to_owned(), 1), ("carol".to_owned(), 1)]));
+	}
+
+	#[test]
+	fn test_take_unmapped() {
+		let unmapped = QueuedChange {
+			change: PendingChange::Unmapped(Box::new(PendingChange::Import(Box::new(
+				test_helpers::user("alice", "alice@example.com"),
+			)))),
+			attempts: 1,
+		};
+
+		let mut queue = vec![delete("bob"), unmapped];
+		let unmapped = PendingChange::take_unmapped(&mut queue);
+		assert_eq!(queue.len(), 1);
+		assert_eq!(queue[0].change.key(), "bob");
+
+		// Unmapped changes are added as the changes they wrap
+		let mut diff = empty_diff();
+		let attempts = PendingChange::add_to_diff(unmapped, &mut diff);
+		assert_eq!(diff.new_users.len(), 1);
+		assert_eq!(attempts, HashMap::from([("alice".to_owned(), 1)]));
+	}
+
+	#[test]
+	fn test_requeue() {
+		let report = SyncReport::default();
+		let failed = vec![
+			PendingChange::Delete(UserId::Nick("alice".to_owned())),
+			PendingChange::Delete(UserId::Nick("bob".to_owned())),
+		];
+		let attempts = HashMap::from([("bob".to_owned(), MAX_ATTEMPTS - 1)]);
+
+		let queue = PendingChange::requeue(failed, &attempts, "LDAP", &report);
+		assert!(matches!(
+			queue.as_slice(),
+			[QueuedChange { change: PendingChange::Delete(UserId::Nick(nick)), attempts: 1 }]
+				if nick == "alice"
+		));
+		assert_eq!(
+			report.entries(),
+			vec![ReportEntry::RetriesExhausted {
+				source: "LDAP".to_owned(),
+				external_user_id: "bob".to_owned(),
+				attempts: MAX_ATTEMPTS,
+			}]
+		);
+	}
+
+	#[test]
+	fn test_sqlite_state_store_newer_version() {
+		let tempdir = TempDir::new().expect("failed to create temp dir");
+		let path = tempdir.path().join("state.db");
+
+		Connection::open(&path)
+			.and_then(|connection| connection.pragma_update(None, "user_version", 100))
+			.expect("failed to create database");
+
+		assert!(SqliteStateStore::open(&path, None).is_err());
+	}
+}
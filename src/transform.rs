@@ -0,0 +1,387 @@
+//! Declarative transformation of the user fields read from sources
+use std::{cell::RefCell, collections::BTreeSet};
+
+use anyhow::{Context, Result};
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::{
+	user::{StringOrBytes, User},
+	zitadel::{ChangedUser, SourceDiff},
+};
+
+/// The transformations to apply to each user field, in order
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+pub struct TransformationsConfig {
+	/// Transformations of the first name
+	#[serde(default)]
+	pub first_name: Vec<Transformation>,
+	/// Transformations of the last name
+	#[serde(default)]
+	pub last_name: Vec<Transformation>,
+	/// Transformations of the email address
+	#[serde(default)]
+	pub email: Vec<Transformation>,
+	/// Transformations of the phone number
+	#[serde(default)]
+	pub phone: Vec<Transformation>,
+	/// Transformations of the preferred username
+	#[serde(default)]
+	pub preferred_username: Vec<Transformation>,
+}
+
+impl TransformationsConfig {
+	/// The source attributes the transformations refer to in templates
+	/// and fallbacks, which sources must fetch along with the mapped
+	/// attributes
+	pub(crate) fn referenced_attributes(&self) -> BTreeSet<String> {
+		let attributes = RefCell::new(BTreeSet::new());
+
+		for transformation in
+			[&self.first_name, &self.last_name, &self.email, &self.phone, &self.preferred_username]
+				.into_iter()
+				.flatten()
+		{
+			match transformation {
+				Transformation::Template(template) => {
+					render_template(template, |attribute| {
+						attributes.borrow_mut().insert(attribute.to_owned());
+						None
+					});
+				}
+				Transformation::FallbackAttribute(attribute) => {
+					attributes.borrow_mut().insert(attribute.clone());
+				}
+				_ => {}
+			}
+		}
+
+		attributes.into_inner()
+	}
+}
+
+/// A single transformation step
+///
+/// Lowercasing, trimming and regex replacements leave binary values
+/// alone. A value is considered missing if it is absent or empty.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Transformation {
+	/// Convert the value to lowercase
+	Lowercase,
+	/// Remove leading and trailing whitespace
+	Trim,
+	/// Replace all matches of a regular expression
+	RegexReplace {
+		/// The regular expression to match
+		pattern: String,
+		/// The replacement, which may refer to capture groups as `$1`
+		/// or `${name}`
+		replacement: String,
+	},
+	/// Replace the value with a template, in which `{attribute}` is
+	/// replaced with the first value of the named source attribute
+	Template(String),
+	/// Use the first value of another source attribute if the value is
+	/// missing
+	FallbackAttribute(String),
+	/// Use a fixed value if the value is missing
+	Default(String),
+}
+
+/// A transformation step, ready to be applied
+#[derive(Debug, Clone)]
+enum Step {
+	/// See [`Transformation::Lowercase`]
+	Lowercase,
+	/// See [`Transformation::Trim`]
+	Trim,
+	/// See [`Transformation::RegexReplace`]
+	RegexReplace(Regex, String),
+	/// See [`Transformation::Template`]
+	Template(String),
+	/// See [`Transformation::FallbackAttribute`]
+	FallbackAttribute(String),
+	/// See [`Transformation::Default`]
+	Default(String),
+}
+
+impl Step {
+	/// Prepare a configured transformation
+	fn new(transformation: &Transformation) -> Result<Self> {
+		Ok(match transformation {
+			Transformation::Lowercase => Self::Lowercase,
+			Transformation::Trim => Self::Trim,
+			Transformation::RegexReplace { pattern, replacement } => Self::RegexReplace(
+				Regex::new(pattern).context(format!("invalid regular expression `{pattern}`"))?,
+				replacement.clone(),
+			),
+			Transformation::Template(template) => Self::Template(template.clone()),
+			Transformation::FallbackAttribute(attribute) => {
+				Self::FallbackAttribute(attribute.clone())
+			}
+			Transformation::Default(value) => Self::Default(value.clone()),
+		})
+	}
+
+	/// Apply the step to a value of the given user
+	fn apply(&self, value: Option<StringOrBytes>, user: &User) -> Option<StringOrBytes> {
+		match (self, value) {
+			(Self::Lowercase, Some(StringOrBytes::String(value))) => {
+				Some(StringOrBytes::String(value.to_lowercase()))
+			}
+			(Self::Trim, Some(StringOrBytes::String(value))) => {
+				Some(StringOrBytes::String(value.trim().to_owned()))
+			}
+			(Self::RegexReplace(regex, replacement), Some(StringOrBytes::String(value))) => {
+				Some(StringOrBytes::String(regex.replace_all(&value, replacement).into_owned()))
+			}
+			(Self::Template(template), _) => {
+				Some(StringOrBytes::String(render_template(template, |attribute| {
+					user.attribute_values(attribute).first().map(ToString::to_string)
+				})))
+			}
+			(Self::FallbackAttribute(attribute), value) if is_missing(value.as_ref()) => user
+				.attribute_values(attribute)
+				.first()
+				.filter(|value| !is_missing(Some(value)))
+				.cloned()
+				.or(value),
+			(Self::Default(default), value) if is_missing(value.as_ref()) => {
+				Some(StringOrBytes::String(default.clone()))
+			}
+			(_, value) => value,
+		}
+	}
+}
+
+/// Whether a value is absent or empty
+fn is_missing(value: Option<&StringOrBytes>) -> bool {
+	match value {
+		None => true,
+		Some(StringOrBytes::String(value)) => value.is_empty(),
+		Some(StringOrBytes::Bytes(value)) => value.is_empty(),
+	}
+}
+
+/// Replace each `{name}` in the template with the value looked up for
+/// the name, or nothing if there is none
+pub(crate) fn render_template(template: &str, lookup: impl Fn(&str) -> Option<String>) -> String {
+	let mut rendered = String::new();
+	let mut rest = template;
+
+	while let Some(start) = rest.find('{') {
+		let Some(length) = rest[start..].find('}') else {
+			break;
+		};
+
+		rendered.push_str(&rest[..start]);
+		if let Some(value) = lookup(&rest[start + 1..start + length]) {
+			rendered.push_str(&value);
+		}
+		rest = &rest[start + length + 1..];
+	}
+
+	rendered.push_str(rest);
+	rendered
+}
+
+/// Applies the configured transformations to users
+#[derive(Debug, Clone)]
+pub(crate) struct Transformer {
+	/// Steps for the first name
+	first_name: Vec<Step>,
+	/// Steps for the last name
+	last_name: Vec<Step>,
+	/// Steps for the email address
+	email: Vec<Step>,
+	/// Steps for the phone number
+	phone: Vec<Step>,
+	/// Steps for the preferred username
+	preferred_username: Vec<Step>,
+}
+
+impl Transformer {
+	/// Prepare the configured transformations, validating them
+	pub(crate) fn new(config: &TransformationsConfig) -> Result<Self> {
+		/// Prepare the steps for a single field
+		fn steps(transformations: &[Transformation]) -> Result<Vec<Step>> {
+			transformations.iter().map(Step::new).collect()
+		}
+
+		Ok(Self {
+			first_name: steps(&config.first_name)?,
+			last_name: steps(&config.last_name)?,
+			email: steps(&config.email)?,
+			phone: steps(&config.phone)?,
+			preferred_username: steps(&config.preferred_username)?,
+		})
+	}
+
+	/// Transform all users of a source diff
+	pub(crate) fn transform_diff(&self, diff: SourceDiff) -> SourceDiff {
+		SourceDiff {
+			new_users: self.transform_users(diff.new_users),
+			changed_users: diff
+				.changed_users
+				.into_iter()
+				.map(|ChangedUser { old, new }| ChangedUser {
+					old: self.transform(old),
+					new: self.transform(new),
+				})
+				.collect(),
+			deleted_user_ids: diff.deleted_user_ids,
+		}
+	}
+
+	/// Transform a list of users
+	pub(crate) fn transform_users(&self, users: Vec<User>) -> Vec<User> {
+		users.into_iter().map(|user| self.transform(user)).collect()
+	}
+
+	/// Transform a single user
+	pub(crate) fn transform(&self, user: User) -> User {
+		/// Run a required value through the given steps
+		fn required(steps: &[Step], value: StringOrBytes, user: &User) -> StringOrBytes {
+			optional(steps, Some(value), user)
+				.unwrap_or_else(|| StringOrBytes::String(String::new()))
+		}
+
+		/// Run an optional value through the given steps
+		fn optional(
+			steps: &[Step],
+			value: Option<StringOrBytes>,
+			user: &User,
+		) -> Option<StringOrBytes> {
+			steps.iter().fold(value, |value, step| step.apply(value, user))
+		}
+
+		User {
+			first_name: required(&self.first_name, user.first_name.clone(), &user),
+			last_name: required(&self.last_name, user.last_name.clone(), &user),
+			email: required(&self.email, user.email.clone(), &user),
+			phone: optional(&self.phone, user.phone.clone(), &user)
+				.filter(|phone| !is_missing(Some(phone))),
+			preferred_username: required(
+				&self.preferred_username,
+				user.preferred_username.clone(),
+				&user,
+			),
+			..user
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::user::test_helpers::{attributes, user};
+
+	fn transformer(config: &str) -> Transformer {
+		let config: TransformationsConfig =
+			serde_yaml::from_str(config).expect("invalid transformations");
+		Transformer::new(&config).expect("failed to prepare transformations")
+	}
+
+	#[test]
+	fn test_transform_string_operations() {
+		let transformer = transformer(
+			r#"
+            first_name: [trim]
+            email: [lowercase]
+            preferred_username:
+              - !regex_replace
+                pattern: "@.*$"
+                replacement: ""
+            "#,
+		);
+
+		let user = transformer.transform(User {
+			first_name: " Alice ".to_owned().into(),
+			preferred_username: "alice.smith@example.com".to_owned().into(),
+			..user("alice", "Alice.Smith@Example.COM")
+		});
+		assert_eq!(user.first_name, StringOrBytes::String("Alice".to_owned()));
+		assert_eq!(user.email, StringOrBytes::String("alice.smith@example.com".to_owned()));
+		assert_eq!(user.preferred_username, StringOrBytes::String("alice.smith".to_owned()));
+	}
+
+	#[test]
+	fn test_transform_template() {
+		let transformer = transformer(
+			r#"
+            preferred_username: [!template "{givenName}.{sN}{missing}"]
+            "#,
+		);
+
+		let user = transformer.transform(User {
+			attributes: attributes(&[("givenName", "Alice"), ("sn", "Smith")]),
+			..user("alice", "alice@example.com")
+		});
+		assert_eq!(user.preferred_username, StringOrBytes::String("Alice.Smith".to_owned()));
+	}
+
+	#[test]
+	fn test_transform_missing_values() {
+		let transformer = transformer(
+			r#"
+            last_name: [!fallback_attribute sn]
+            phone: [!fallback_attribute mobile]
+            first_name: [!default Unknown]
+            email: [!fallback_attribute missing, !default nobody@example.com]
+            "#,
+		);
+
+		let user = transformer.transform(User {
+			first_name: " Alice ".to_owned().into(),
+			last_name: "".to_owned().into(),
+			attributes: attributes(&[("sn", "Smith"), ("mobile", "+12015550123")]),
+			..user("alice", "Alice.Smith@Example.COM")
+		});
+		assert_eq!(user.last_name, StringOrBytes::String("Smith".to_owned()));
+		assert_eq!(user.phone, Some(StringOrBytes::String("+12015550123".to_owned())));
+		assert_eq!(user.first_name, StringOrBytes::String(" Alice ".to_owned()));
+		assert_eq!(user.email, StringOrBytes::String("Alice.Smith@Example.COM".to_owned()));
+	}
+
+	#[test]
+	fn test_transform_binary_values() {
+		let transformer = transformer("preferred_username: [lowercase, trim]");
+
+		let mut user = user("alice", "alice@example.com");
+		user.preferred_username = StringOrBytes::Bytes(vec![0xA0, 0x20]);
+
+		let user = transformer.transform(user);
+		assert_eq!(user.preferred_username, StringOrBytes::Bytes(vec![0xA0, 0x20]));
+	}
+
+	#[test]
+	fn test_referenced_attributes() {
+		let config = TransformationsConfig {
+			first_name: vec![Transformation::FallbackAttribute("givenName".to_owned())],
+			last_name: vec![Transformation::Trim],
+			preferred_username: vec![Transformation::Template("{title} {sn}".to_owned())],
+			..Default::default()
+		};
+
+		assert_eq!(
+			config.referenced_attributes(),
+			BTreeSet::from(["givenName".to_owned(), "sn".to_owned(), "title".to_owned()])
+		);
+	}
+
+	#[test]
+	fn test_transform_invalid_regex() {
+		let config: TransformationsConfig = serde_yaml::from_str(
+			r#"
+            email:
+              - !regex_replace
+                pattern: "("
+                replacement: ""
+            "#,
+		)
+		.expect("invalid transformations");
+
+		assert!(Transformer::new(&config).is_err());
+	}
+}
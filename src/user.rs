@@ -1,13 +1,17 @@
 //! User data helpers
-use std::fmt::Display;
+use std::{
+	collections::{BTreeMap, HashMap},
+	fmt::Display,
+};
 
 use base64::prelude::{Engine, BASE64_STANDARD};
+use serde::{Deserialize, Serialize};
 use zitadel_rust_client::v1::{Email, Gender, Idp, ImportHumanUserRequest, Phone, Profile};
 
-use crate::{config::FeatureFlags, FeatureFlag};
+use crate::{config::FeatureFlags, grants::Grants, zitadel::ZitadelConfig, FeatureFlag};
 
 /// Source-agnostic representation of a user
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub(crate) struct User {
 	/// The user's first name
 	pub(crate) first_name: StringOrBytes,
@@ -23,18 +27,96 @@ pub(crate) struct User {
 	pub(crate) preferred_username: StringOrBytes,
 	/// The user's LDAP ID
 	pub(crate) external_user_id: StringOrBytes,
+	/// The raw attributes of the user's source record, keyed by
+	/// lowercase attribute name
+	pub(crate) attributes: HashMap<String, Vec<StringOrBytes>>,
+	/// Additional Zitadel metadata to maintain for the user, keyed by
+	/// metadata key
+	pub(crate) metadata: BTreeMap<String, StringOrBytes>,
+	/// Roles to grant in addition to the configured grants, keyed by
+	/// project ID
+	pub(crate) extra_grants: Grants,
+	/// The display name stored in Zitadel; only known for users read
+	/// from Zitadel, it is rendered from the configured template for
+	/// all others
+	pub(crate) display_name: Option<String>,
+	/// The Matrix localpart to assign on import, as resolved by the
+	/// collision check
+	pub(crate) localpart: Option<String>,
+	/// The login name stored in Zitadel; only known for users read from
+	/// Zitadel, it is taken from the configured source for all others
+	pub(crate) login_name: Option<String>,
 }
 
 impl User {
 	/// Convert the agnostic user to a Zitadel user
-	pub fn to_zitadel_user(&self, feature_flags: &FeatureFlags, idp_id: &str) -> ZitadelUser {
+	pub fn to_zitadel_user(
+		&self,
+		feature_flags: &FeatureFlags,
+		zitadel_config: &ZitadelConfig,
+	) -> ZitadelUser {
+		let idp_id = &zitadel_config.idp_id;
+
 		ZitadelUser {
 			user_data: self.clone(),
+			display_name: self
+				.display_name
+				.clone()
+				.unwrap_or_else(|| zitadel_config.display_name(self)),
+			login_name: self.login_name.clone().unwrap_or_else(|| zitadel_config.login_name(self)),
 			needs_email_verification: feature_flags.is_enabled(FeatureFlag::VerifyEmail),
 			needs_phone_verification: feature_flags.is_enabled(FeatureFlag::VerifyPhone),
 			idp_id: feature_flags.contains(&FeatureFlag::SsoLogin).then(|| idp_id.to_owned()),
 		}
 	}
+
+	/// Whether the user has an email address
+	pub(crate) fn has_email(&self) -> bool {
+		!self.email.to_string().is_empty()
+	}
+
+	/// Get all values of a raw source attribute; attribute names are
+	/// case-insensitive
+	pub(crate) fn attribute_values(&self, name: &str) -> &[StringOrBytes] {
+		self.attributes.get(&name.to_lowercase()).map_or(&[], Vec::as_slice)
+	}
+
+	/// Get the value of a user field by name, or else the first value of
+	/// the source attribute of that name
+	pub(crate) fn field_or_attribute(&self, name: &str) -> Option<String> {
+		match name {
+			"first_name" => Some(self.first_name.to_string()),
+			"last_name" => Some(self.last_name.to_string()),
+			"email" => Some(self.email.to_string()),
+			"phone" => self.phone.as_ref().map(ToString::to_string),
+			"preferred_username" => Some(self.preferred_username.to_string()),
+			"external_user_id" => Some(self.external_user_id.to_string()),
+			_ => self.attribute_values(name).first().map(ToString::to_string),
+		}
+	}
+
+	/// Describe the user as a JSON document, in the form the values are
+	/// written to Zitadel
+	pub(crate) fn describe(&self, zitadel_config: &ZitadelConfig) -> String {
+		let metadata: BTreeMap<&String, String> =
+			self.metadata.iter().map(|(key, value)| (key, value.to_string())).collect();
+
+		serde_json::json!({
+			"external_user_id": self.external_user_id.to_string(),
+			"enabled": self.enabled,
+			"login_name": zitadel_config.login_name(self),
+			"display_name": zitadel_config.display_name(self),
+			"first_name": self.first_name.to_string(),
+			"last_name": self.last_name.to_string(),
+			"email": self.email.to_string(),
+			"phone": self.phone.as_ref().map(ToString::to_string),
+			"preferred_username": self.preferred_username.to_string(),
+			"metadata": metadata,
+			"extra_grants": self.extra_grants,
+			"localpart": self.localpart,
+		})
+		.to_string()
+	}
 }
 
 /// Crate-internal representation of a Zitadel user
@@ -42,6 +124,10 @@ impl User {
 pub struct ZitadelUser {
 	/// Details about the user
 	pub(crate) user_data: User,
+	/// The user's display name
+	pub(crate) display_name: String,
+	/// The user's login name
+	pub(crate) login_name: String,
 
 	/// Whether the user should be prompted to verify their email
 	pub(crate) needs_email_verification: bool,
@@ -54,7 +140,12 @@ pub struct ZitadelUser {
 impl ZitadelUser {
 	/// Get a display name for the user
 	pub(crate) fn get_display_name(&self) -> String {
-		format!("{}, {}", self.user_data.last_name, self.user_data.first_name)
+		self.display_name.clone()
+	}
+
+	/// Return a copy of the user without a phone number
+	pub(crate) fn without_phone(&self) -> Self {
+		Self { user_data: User { phone: None, ..self.user_data.clone() }, ..self.clone() }
 	}
 
 	/// Return the name to be used in logs to identify this user
@@ -63,7 +154,7 @@ impl ZitadelUser {
 	}
 
 	/// Get idp link as required by Zitadel
-	fn get_idps(&self) -> Vec<Idp> {
+	pub(crate) fn get_idps(&self) -> Vec<Idp> {
 		if let Some(idp_id) = self.idp_id.clone() {
 			vec![Idp {
 				config_id: idp_id,
@@ -79,7 +170,7 @@ impl ZitadelUser {
 impl From<ZitadelUser> for ImportHumanUserRequest {
 	fn from(user: ZitadelUser) -> Self {
 		Self {
-			user_name: user.user_data.email.clone().to_string(),
+			user_name: user.login_name.clone(),
 			profile: Some(Profile {
 				first_name: user.user_data.first_name.clone().to_string(),
 				last_name: user.user_data.last_name.clone().to_string(),
@@ -112,8 +203,27 @@ impl Display for ZitadelUser {
 	}
 }
 
+/// A condition on the source attributes of a user
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct AttributeCondition {
+	/// The name of the source attribute, e.g. `memberOf`
+	pub attribute: String,
+	/// The value one of the attribute's values must have, compared
+	/// case-insensitively
+	pub value: String,
+}
+
+impl AttributeCondition {
+	/// Whether a user matches the condition
+	pub(crate) fn matches(&self, user: &User) -> bool {
+		user.attribute_values(&self.attribute)
+			.iter()
+			.any(|value| value.to_string().eq_ignore_ascii_case(&self.value))
+	}
+}
+
 /// A structure that can either be a string or bytes
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub(crate) enum StringOrBytes {
 	/// A string
 	String(String),
@@ -146,3 +256,65 @@ impl From<String> for StringOrBytes {
 		Self::String(value)
 	}
 }
+
+/// Fixtures shared by the tests of several modules
+#[cfg(test)]
+pub(crate) mod test_helpers {
+	use std::collections::{BTreeMap, HashMap};
+
+	use indoc::indoc;
+
+	use super::{StringOrBytes, User};
+	use crate::{grants::Grants, zitadel::ZitadelConfig};
+
+	/// A Zitadel configuration importing users into `org`, or into
+	/// `other-org` for addresses in `other.example.com`
+	const ZITADEL_CONFIG: &str = indoc! {r#"
+        url: http://localhost:8080
+        key_file: tests/environment/zitadel/service-user.json
+        organization_id: org
+        project_id: project
+        idp_id: 1
+        organization_rules:
+          - organization_id: other-org
+            email_domain: other.example.com
+    "#};
+
+	/// Parse [`ZITADEL_CONFIG`]
+	pub(crate) fn zitadel_config() -> ZitadelConfig {
+		serde_yaml::from_str(ZITADEL_CONFIG).expect("invalid config")
+	}
+
+	/// An enabled user named Test User, with the given external ID and
+	/// email address, whose preferred username is the external ID
+	pub(crate) fn user(id: &str, email: &str) -> User {
+		User {
+			first_name: "Test".to_owned().into(),
+			last_name: "User".to_owned().into(),
+			email: email.to_owned().into(),
+			phone: None,
+			enabled: true,
+			preferred_username: id.to_owned().into(),
+			external_user_id: id.to_owned().into(),
+			attributes: HashMap::new(),
+			metadata: BTreeMap::new(),
+			extra_grants: Grants::new(),
+			display_name: None,
+			localpart: None,
+			login_name: None,
+		}
+	}
+
+	/// Source attributes with the given names and values; names may repeat
+	/// for attributes with several values
+	pub(crate) fn attributes(values: &[(&str, &str)]) -> HashMap<String, Vec<StringOrBytes>> {
+		let mut attributes: HashMap<String, Vec<StringOrBytes>> = HashMap::new();
+		for (name, value) in values {
+			attributes
+				.entry(name.to_lowercase())
+				.or_default()
+				.push(StringOrBytes::String((*value).to_owned()));
+		}
+		attributes
+	}
+}
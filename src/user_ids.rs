@@ -0,0 +1,176 @@
+//! Local cache mapping external user IDs to Zitadel user IDs
+use std::{
+	collections::HashMap,
+	path::{Path, PathBuf},
+	sync::{Arc, Mutex, PoisonError},
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// Where a user lives in Zitadel
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct UserRef {
+	/// The Zitadel ID of the user
+	pub(crate) user_id: String,
+	/// The organization the user belongs to
+	pub(crate) organization_id: String,
+}
+
+/// Maps the stable external ID of each user (the ID of the user in the
+/// source, stored as nick name in Zitadel) to their Zitadel user ID and
+/// organization.
+///
+/// The cache is optionally persisted to a JSON file, so lookups keep
+/// working even if the user's nick name was changed in Zitadel. Clones
+/// share the same underlying map.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct UserIdCache {
+	/// Where the cache is persisted, if anywhere
+	path: Option<PathBuf>,
+	/// The cached users, keyed by external user ID
+	ids: Arc<Mutex<HashMap<String, UserRef>>>,
+}
+
+impl UserIdCache {
+	/// Load the cache from the given path; a missing file results in
+	/// an empty cache
+	pub(crate) async fn load(path: Option<&Path>) -> Result<Self> {
+		let Some(path) = path else {
+			return Ok(Self::default());
+		};
+
+		let ids = match tokio::fs::read(path).await {
+			Ok(data) => match serde_json::from_slice(&data) {
+				Ok(ids) => ids,
+				// Caches written before users were tracked per
+				// organization lack the organization, so start over
+				Err(_) if serde_json::from_slice::<HashMap<String, String>>(&data).is_ok() => {
+					tracing::info!("Discarding user ID cache without organizations");
+					HashMap::new()
+				}
+				Err(error) => bail!(anyhow::Error::new(error).context("user ID cache is corrupt")),
+			},
+			Err(err) => {
+				if err.kind() == std::io::ErrorKind::NotFound {
+					tracing::info!("User ID cache missing");
+					HashMap::new()
+				} else {
+					bail!(err)
+				}
+			}
+		};
+
+		Ok(Self { path: Some(path.to_owned()), ids: Arc::new(Mutex::new(ids)) })
+	}
+
+	/// Create a cache holding the given users, which is not persisted
+	/// to a file
+	pub(crate) fn with_ids(ids: HashMap<String, UserRef>) -> Self {
+		Self { path: None, ids: Arc::new(Mutex::new(ids)) }
+	}
+
+	/// All cached users, keyed by external user ID
+	pub(crate) fn to_map(&self) -> HashMap<String, UserRef> {
+		self.lock().clone()
+	}
+
+	/// Write the cache to disk, if a path is configured
+	pub(crate) async fn persist(&self) -> Result<()> {
+		let Some(path) = &self.path else {
+			return Ok(());
+		};
+
+		let data = serde_json::to_vec(&*self.lock()).context("failed to serialize user IDs")?;
+		tokio::fs::write(path, data).await.context("failed to write user ID cache")?;
+
+		Ok(())
+	}
+
+	/// Get the Zitadel ID and organization of a user
+	pub(crate) fn get(&self, external_id: &str) -> Option<UserRef> {
+		self.lock().get(external_id).cloned()
+	}
+
+	/// Remember the Zitadel ID and organization of a user
+	pub(crate) fn insert(&self, external_id: String, user: UserRef) {
+		self.lock().insert(external_id, user);
+	}
+
+	/// Forget a user by their external ID
+	pub(crate) fn remove(&self, external_id: &str) {
+		self.lock().remove(external_id);
+	}
+
+	/// Forget a user by their Zitadel ID
+	pub(crate) fn remove_zitadel_id(&self, zitadel_id: &str) {
+		self.lock().retain(|_, user| user.user_id != zitadel_id);
+	}
+
+	/// Lock the underlying map
+	fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, UserRef>> {
+		self.ids.lock().unwrap_or_else(PoisonError::into_inner)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use tempfile::TempDir;
+
+	use super::*;
+
+	fn user_ref(user_id: &str) -> UserRef {
+		UserRef { user_id: user_id.to_owned(), organization_id: "org".to_owned() }
+	}
+
+	#[tokio::test]
+	async fn test_user_id_cache_roundtrip() {
+		let tempdir = TempDir::new().expect("failed to create temp dir");
+		let path = tempdir.path().join("user_ids.json");
+
+		let cache = UserIdCache::load(Some(&path)).await.expect("failed to load missing cache");
+		assert_eq!(cache.get("alice"), None);
+
+		cache.insert("alice".to_owned(), user_ref("1"));
+		cache.insert("bob".to_owned(), user_ref("2"));
+		cache.insert("carol".to_owned(), user_ref("3"));
+		cache.remove("bob");
+		cache.remove_zitadel_id("3");
+		cache.persist().await.expect("failed to persist cache");
+
+		let cache = UserIdCache::load(Some(&path)).await.expect("failed to load cache");
+		assert_eq!(cache.get("alice"), Some(user_ref("1")));
+		assert_eq!(cache.get("bob"), None);
+		assert_eq!(cache.get("carol"), None);
+	}
+
+	#[tokio::test]
+	async fn test_user_id_cache_in_memory() {
+		let cache = UserIdCache::load(None).await.expect("failed to create cache");
+
+		cache.insert("alice".to_owned(), user_ref("1"));
+		cache.persist().await.expect("persisting without a path must be a no-op");
+
+		assert_eq!(cache.clone().get("alice"), Some(user_ref("1")));
+	}
+
+	#[test]
+	fn test_user_id_cache_with_ids() {
+		let cache = UserIdCache::with_ids(HashMap::from([("alice".to_owned(), user_ref("1"))]));
+
+		cache.insert("bob".to_owned(), user_ref("2"));
+		cache.remove("alice");
+
+		assert_eq!(cache.to_map(), HashMap::from([("bob".to_owned(), user_ref("2"))]));
+	}
+
+	#[tokio::test]
+	async fn test_user_id_cache_legacy_format() {
+		let tempdir = TempDir::new().expect("failed to create temp dir");
+		let path = tempdir.path().join("user_ids.json");
+		std::fs::write(&path, r#"{"alice": "1"}"#).expect("failed to write legacy cache");
+
+		let cache = UserIdCache::load(Some(&path)).await.expect("failed to load legacy cache");
+		assert_eq!(cache.get("alice"), None);
+	}
+}
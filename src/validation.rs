@@ -0,0 +1,590 @@
+//! Pre-flight validation of users before they are written to Zitadel
+use std::{collections::HashMap, fmt::Display};
+
+use anyhow::{bail, Result};
+use itertools::Itertools;
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::{
+	report::{ReportEntry, SyncReport},
+	state::PendingChange,
+	user::{StringOrBytes, User},
+	zitadel::{ChangedUser, LoginNameSource, SourceDiff, ZitadelConfig},
+};
+
+/// The maximum length of the values Zitadel accepts for user fields
+const MAX_LENGTH: usize = 200;
+
+/// The email addresses Zitadel accepts, which follow the HTML
+/// specification
+const EMAIL_PATTERN: &str = r"^[a-zA-Z0-9.!#$%&'*+/=?^_`{|}~-]+@[a-zA-Z0-9](?:[a-zA-Z0-9-]{0,61}[a-zA-Z0-9])?(?:\.[a-zA-Z0-9](?:[a-zA-Z0-9-]{0,61}[a-zA-Z0-9])?)*$";
+
+/// Phone numbers consisting of digits and formatting characters
+const PHONE_PATTERN: &str = r"^\+?[0-9()./ -]*[0-9][0-9()./ -]*$";
+
+/// What to do with a user that fails validation
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ValidationPolicy {
+	/// Sync the user without the invalid field, only possible for
+	/// optional fields
+	DropField,
+	/// Leave the user alone, neither importing nor updating it
+	SkipUser,
+	/// Abort the sync run before anything is written
+	Abort,
+}
+
+impl Display for ValidationPolicy {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::DropField => write!(f, "the field is not synced"),
+			Self::SkipUser => write!(f, "the user is not synced"),
+			Self::Abort => write!(f, "the sync is aborted"),
+		}
+	}
+}
+
+/// What to do with users that fail validation, per field
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct ValidationConfig {
+	/// Missing, overly long or malformed email addresses
+	pub email: ValidationPolicy,
+	/// Missing or overly long first names
+	pub first_name: ValidationPolicy,
+	/// Missing or overly long last names
+	pub last_name: ValidationPolicy,
+	/// Overly long preferred usernames
+	pub preferred_username: ValidationPolicy,
+	/// Overly long or malformed phone numbers
+	pub phone: ValidationPolicy,
+	/// Missing or overly long login names
+	pub login_name: ValidationPolicy,
+	/// Email addresses, login names or localparts shared by several
+	/// users of a source, or with users already in Zitadel
+	pub duplicates: ValidationPolicy,
+}
+
+impl Default for ValidationConfig {
+	fn default() -> Self {
+		Self {
+			email: ValidationPolicy::SkipUser,
+			first_name: ValidationPolicy::SkipUser,
+			last_name: ValidationPolicy::SkipUser,
+			preferred_username: ValidationPolicy::DropField,
+			phone: ValidationPolicy::DropField,
+			login_name: ValidationPolicy::SkipUser,
+			duplicates: ValidationPolicy::SkipUser,
+		}
+	}
+}
+
+impl ValidationConfig {
+	/// Check that only optional fields are configured to be dropped
+	pub(crate) fn check(&self) -> Result<()> {
+		for field in
+			[Field::Email, Field::FirstName, Field::LastName, Field::LoginName, Field::Duplicate]
+		{
+			if self.policy(field) == ValidationPolicy::DropField {
+				bail!("invalid {} cannot be dropped, as they are required", field);
+			}
+		}
+
+		Ok(())
+	}
+
+	/// The policy for problems with a field
+	fn policy(&self, field: Field) -> ValidationPolicy {
+		match field {
+			Field::Email => self.email,
+			Field::FirstName => self.first_name,
+			Field::LastName => self.last_name,
+			Field::PreferredUsername => self.preferred_username,
+			Field::Phone => self.phone,
+			Field::LoginName => self.login_name,
+			Field::Duplicate => self.duplicates,
+		}
+	}
+}
+
+/// A validated field of a user
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+	/// The email address
+	Email,
+	/// The first name
+	FirstName,
+	/// The last name
+	LastName,
+	/// The preferred username
+	PreferredUsername,
+	/// The phone number
+	Phone,
+	/// The login name
+	LoginName,
+	/// A value shared with other users
+	Duplicate,
+}
+
+impl Field {
+	/// Remove the field from a user, if it is optional
+	fn remove_from(self, user: &mut User) {
+		match self {
+			Self::PreferredUsername => {
+				user.preferred_username = StringOrBytes::String(String::new());
+			}
+			Self::Phone => user.phone = None,
+			Self::Email | Self::FirstName | Self::LastName | Self::LoginName | Self::Duplicate => {}
+		}
+	}
+}
+
+impl Display for Field {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::Email => write!(f, "email addresses"),
+			Self::FirstName => write!(f, "first names"),
+			Self::LastName => write!(f, "last names"),
+			Self::PreferredUsername => write!(f, "preferred usernames"),
+			Self::Phone => write!(f, "phone numbers"),
+			Self::LoginName => write!(f, "login names"),
+			Self::Duplicate => write!(f, "duplicate values"),
+		}
+	}
+}
+
+/// A problem found with a user
+#[derive(Debug, Clone)]
+struct Problem {
+	/// The field the problem concerns
+	field: Field,
+	/// What is wrong, phrased to follow the user's ID
+	description: String,
+}
+
+impl Problem {
+	/// Create a new problem
+	fn new(field: Field, description: impl Into<String>) -> Self {
+		Self { field, description: description.into() }
+	}
+}
+
+/// Validates the users of a source before they are written to Zitadel,
+/// applying the configured policies to invalid users
+#[derive(Debug)]
+pub(crate) struct Validator<'a> {
+	/// The policies to apply
+	config: &'a ValidationConfig,
+	/// The Zitadel configuration, which determines the login names
+	zitadel_config: &'a ZitadelConfig,
+	/// Matches valid email addresses
+	email: Regex,
+	/// Matches valid phone numbers
+	phone: Regex,
+	/// Where to report invalid users
+	report: SyncReport,
+}
+
+impl<'a> Validator<'a> {
+	/// Create a validator for the given configuration
+	pub(crate) fn new(
+		config: &'a ValidationConfig,
+		zitadel_config: &'a ZitadelConfig,
+		report: SyncReport,
+	) -> Result<Self> {
+		Ok(Self {
+			config,
+			zitadel_config,
+			email: Regex::new(EMAIL_PATTERN)?,
+			phone: Regex::new(PHONE_PATTERN)?,
+			report,
+		})
+	}
+
+	/// Validate the new and changed users of a diff against each other
+	/// and the given users already in Zitadel
+	///
+	/// Invalid users are left out of the diff or have their invalid
+	/// fields dropped. The changes of users left out are returned
+	/// separately, so they can be retried once the users were fixed.
+	/// Users that are disabled are about to be deleted, so they are not
+	/// validated.
+	pub(crate) fn validate_diff(
+		&self,
+		source_name: &str,
+		diff: SourceDiff,
+		existing: &[User],
+	) -> Result<(SourceDiff, Vec<PendingChange>)> {
+		let duplicates = self.find_duplicates(
+			diff.new_users.iter().chain(diff.changed_users.iter().map(|changed| &changed.new)),
+			existing,
+		);
+
+		let mut skipped = Vec::new();
+
+		let mut new_users = Vec::new();
+		for user in diff.new_users {
+			match self.validate_user(source_name, user.clone(), &duplicates)? {
+				Some(user) => new_users.push(user),
+				None => skipped.push(PendingChange::Import(Box::new(user))),
+			}
+		}
+
+		let mut changed_users = Vec::new();
+		for ChangedUser { old, new } in diff.changed_users {
+			match self.validate_user(source_name, new.clone(), &duplicates)? {
+				Some(new) => changed_users.push(ChangedUser { old, new }),
+				None => skipped.push(PendingChange::Update(Box::new(ChangedUser { old, new }))),
+			}
+		}
+
+		Ok((
+			SourceDiff { new_users, changed_users, deleted_user_ids: diff.deleted_user_ids },
+			skipped,
+		))
+	}
+
+	/// Validate a single user, returning it if it should be synced
+	fn validate_user(
+		&self,
+		source_name: &str,
+		mut user: User,
+		duplicates: &HashMap<String, Vec<Problem>>,
+	) -> Result<Option<User>> {
+		if !user.enabled {
+			return Ok(Some(user));
+		}
+
+		let external_user_id = user.external_user_id.to_string();
+		let mut problems = self.find_problems(&user);
+		problems.extend(duplicates.get(&external_user_id).into_iter().flatten().cloned());
+
+		if let Some(problem) = problems
+			.iter()
+			.find(|problem| self.config.policy(problem.field) == ValidationPolicy::Abort)
+		{
+			bail!("{}: user `{}` {}", source_name, external_user_id, problem.description);
+		}
+
+		let skip = problems
+			.iter()
+			.any(|problem| self.config.policy(problem.field) == ValidationPolicy::SkipUser);
+
+		for problem in problems {
+			let policy =
+				if skip { ValidationPolicy::SkipUser } else { self.config.policy(problem.field) };
+			tracing::warn!("User `{}` {}, {}", external_user_id, problem.description, policy);
+			self.report.record(ReportEntry::InvalidUser {
+				source: source_name.to_owned(),
+				external_user_id: external_user_id.clone(),
+				problem: problem.description,
+				policy,
+			});
+
+			if !skip {
+				problem.field.remove_from(&mut user);
+			}
+		}
+
+		Ok((!skip).then_some(user))
+	}
+
+	/// Find the problems with the fields of a single user
+	fn find_problems(&self, user: &User) -> Vec<Problem> {
+		let mut problems = Vec::new();
+
+		let email = user.email.to_string();
+		if email.is_empty() {
+			problems.push(Problem::new(Field::Email, "has no email address"));
+		} else if !self.email.is_match(&email) {
+			problems.push(Problem::new(
+				Field::Email,
+				format!("has an invalid email address `{email}`"),
+			));
+		}
+
+		for (field, name, value) in [
+			(Field::FirstName, "first name", user.first_name.to_string()),
+			(Field::LastName, "last name", user.last_name.to_string()),
+		] {
+			if value.is_empty() {
+				problems.push(Problem::new(field, format!("has no {name}")));
+			}
+		}
+
+		// Login names fall back to the email address, so they are only
+		// ever missing along with it
+		for (field, name, value) in [
+			(Field::Email, "email address", email),
+			(Field::FirstName, "first name", user.first_name.to_string()),
+			(Field::LastName, "last name", user.last_name.to_string()),
+			(Field::PreferredUsername, "preferred username", user.preferred_username.to_string()),
+			(Field::LoginName, "login name", self.zitadel_config.login_name(user)),
+		] {
+			if value.chars().count() > MAX_LENGTH {
+				problems.push(Problem::new(
+					field,
+					format!("has a {name} longer than {MAX_LENGTH} characters"),
+				));
+			}
+		}
+
+		if let Some(phone) = user.phone.as_ref().map(ToString::to_string) {
+			if phone.chars().count() > MAX_LENGTH || !self.phone.is_match(&phone) {
+				problems.push(Problem::new(
+					Field::Phone,
+					format!("has an invalid phone number `{phone}`"),
+				));
+			}
+		}
+
+		problems
+	}
+
+	/// Find the email addresses, login names and localparts shared by
+	/// several enabled users, or with users already in Zitadel, keyed by
+	/// the external IDs of the users
+	fn find_duplicates<'u>(
+		&self,
+		users: impl Iterator<Item = &'u User>,
+		existing: &[User],
+	) -> HashMap<String, Vec<Problem>> {
+		let mut values = Vec::new();
+		for user in users.filter(|user| user.enabled) {
+			let external_user_id = user.external_user_id.to_string();
+
+			values.push((
+				("email address", user.email.to_string().to_lowercase()),
+				external_user_id.clone(),
+			));
+			if let Some(localpart) = &user.localpart {
+				values.push((("localpart", localpart.clone()), external_user_id.clone()));
+			}
+			// Email login names are already covered by the email addresses
+			if self.zitadel_config.login_name != LoginNameSource::Email {
+				let login_name = self.zitadel_config.login_name(user).to_lowercase();
+				values.push((("login name", login_name), external_user_id));
+			}
+		}
+
+		// Users already in Zitadel do not conflict with the users of the
+		// same external ID, which they are. Email login names count as
+		// email addresses, and localparts are checked when assigned.
+		let login_name_kind = match self.zitadel_config.login_name {
+			LoginNameSource::Email => "email address",
+			_ => "login name",
+		};
+		for user in existing {
+			let login_name = user.login_name.clone().unwrap_or_default().to_lowercase();
+			let external_user_id = match user.external_user_id.to_string() {
+				external_user_id if external_user_id.is_empty() => login_name.clone(),
+				external_user_id => external_user_id,
+			};
+
+			values.push((
+				("email address", user.email.to_string().to_lowercase()),
+				external_user_id.clone(),
+			));
+			values.push(((login_name_kind, login_name), external_user_id));
+		}
+
+		let mut duplicates: HashMap<String, Vec<Problem>> = HashMap::new();
+		let groups =
+			values.into_iter().filter(|((_, value), _)| !value.is_empty()).into_group_map();
+		for ((name, value), external_user_ids) in groups {
+			let external_user_ids: Vec<String> =
+				external_user_ids.into_iter().sorted().dedup().collect();
+			if external_user_ids.len() < 2 {
+				continue;
+			}
+
+			let description = format!(
+				"shares the {name} `{value}` with other users ({})",
+				external_user_ids.iter().join(", ")
+			);
+			for external_user_id in external_user_ids {
+				duplicates
+					.entry(external_user_id)
+					.or_default()
+					.push(Problem::new(Field::Duplicate, description.clone()));
+			}
+		}
+
+		duplicates
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		user::test_helpers::{user, zitadel_config},
+		zitadel::UserId,
+	};
+
+	fn diff(new_users: Vec<User>) -> SourceDiff {
+		SourceDiff {
+			new_users,
+			changed_users: vec![],
+			deleted_user_ids: vec![UserId::ZitadelId("deleted".to_owned())],
+		}
+	}
+
+	#[test]
+	fn test_validate_fields() {
+		let config = ValidationConfig::default();
+		let zitadel_config = zitadel_config();
+		let report = SyncReport::default();
+		let validator =
+			Validator::new(&config, &zitadel_config, report.clone()).expect("invalid validator");
+
+		let valid = User {
+			phone: Some("+1 (201) 555-0123".to_owned().into()),
+			..user("valid", "valid@example.com")
+		};
+		let mut bad_phone = user("bad_phone", "bad_phone@example.com");
+		bad_phone.phone = Some("call me".to_owned().into());
+		let mut no_name = user("no_name", "no_name@example.com");
+		no_name.first_name = String::new().into();
+		let mut long_name = user("long_name", "long_name@example.com");
+		long_name.last_name = "a".repeat(MAX_LENGTH + 1).into();
+		let mut disabled = user("disabled", "invalid");
+		disabled.enabled = false;
+
+		let (diff, _) = validator
+			.validate_diff(
+				"LDAP",
+				diff(vec![
+					valid,
+					user("bad_email", "bad email@example.com"),
+					bad_phone,
+					no_name,
+					long_name,
+					disabled,
+				]),
+				&[],
+			)
+			.expect("validation should not abort");
+
+		let ids: Vec<String> =
+			diff.new_users.iter().map(|user| user.external_user_id.to_string()).collect();
+		assert_eq!(ids, vec!["valid", "bad_phone", "disabled"]);
+		assert!(diff.new_users[0].phone.is_some());
+		assert_eq!(diff.new_users[1].phone, None);
+		assert_eq!(diff.deleted_user_ids.len(), 1);
+
+		let entries = report.entries();
+		assert_eq!(entries.len(), 4);
+		assert_eq!(
+			entries[1],
+			ReportEntry::InvalidUser {
+				source: "LDAP".to_owned(),
+				external_user_id: "bad_phone".to_owned(),
+				problem: "has an invalid phone number `call me`".to_owned(),
+				policy: ValidationPolicy::DropField,
+			}
+		);
+	}
+
+	#[test]
+	fn test_validate_duplicates() {
+		let config = ValidationConfig::default();
+		let zitadel_config = zitadel_config();
+		let validator = Validator::new(&config, &zitadel_config, SyncReport::default())
+			.expect("invalid validator");
+
+		let mut changed = user("changed", "Alice@example.com");
+		changed.preferred_username = "changed".to_owned().into();
+		let diff = SourceDiff {
+			new_users: vec![user("alice", "alice@example.com"), user("bob", "bob@example.com")],
+			changed_users: vec![ChangedUser {
+				old: user("changed", "old@example.com"),
+				new: changed,
+			}],
+			deleted_user_ids: vec![],
+		};
+
+		let (diff, skipped) =
+			validator.validate_diff("LDAP", diff, &[]).expect("validation should not abort");
+		assert_eq!(diff.new_users.len(), 1);
+		assert_eq!(diff.new_users[0].external_user_id, StringOrBytes::String("bob".to_owned()));
+		assert!(diff.changed_users.is_empty());
+
+		// Skipped users are retried later, unchanged
+		assert!(matches!(
+			skipped.as_slice(),
+			[PendingChange::Import(alice), PendingChange::Update(changed)]
+				if alice.external_user_id == StringOrBytes::String("alice".to_owned())
+					&& changed.new.email == StringOrBytes::String("Alice@example.com".to_owned())
+		));
+	}
+
+	#[test]
+	fn test_validate_duplicates_existing() {
+		let config = ValidationConfig::default();
+		let zitadel_config = zitadel_config();
+		let validator = Validator::new(&config, &zitadel_config, SyncReport::default())
+			.expect("invalid validator");
+
+		let existing = vec![
+			User { login_name: Some("alice@example.com".to_owned()), ..user("alice", "") },
+			User { login_name: Some("carol@example.com".to_owned()), ..user("", "") },
+		];
+		let diff = diff(vec![
+			user("alice", "Alice@example.com"),
+			user("bob", "alice@example.com"),
+			user("carol", "carol@example.com"),
+			user("dave", "dave@example.com"),
+		]);
+
+		let (diff, _) =
+			validator.validate_diff("LDAP", diff, &existing).expect("validation should not abort");
+		let ids: Vec<String> =
+			diff.new_users.iter().map(|user| user.external_user_id.to_string()).collect();
+		// Alice conflicts with Bob, but not with herself
+		assert_eq!(ids, vec!["dave"]);
+	}
+
+	#[test]
+	fn test_validate_missing_email() {
+		let config = ValidationConfig::default();
+		let mut zitadel_config = zitadel_config();
+		let no_email = || diff(vec![user("no_email", "")]);
+
+		let validator = Validator::new(&config, &zitadel_config, SyncReport::default())
+			.expect("invalid validator");
+		let (diff, _) =
+			validator.validate_diff("LDAP", no_email(), &[]).expect("validation should not abort");
+		assert!(diff.new_users.is_empty());
+
+		// Zitadel requires email addresses, whatever the login names are
+		zitadel_config.login_name = LoginNameSource::ExternalUserId;
+		let validator = Validator::new(&config, &zitadel_config, SyncReport::default())
+			.expect("invalid validator");
+		let (diff, _) =
+			validator.validate_diff("LDAP", no_email(), &[]).expect("validation should not abort");
+		assert!(diff.new_users.is_empty());
+	}
+
+	#[test]
+	fn test_validate_abort() {
+		let config = ValidationConfig { phone: ValidationPolicy::Abort, ..Default::default() };
+		let zitadel_config = zitadel_config();
+		let validator = Validator::new(&config, &zitadel_config, SyncReport::default())
+			.expect("invalid validator");
+
+		let mut bad_phone = user("bad_phone", "bad_phone@example.com");
+		bad_phone.phone = Some("call me".to_owned().into());
+
+		assert!(validator
+			.validate_diff("LDAP", diff(vec![user("valid", "valid@example.com")]), &[])
+			.is_ok());
+		assert!(validator.validate_diff("LDAP", diff(vec![bad_phone]), &[]).is_err());
+
+		let config = ValidationConfig { email: ValidationPolicy::DropField, ..Default::default() };
+		assert!(config.check().is_err());
+		assert!(ValidationConfig::default().check().is_ok());
+	}
+}
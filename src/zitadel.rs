@@ -1,27 +1,65 @@
 //! Helper functions for submitting data to Zitadel
-use std::path::PathBuf;
+use std::{
+	collections::{BTreeMap, BTreeSet, HashMap},
+	future::Future,
+	path::PathBuf,
+	sync::{
+		atomic::{AtomicUsize, Ordering},
+		Arc, Mutex, PoisonError,
+	},
+};
 
-use anyhow::{bail, Context, Result};
-use serde::Deserialize;
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use url::Url;
-use uuid::{uuid, Uuid};
 use zitadel_rust_client::v1::{
 	error::{Error as ZitadelError, TonicErrorCode},
-	Zitadel as ZitadelClient,
+	UserState, UserType, Zitadel as ZitadelClient,
 };
 
 use crate::{
+	audit::{AuditAction, AuditJournal, AuditRecord, AuditResult, JournalHead},
+	circuit_breaker::CircuitBreaker,
+	concurrency::{self, BatchSummary},
 	config::{Config, FeatureFlags},
+	grants::{self, GrantConfig, Grants},
+	localpart::{self, LocalpartConfig},
+	organizations::{self, OrganizationRule},
+	rate_limit::{RateLimitConfig, RateLimiter},
+	reconcile,
+	report::{ReportEntry, SyncReport},
+	state::StateStore,
+	transform,
 	user::{StringOrBytes, User, ZitadelUser},
+	user_ids::{UserIdCache, UserRef},
 	FeatureFlag,
 };
 
-/// The Famedly UUID namespace to use to generate v5 UUIDs.
-const FAMEDLY_NAMESPACE: Uuid = uuid!("d9979cff-abee-4666-bc88-1ec45a843fb8");
-
-/// The Zitadel project role to assign to users.
+/// The Zitadel project role to assign to users if no grants are
+/// configured.
 const FAMEDLY_USER_ROLE: &str = "User";
 
+/// The display name template used unless another one is configured
+const DEFAULT_DISPLAY_NAME_TEMPLATE: &str = "{last_name}, {first_name}";
+
+/// How many users are written to Zitadel at once unless configured
+/// otherwise
+const DEFAULT_CONCURRENCY: usize = 4;
+
+/// How many requests in a row may fail to reach Zitadel before a run is
+/// aborted, unless configured otherwise
+const DEFAULT_CIRCUIT_BREAKER_THRESHOLD: u32 = 10;
+
+/// The metadata key marking a user as managed by the sync, whose value
+/// names the source the user was imported from.
+const MANAGED_BY_METADATA_KEY: &str = "managed_by";
+
+/// Metadata keys the sync maintains itself, which may not be mapped
+/// from source attributes
+pub(crate) const RESERVED_METADATA_KEYS: [&str; 3] =
+	[MANAGED_BY_METADATA_KEY, "preferred_username", "localpart"];
+
 /// A very high-level Zitadel zitadel_client
 #[derive(Clone)]
 pub(crate) struct Zitadel {
@@ -31,214 +69,486 @@ pub(crate) struct Zitadel {
 	feature_flags: FeatureFlags,
 	/// The backing Zitadel zitadel_client
 	zitadel_client: ZitadelClient,
+	/// Limits the requests sent through the client
+	rate_limiter: RateLimiter,
+	/// Stops sending requests once Zitadel is unreachable
+	circuit_breaker: CircuitBreaker,
+	/// Report of noteworthy events during the sync run
+	report: SyncReport,
+	/// Cached Zitadel IDs of users, keyed by their external ID
+	user_ids: UserIdCache,
+	/// The localparts taken in Zitadel, mapped to their holders, once
+	/// they are known
+	localparts: Arc<Mutex<Option<HashMap<String, String>>>>,
+	/// Journal of the changes made to Zitadel, if one is kept
+	journal: Option<AuditJournal>,
 }
 
 impl Zitadel {
 	/// Construct the Zitadel instance
-	pub(crate) async fn new(config: &Config) -> Result<Self> {
+	///
+	/// The cached Zitadel IDs of users are taken from the state store if
+	/// one is given, importing the user ID cache file if the store has
+	/// none yet. So are the taken localparts, if the store has any.
+	pub(crate) async fn new(
+		config: &Config,
+		report: SyncReport,
+		state: Option<&dyn StateStore>,
+	) -> Result<Self> {
 		let zitadel_client =
 			ZitadelClient::new(config.zitadel.url.clone(), config.zitadel.key_file.clone())
 				.await
 				.context("failed to configure zitadel_client")?;
 
+		let user_id_cache_path = config.zitadel.user_id_cache_path.as_deref();
+		let user_ids = match state {
+			Some(state) => {
+				let mut ids = state.user_refs().await?;
+				if ids.is_empty() && user_id_cache_path.is_some() {
+					tracing::info!("Importing user ID cache into the state store");
+					ids = UserIdCache::load(user_id_cache_path).await?.to_map();
+				}
+				UserIdCache::with_ids(ids)
+			}
+			None => UserIdCache::load(user_id_cache_path).await?,
+		};
+
+		let localparts = match state {
+			Some(state) => {
+				Some(state.localparts().await?).filter(|localparts| !localparts.is_empty())
+			}
+			None => None,
+		};
+
+		let journal = match &config.zitadel.audit_journal_path {
+			Some(path) => {
+				let head = match state {
+					Some(state) => state.journal_head().await?,
+					None => None,
+				};
+				Some(AuditJournal::open(path, head.as_ref())?)
+			}
+			None => None,
+		};
+
 		Ok(Self {
 			zitadel_config: config.zitadel.clone(),
 			feature_flags: config.feature_flags.clone(),
 			zitadel_client,
+			rate_limiter: RateLimiter::new(&config.zitadel.rate_limit),
+			circuit_breaker: CircuitBreaker::new(config.zitadel.circuit_breaker_threshold),
+			report,
+			user_ids,
+			localparts: Arc::new(Mutex::new(localparts)),
+			journal,
 		})
 	}
 
-	/// Import a list of new users into Zitadel
-	pub(crate) async fn import_new_users(&self, users: Vec<User>) -> Result<()> {
-		for user in users {
-			let zitadel_user =
-				user.to_zitadel_user(&self.feature_flags, &self.zitadel_config.idp_id);
-			let status = self.import_user(&zitadel_user).await;
-
-			if let Err(error) = status {
-				tracing::error!(
-					"Failed to sync-import user `{}`: {:?}",
-					zitadel_user.log_name(),
-					error
-				);
-
-				if Self::is_invalid_phone_error(error) {
-					let zitadel_user = ZitadelUser {
-						user_data: User { phone: None, ..zitadel_user.user_data },
-						..zitadel_user
-					};
-
-					let retry_status = self.import_user(&zitadel_user).await;
+	/// Import a list of new users from the given source into Zitadel,
+	/// returning the external IDs of the users that failed to import
+	pub(crate) async fn import_new_users(
+		&self,
+		source_name: &str,
+		users: Vec<User>,
+	) -> Result<Vec<String>> {
+		let users = users
+			.into_iter()
+			.map(|user| {
+				let zitadel_user = user.to_zitadel_user(&self.feature_flags, &self.zitadel_config);
+				(user.external_user_id.to_string(), zitadel_user)
+			})
+			.collect();
 
-					match retry_status {
-						Ok(_) => {
-							tracing::info!(
-								"Retry sync-import succeeded for user `{}`",
-								zitadel_user.log_name()
-							);
-						}
-						Err(retry_error) => {
-							tracing::error!(
-								"Retry sync-import failed for user `{}`: {:?}",
-								zitadel_user.log_name(),
-								retry_error
-							);
-						}
-					}
-				}
-			}
-		}
+		let source_name = source_name.to_owned();
+		let summary = self
+			.for_each_user("import", users, move |zitadel, user| {
+				let source_name = source_name.clone();
+				async move { zitadel.import_new_user(user, &source_name).await }
+			})
+			.await;
 
-		Ok(())
+		Ok(summary.failed_users)
 	}
 
-	/// Delete a list of Zitadel users given their IDs
-	pub(crate) async fn delete_users_by_id(&self, users: Vec<UserId>) -> Result<()> {
-		for user_id in users {
-			match user_id {
-				UserId::Login(login) => {
-					let status = self.delete_user_by_email(&login).await;
-					if let Err(error) = status {
-						tracing::error!("Failed to delete user by email `{}`: {:?}", login, error);
-					}
-				}
-				UserId::Nick(nick) => {
-					let status = self.delete_user_by_nick(&nick).await;
-					if let Err(error) = status {
-						tracing::error!("Failed to delete user by nick `{}`: {:?}", nick, error);
-					}
+	/// Delete a list of Zitadel users given their IDs, returning the IDs
+	/// of the users that failed to be deleted
+	pub(crate) async fn delete_users_by_id(&self, users: Vec<UserId>) -> Result<Vec<String>> {
+		let users = users.into_iter().map(|user_id| (user_id.key().to_owned(), user_id)).collect();
+
+		let summary = self
+			.for_each_user("delete", users, |zitadel, user_id| async move {
+				match user_id {
+					UserId::Login(login) => zitadel.delete_user_by_email(&login).await,
+					UserId::Nick(nick) => zitadel.delete_user_by_nick(&nick).await,
+					UserId::ZitadelId(id) => zitadel.delete_user_by_zitadel_id(&id).await,
 				}
-				UserId::ZitadelId(id) => {
-					let status = self.delete_user_by_id(&id).await;
-					if let Err(error) = status {
-						tracing::error!("Failed to delete user by id `{}`: {:?}", id, error);
-					}
-				}
-			}
-		}
+			})
+			.await;
 
-		Ok(())
+		Ok(summary.failed_users)
 	}
 
-	/// Update a list of old/new user maps
-	pub(crate) async fn update_users(&self, users: Vec<ChangedUser>) -> Result<()> {
-		let disabled: Vec<ZitadelUser> = users
+	/// Update a list of old/new user maps from the given source,
+	/// returning the external IDs of the users that failed to be updated
+	pub(crate) async fn update_users(
+		&self,
+		source_name: &str,
+		users: Vec<ChangedUser>,
+	) -> Result<Vec<String>> {
+		let zitadel_user = |user: &User| {
+			(
+				user.external_user_id.to_string(),
+				user.to_zitadel_user(&self.feature_flags, &self.zitadel_config),
+			)
+		};
+
+		let disabled: Vec<(String, ZitadelUser)> = users
 			.iter()
 			.filter(|user| user.old.enabled && !user.new.enabled)
-			.map(|user| {
-				user.new.to_zitadel_user(&self.feature_flags, &self.zitadel_config.idp_id).clone()
-			})
+			.map(|user| zitadel_user(&user.new))
 			.collect();
 
-		let enabled: Vec<ZitadelUser> = users
+		let enabled: Vec<(String, (ZitadelUser, ZitadelUser))> = users
 			.iter()
 			.filter(|user| !user.old.enabled && user.new.enabled)
 			.map(|user| {
-				user.new.to_zitadel_user(&self.feature_flags, &self.zitadel_config.idp_id).clone()
+				let (key, new) = zitadel_user(&user.new);
+				(key, (zitadel_user(&user.old).1, new))
 			})
 			.collect();
 
-		let changed: Vec<(ZitadelUser, ZitadelUser)> = users
-			.into_iter()
+		let changed: Vec<(String, (ZitadelUser, ZitadelUser))> = users
+			.iter()
 			.filter(|user| user.new.enabled && user.old.enabled == user.new.enabled)
 			.map(|user| {
-				(
-					user.old
-						.to_zitadel_user(&self.feature_flags, &self.zitadel_config.idp_id)
-						.clone(),
-					user.new
-						.to_zitadel_user(&self.feature_flags, &self.zitadel_config.idp_id)
-						.clone(),
-				)
+				let (key, new) = zitadel_user(&user.new);
+				(key, (zitadel_user(&user.old).1, new))
 			})
 			.collect();
 
-		for user in disabled {
-			let status = self.delete_user(&user).await;
+		let mut failed_users = self
+			.for_each_user("delete", disabled, |zitadel, user| async move {
+				zitadel.delete_user(&user).await
+			})
+			.await
+			.failed_users;
 
-			if let Err(error) = status {
-				tracing::error!("Failed to delete user `{}`: {:?}`", user.log_name(), error);
-			}
+		if !self.feature_flags.is_enabled(FeatureFlag::DeactivateOnly) {
+			let source_name = source_name.to_owned();
+
+			let summary = self
+				.for_each_user("re-enable", enabled, {
+					let source_name = source_name.clone();
+					move |zitadel, (old, new)| {
+						let source_name = source_name.clone();
+						async move { zitadel.reenable_user(&old, new, &source_name).await }
+					}
+				})
+				.await;
+			failed_users.extend(summary.failed_users);
+
+			let summary = self
+				.for_each_user("update", changed, move |zitadel, (old, new)| {
+					let source_name = source_name.clone();
+					async move { zitadel.update_changed_user(&old, new, &source_name).await }
+				})
+				.await;
+			failed_users.extend(summary.failed_users);
 		}
 
-		if !self.feature_flags.is_enabled(FeatureFlag::DeactivateOnly) {
-			for user in enabled {
-				let status = self.import_user(&user).await;
+		Ok(failed_users)
+	}
 
-				if let Err(error) = status {
-					tracing::error!("Failed to re-create user `{}`: {:?}", user.log_name(), error);
-				}
+	/// Whether Zitadel failed often enough in a row that no further
+	/// requests are sent during this run
+	pub(crate) fn is_unreachable(&self) -> bool {
+		self.circuit_breaker.is_open()
+	}
+
+	/// Whether the audit journal could not be written, so that no further
+	/// changes are made during this run
+	pub(crate) fn is_journal_broken(&self) -> bool {
+		self.journal.as_ref().is_some_and(AuditJournal::is_broken)
+	}
+
+	/// The head of the audit journal, if one is kept, to be recorded in
+	/// the state store
+	pub(crate) async fn journal_head(&self) -> Result<Option<JournalHead>> {
+		match &self.journal {
+			Some(journal) => Ok(Some(journal.head().await?)),
+			None => Ok(None),
+		}
+	}
+
+	/// Whether no further changes are made during this run, because
+	/// Zitadel is unreachable or the audit journal could not be written
+	pub(crate) fn is_aborted(&self) -> bool {
+		self.is_unreachable() || self.is_journal_broken()
+	}
+
+	/// Send a request through the Zitadel client, respecting the rate
+	/// limit and retrying if Zitadel is overloaded or unavailable
+	///
+	/// Once Zitadel is unreachable, requests fail without being sent.
+	async fn call<T, F, Fut>(&self, request: F) -> Result<T, ZitadelError>
+	where
+		F: Fn() -> Fut,
+		Fut: Future<Output = Result<T, ZitadelError>>,
+	{
+		self.call_retrying(request, Self::is_retryable_error).await
+	}
+
+	/// Send a request like [`Self::call`], retrying it only on the given
+	/// errors
+	async fn call_retrying<T, F, Fut>(
+		&self,
+		request: F,
+		is_retryable: fn(&ZitadelError) -> bool,
+	) -> Result<T, ZitadelError>
+	where
+		F: Fn() -> Fut,
+		Fut: Future<Output = Result<T, ZitadelError>>,
+	{
+		if self.is_unreachable() {
+			return Err(ZitadelError::TonicResponseError(tonic::Status::unavailable(
+				"Zitadel is unreachable, request not sent",
+			)));
+		}
+
+		let result = self.rate_limiter.call(request, is_retryable).await;
+
+		match &result {
+			Err(error) if Self::is_connection_error(error) => self.circuit_breaker.record_failure(),
+			_ => self.circuit_breaker.record_success(),
+		}
+
+		result
+	}
+
+	/// Send a request changing Zitadel through [`Self::call`], recording
+	/// the change in the audit journal
+	///
+	/// Once the journal cannot be written, requests fail without being
+	/// sent.
+	async fn write<T, F, Fut>(&self, record: AuditRecord, request: F) -> Result<T, ZitadelError>
+	where
+		F: Fn() -> Fut,
+		Fut: Future<Output = Result<T, ZitadelError>>,
+	{
+		self.audit_intent(&record).await?;
+		let result = self.call(request).await;
+		self.audit(record, result.as_ref().map(drop)).await;
+		result
+	}
+
+	/// Record a change about to be made to Zitadel in the audit journal,
+	/// if one is kept, so that it is on record even if the run ends
+	/// before its outcome is
+	///
+	/// Fails if the journal cannot be written, so the change is not made.
+	async fn audit_intent(&self, record: &AuditRecord) -> Result<(), ZitadelError> {
+		let Some(journal) = &self.journal else {
+			return Ok(());
+		};
+
+		let intent = AuditRecord { result: AuditResult::Pending, ..record.clone() };
+		if let Err(error) = journal.append(intent).await {
+			tracing::error!("Failed to record change, no further changes are made: {:?}", error);
+			return Err(ZitadelError::TonicResponseError(tonic::Status::unavailable(
+				"audit journal cannot be written, request not sent",
+			)));
+		}
+		Ok(())
+	}
+
+	/// Record the outcome of a change made to Zitadel in the audit
+	/// journal, if one is kept
+	async fn audit(&self, mut record: AuditRecord, result: Result<(), &ZitadelError>) {
+		let Some(journal) = &self.journal else {
+			return;
+		};
+
+		if let Err(error) = result {
+			record.result = AuditResult::Failure { error: error.to_string() };
+		}
+
+		if let Err(error) = journal.append(record).await {
+			tracing::error!("Failed to record change, no further changes are made: {:?}", error);
+		}
+	}
+
+	/// Run an operation on each of a list of users, keyed by their
+	/// external ID, writing to Zitadel for the configured number of users
+	/// at once
+	async fn for_each_user<T, F, Fut>(
+		&self,
+		action: &str,
+		users: Vec<(String, T)>,
+		operation: F,
+	) -> BatchSummary
+	where
+		T: Send + 'static,
+		F: Fn(Self, T) -> Fut + Clone + Send + 'static,
+		Fut: Future<Output = Result<()>> + Send + 'static,
+	{
+		let zitadel = self.clone();
+		let is_aborted = {
+			let zitadel = self.clone();
+			move || zitadel.is_aborted()
+		};
+
+		concurrency::for_each_concurrent(
+			self.zitadel_config.concurrency,
+			action,
+			users,
+			move |user| operation(zitadel.clone(), user),
+			is_aborted,
+		)
+		.await
+	}
+
+	/// Import a new user, retrying without their phone number if Zitadel
+	/// rejects it
+	async fn import_new_user(&self, user: ZitadelUser, source_name: &str) -> Result<()> {
+		match self.import_user(&user, source_name).await {
+			Err(error) if Self::is_invalid_phone_error(&error) => {
+				tracing::warn!(
+					"Phone number of user `{}` is invalid, retrying without it: {:?}",
+					user.log_name(),
+					error
+				);
+				self.import_user(&user.without_phone(), source_name).await
 			}
+			status => status,
+		}
+	}
 
-			for (old, new) in changed {
-				let status = self.update_user(&old, &new).await;
-
-				if let Err(error) = status {
-					tracing::error!("Failed to sync-update user `{}`: {:?}", new.log_name(), error);
-
-					if Self::is_invalid_phone_error(error) {
-						let new =
-							ZitadelUser { user_data: User { phone: None, ..new.user_data }, ..new };
-
-						let retry_status = self.update_user(&old, &new).await;
-
-						match retry_status {
-							Ok(_) => {
-								tracing::info!(
-									"Retry sync-update succeeded for user `{}`",
-									new.log_name()
-								);
-							}
-							Err(retry_error) => {
-								tracing::error!(
-									"Retry sync-update failed for user `{}`: {:?}",
-									new.log_name(),
-									retry_error
-								);
-							}
-						}
-					}
-				}
+	/// Update a changed user, retrying without their phone number if
+	/// Zitadel rejects it
+	async fn update_changed_user(
+		&self,
+		old: &ZitadelUser,
+		new: ZitadelUser,
+		source_name: &str,
+	) -> Result<()> {
+		match self.update_user(old, &new, source_name).await {
+			Err(error) if Self::is_invalid_phone_error(&error) => {
+				tracing::warn!(
+					"Phone number of user `{}` is invalid, retrying without it: {:?}",
+					new.log_name(),
+					error
+				);
+				self.update_user(old, &new.without_phone(), source_name).await
 			}
+			status => status,
 		}
+	}
 
-		Ok(())
+	/// Re-enable a user: an account deactivated in Zitadel is
+	/// reactivated and updated, while a user whose account was deleted
+	/// when they were disabled in the source is imported again
+	async fn reenable_user(
+		&self,
+		old: &ZitadelUser,
+		new: ZitadelUser,
+		source_name: &str,
+	) -> Result<()> {
+		let Some(user_ref) = self.get_user_ref(old).await? else {
+			return self.import_user(&new, source_name).await;
+		};
+
+		if !self.is_managed_user(&user_ref).await? {
+			bail!("refusing to reactivate user `{}` not managed by the sync", old.log_name());
+		}
+
+		let state = self
+			.call(|| self.zitadel_client.get_user_by_id(&user_ref.user_id))
+			.await?
+			.map(|user| user.state());
+		if state == Some(UserState::Inactive) {
+			if self.feature_flags.is_enabled(FeatureFlag::DryRun) {
+				tracing::info!("Not reactivating user due to dry run: {:?}", new);
+				return Ok(());
+			}
+
+			let external_id = new.user_data.external_user_id.to_string();
+			let record = AuditRecord::new(
+				source_name,
+				AuditAction::Reactivate,
+				Some(&external_id),
+				Some(&user_ref),
+			);
+			self.write(record, || self.zitadel_client.reactivate_user(user_ref.user_id.clone()))
+				.await?;
+
+			tracing::info!("Successfully reactivated user {}", new.log_name());
+		}
+
+		self.update_changed_user(old, new, source_name).await
 	}
 
 	/// Update a Zitadel user
-	async fn update_user(&self, old: &ZitadelUser, new: &ZitadelUser) -> Result<()> {
+	async fn update_user(
+		&self,
+		old: &ZitadelUser,
+		new: &ZitadelUser,
+		source_name: &str,
+	) -> Result<()> {
 		if self.feature_flags.is_enabled(FeatureFlag::DryRun) {
 			tracing::info!("Not updating user due to dry run: {:?} -> {:?}", old, new);
 			return Ok(());
 		}
 
-		let Some(user_id) = self.get_user_id(old).await? else {
+		let Some(user_ref) = self.get_user_ref(old).await? else {
 			bail!("could not find user `{}` to update", old.user_data.email);
 		};
 
-		if old.user_data.email != new.user_data.email {
-			self.zitadel_client
-				.update_human_user_name(
-					&self.zitadel_config.organization_id,
-					user_id.clone(),
-					new.user_data.email.clone().to_string(),
-				)
-				.await?;
+		if !self.is_managed_user(&user_ref).await? {
+			bail!("refusing to update user `{}` not managed by the sync", old.user_data.email);
+		}
 
-			tracing::warn!(
-				"User email/login changed for {} -> {}",
-				old.user_data.email,
-				new.user_data.email
-			);
+		let organization_id = self.zitadel_config.organization_for(&new.user_data);
+		if user_ref.organization_id != organization_id {
+			return self.move_user(&user_ref, new, source_name).await;
+		}
+
+		let external_id = new.user_data.external_user_id.to_string();
+		let record =
+			|action| AuditRecord::new(source_name, action, Some(&external_id), Some(&user_ref));
+
+		if old.login_name != new.login_name {
+			self.rename_user(
+				&user_ref,
+				&old.login_name,
+				&new.login_name,
+				source_name,
+				&external_id,
+			)
+			.await?;
+
+			tracing::warn!("User login changed for {} -> {}", old.login_name, new.login_name);
 		};
 
 		if old.user_data.first_name != new.user_data.first_name
 			|| old.user_data.last_name != new.user_data.last_name
+			|| old.get_display_name() != new.get_display_name()
 		{
-			self.zitadel_client
-				.update_human_user_profile(
-					&self.zitadel_config.organization_id,
-					user_id.clone(),
+			let record = record(AuditAction::UpdateProfile)
+				.change(
+					"first_name",
+					old.user_data.first_name.to_string(),
+					new.user_data.first_name.to_string(),
+				)
+				.change(
+					"last_name",
+					old.user_data.last_name.to_string(),
+					new.user_data.last_name.to_string(),
+				)
+				.change("display_name", old.get_display_name(), new.get_display_name());
+			self.write(record, || {
+				self.zitadel_client.update_human_user_profile(
+					&user_ref.organization_id,
+					user_ref.user_id.clone(),
 					new.user_data.first_name.clone().to_string(),
 					new.user_data.last_name.clone().to_string(),
 					None,
@@ -246,75 +556,235 @@ impl Zitadel {
 					None,
 					None,
 				)
-				.await?;
+			})
+			.await?;
 		};
 
+		self.update_user_contact(&user_ref, old, new, source_name).await?;
+
+		if old.user_data.preferred_username != new.user_data.preferred_username {
+			let preferred_username = new.user_data.preferred_username.to_string();
+			let record = record(AuditAction::SetMetadata).change(
+				"preferred_username",
+				old.user_data.preferred_username.to_string(),
+				preferred_username.clone(),
+			);
+			self.write(record, || {
+				self.zitadel_client.set_user_metadata(
+					Some(&user_ref.organization_id),
+					user_ref.user_id.clone(),
+					"preferred_username".to_owned(),
+					&preferred_username,
+				)
+			})
+			.await?;
+		};
+
+		self.sync_user_metadata(
+			&user_ref,
+			&old.user_data.metadata,
+			&new.user_data.metadata,
+			source_name,
+			&external_id,
+		)
+		.await?;
+
+		self.sync_user_grants(&user_ref, &new.user_data, source_name).await?;
+
+		tracing::info!("Successfully updated user {}", old.user_data.email);
+
+		Ok(())
+	}
+
+	/// Update the phone number and email address of a Zitadel user
+	async fn update_user_contact(
+		&self,
+		user_ref: &UserRef,
+		old: &ZitadelUser,
+		new: &ZitadelUser,
+		source_name: &str,
+	) -> Result<()> {
+		let external_id = new.user_data.external_user_id.to_string();
+		let record =
+			|action| AuditRecord::new(source_name, action, Some(&external_id), Some(user_ref));
+
+		let old_phone = old.user_data.phone.as_ref().map(ToString::to_string);
 		match (&old.user_data.phone, &new.user_data.phone) {
 			(Some(_), None) => {
-				self.zitadel_client
-					.remove_human_user_phone(&self.zitadel_config.organization_id, user_id.clone())
-					.await?;
+				let record =
+					record(AuditAction::UpdatePhone).change("phone", old_phone, Value::Null);
+				self.write(record, || {
+					self.zitadel_client.remove_human_user_phone(
+						&user_ref.organization_id,
+						user_ref.user_id.clone(),
+					)
+				})
+				.await?;
 			}
 			(_, Some(new_phone)) => {
-				self.zitadel_client
-					.update_human_user_phone(
-						&self.zitadel_config.organization_id,
-						user_id.clone(),
+				let record = record(AuditAction::UpdatePhone).change(
+					"phone",
+					old_phone,
+					new_phone.to_string(),
+				);
+				self.write(record, || {
+					self.zitadel_client.update_human_user_phone(
+						&user_ref.organization_id,
+						user_ref.user_id.clone(),
 						new_phone.clone().to_string(),
 						!self.feature_flags.is_enabled(FeatureFlag::VerifyPhone),
 					)
-					.await?;
+				})
+				.await?;
 			}
 			(None, None) => {}
 		};
 
 		if old.user_data.email != new.user_data.email {
-			self.zitadel_client
-				.update_human_user_email(
-					&self.zitadel_config.organization_id,
-					user_id.clone(),
+			let record = record(AuditAction::UpdateEmail).change(
+				"email",
+				old.user_data.email.to_string(),
+				new.user_data.email.to_string(),
+			);
+			self.write(record, || {
+				self.zitadel_client.update_human_user_email(
+					&user_ref.organization_id,
+					user_ref.user_id.clone(),
 					new.user_data.email.clone().to_string(),
 					!self.feature_flags.is_enabled(FeatureFlag::VerifyEmail),
 				)
-				.await?;
+			})
+			.await?;
 		};
 
-		if old.user_data.preferred_username != new.user_data.preferred_username {
-			self.zitadel_client
-				.set_user_metadata(
-					Some(&self.zitadel_config.organization_id),
-					user_id,
-					"preferred_username".to_owned(),
-					&new.user_data.preferred_username.clone().to_string(),
-				)
-				.await?;
+		Ok(())
+	}
+
+	/// Move a user to the organization they are now routed to
+	///
+	/// Zitadel cannot move users between organizations, so the user is
+	/// re-created in the new one. Login names are unique across
+	/// organizations, so the old account is renamed to a temporary login
+	/// name first. It is only deleted once the new one exists, so a failed
+	/// import restores its login name and leaves the user in place to be
+	/// moved next run.
+	async fn move_user(
+		&self,
+		user_ref: &UserRef,
+		new: &ZitadelUser,
+		source_name: &str,
+	) -> Result<()> {
+		let organization_id = self.zitadel_config.organization_for(&new.user_data);
+		tracing::info!(
+			"Moving user {} from organization {} to {}",
+			new.user_data.email,
+			user_ref.organization_id,
+			organization_id
+		);
+
+		// The user keeps their localpart, which is not derived again
+		let mut new = new.clone();
+		if let Some(localpart) = self.get_user_metadata(user_ref, "localpart").await? {
+			new.user_data.localpart = Some(localpart);
+		}
+
+		let external_id = new.user_data.external_user_id.to_string();
+		let Some(old_user) =
+			self.call(|| self.zitadel_client.get_user_by_id(&user_ref.user_id)).await?
+		else {
+			bail!("could not find user `{}` to move", new.log_name());
+		};
+		let temporary_login_name = format!("moving-{}", user_ref.user_id);
+		self.rename_user(
+			user_ref,
+			&old_user.user_name,
+			&temporary_login_name,
+			source_name,
+			&external_id,
+		)
+		.await?;
+
+		let result = match self.import_user(&new, source_name).await {
+			// The import may have ended in a conflict that left the user
+			// where they were
+			Ok(()) => match self.user_ids.get(&external_id) {
+				Some(moved) if moved.organization_id == organization_id && moved != *user_ref => {
+					Ok(())
+				}
+				_ => Err(anyhow!(
+					"could not create user `{}` in organization {}, keeping the old account",
+					new.log_name(),
+					organization_id
+				)),
+			},
+			Err(error) => Err(error),
 		};
 
-		tracing::info!("Successfully updated user {}", old.user_data.email);
+		if let Err(error) = result {
+			if let Err(restore_error) = self
+				.rename_user(
+					user_ref,
+					&temporary_login_name,
+					&old_user.user_name,
+					source_name,
+					&external_id,
+				)
+				.await
+			{
+				tracing::error!(
+					"Failed to restore the login name `{}` of user `{}`: {:?}",
+					old_user.user_name,
+					user_ref.user_id,
+					restore_error
+				);
+			}
+			return Err(error);
+		}
+
+		self.remove_managed_user(user_ref, Some(&external_id)).await
+	}
+
+	/// Change the login name of a Zitadel user
+	async fn rename_user(
+		&self,
+		user_ref: &UserRef,
+		old_login_name: &str,
+		new_login_name: &str,
+		source_name: &str,
+		external_id: &str,
+	) -> Result<()> {
+		let record = AuditRecord::new(
+			source_name,
+			AuditAction::UpdateUsername,
+			Some(external_id),
+			Some(user_ref),
+		)
+		.change("login_name", old_login_name.to_owned(), new_login_name.to_owned());
+		self.write(record, || {
+			self.zitadel_client.update_human_user_name(
+				&user_ref.organization_id,
+				user_ref.user_id.clone(),
+				new_login_name.to_owned(),
+			)
+		})
+		.await?;
 
 		Ok(())
 	}
 
-	/// Delete a Zitadel user given only their LDAP id
-	async fn delete_user_by_id(&self, user_id: &str) -> Result<()> {
+	/// Delete a Zitadel user given their Zitadel ID
+	async fn delete_user_by_zitadel_id(&self, zitadel_id: &str) -> Result<()> {
 		if self.feature_flags.is_enabled(FeatureFlag::DryRun) {
-			tracing::info!("Not deleting user `{}` due to dry run", user_id);
+			tracing::info!("Not deleting user `{}` due to dry run", zitadel_id);
 			return Ok(());
 		}
 
-		let user = self
-			.zitadel_client
-			.get_user_by_nick_name(
-				Some(self.zitadel_config.organization_id.clone()),
-				user_id.to_owned(),
-			)
-			.await?;
-		match user {
-			Some(user) => self.zitadel_client.remove_user(user.id).await?,
-			None => bail!("Could not find user with ldap uid '{user_id}' for deletion"),
+		match self.get_user_ref_by_id(zitadel_id).await? {
+			Some(user_ref) => self.remove_managed_user(&user_ref, None).await?,
+			None => tracing::info!("Could not find user with id '{zitadel_id}' for deletion"),
 		}
 
-		tracing::info!("Successfully deleted user {}", user_id);
+		tracing::info!("Successfully deleted user {}", zitadel_id);
 
 		Ok(())
 	}
@@ -326,9 +796,13 @@ impl Zitadel {
 			return Ok(());
 		}
 
-		let user = self.zitadel_client.get_user_by_login_name(email).await?;
+		let user = self.call(|| self.zitadel_client.get_user_by_login_name(email)).await?;
 		match user {
-			Some(user) => self.zitadel_client.remove_user(user.id).await?,
+			Some(user) => {
+				let user_ref =
+					self.user_ref(user.id, user.details.map(|details| details.resource_owner));
+				self.remove_managed_user(&user_ref, None).await?;
+			}
 			None => tracing::info!("Could not find user with email '{email}' for deletion"),
 		}
 
@@ -344,15 +818,8 @@ impl Zitadel {
 			return Ok(());
 		}
 
-		let user = self
-			.zitadel_client
-			.get_user_by_nick_name(
-				Some(self.zitadel_config.organization_id.clone()),
-				nick.to_owned(),
-			)
-			.await?;
-		match user {
-			Some(user) => self.zitadel_client.remove_user(user.id).await?,
+		match self.get_user_ref_by_external_id(nick).await? {
+			Some(user_ref) => self.remove_managed_user(&user_ref, Some(nick)).await?,
 			None => tracing::info!("Could not find user with nick '{nick}' for deletion"),
 		}
 
@@ -361,21 +828,579 @@ impl Zitadel {
 		Ok(())
 	}
 
-	/// Retrieve the Zitadel user ID of a user, or None if the user
-	/// cannot be found
-	async fn get_user_id(&self, user: &ZitadelUser) -> Result<Option<String>> {
-		let status = self
-			.zitadel_client
-			.get_user_by_login_name(&user.user_data.email.clone().to_string())
+	/// List the human users of the configured organizations, with the
+	/// fields a listing includes
+	///
+	/// The external ID of a user is their nick name, and their login
+	/// name is set to the one in Zitadel.
+	async fn list_human_users(&self) -> Result<Vec<(UserRef, User)>> {
+		let mut users = Vec::new();
+
+		for organization_id in self.zitadel_config.organization_ids() {
+			let listed_users = self
+				.call(|| self.zitadel_client.list_users(Some(organization_id.clone())))
+				.await
+				.context("failed to list Zitadel users")?;
+
+			for user in listed_users {
+				let Some(UserType::Human(human)) = user.r#type.clone() else {
+					continue;
+				};
+				let profile = human.profile.unwrap_or_default();
+
+				users.push((
+					UserRef { user_id: user.id.clone(), organization_id: organization_id.clone() },
+					User {
+						first_name: profile.first_name.into(),
+						last_name: profile.last_name.into(),
+						email: human.email.map(|email| email.email).unwrap_or_default().into(),
+						phone: human
+							.phone
+							.map(|phone| phone.phone)
+							.filter(|phone| !phone.is_empty())
+							.map(Into::into),
+						enabled: matches!(user.state(), UserState::Active | UserState::Initial),
+						preferred_username: String::new().into(),
+						external_user_id: profile.nick_name.into(),
+						attributes: HashMap::new(),
+						metadata: BTreeMap::new(),
+						extra_grants: Grants::new(),
+						display_name: Some(profile.display_name),
+						localpart: None,
+						login_name: Some(user.user_name.clone()),
+					},
+				));
+			}
+		}
+
+		Ok(users)
+	}
+
+	/// Fetch the users of the configured organizations, including those
+	/// of other sources and users not managed by the sync, as far as a
+	/// listing includes them
+	pub(crate) async fn get_existing_users(&self) -> Result<Vec<User>> {
+		Ok(self.list_human_users().await?.into_iter().map(|(_, user)| user).collect())
+	}
+
+	/// Fetch all users of the organization that are managed by the given
+	/// source, along with their current state in Zitadel, including the
+	/// given additional metadata keys
+	///
+	/// Fetching the metadata and grants of a user takes several requests,
+	/// so users are fetched with the configured concurrency.
+	pub(crate) async fn get_managed_users(
+		&self,
+		source_name: &str,
+		metadata_keys: &BTreeSet<String>,
+	) -> Result<Vec<ManagedUser>> {
+		let users: Vec<_> = self
+			.list_human_users()
+			.await?
+			.into_iter()
+			.map(|(user_ref, user)| {
+				(
+					user_ref.user_id.clone(),
+					ManagedUser {
+						user,
+						zitadel_id: user_ref.user_id,
+						organization_id: user_ref.organization_id,
+						grants: Grants::new(),
+					},
+				)
+			})
+			.collect();
+
+		let managed_users = Arc::new(Mutex::new(Vec::new()));
+		let summary = self
+			.for_each_user("fetch", users, {
+				let managed_users = managed_users.clone();
+				let source_name = source_name.to_owned();
+				let metadata_keys = Arc::new(metadata_keys.clone());
+				move |zitadel, user| {
+					let managed_users = managed_users.clone();
+					let source_name = source_name.clone();
+					let metadata_keys = metadata_keys.clone();
+					async move {
+						if let Some(user) = zitadel
+							.complete_managed_user(user, &source_name, &metadata_keys)
+							.await?
+						{
+							managed_users.lock().unwrap_or_else(PoisonError::into_inner).push(user);
+						}
+						Ok(())
+					}
+				}
+			})
 			.await;
 
-		if let Err(ZitadelError::TonicResponseError(ref error)) = status {
-			if error.code() == TonicErrorCode::NotFound {
-				return Ok(None);
+		// Users missing from the list would be re-imported, so the list
+		// must be complete
+		if summary.failed > 0 || summary.skipped > 0 {
+			bail!("failed to fetch {} Zitadel users", summary.failed + summary.skipped);
+		}
+
+		let managed_users =
+			std::mem::take(&mut *managed_users.lock().unwrap_or_else(PoisonError::into_inner));
+		Ok(managed_users)
+	}
+
+	/// Fill in the metadata and grants of a listed Zitadel user, or
+	/// return None if the user is not managed by the given source
+	async fn complete_managed_user(
+		&self,
+		mut managed: ManagedUser,
+		source_name: &str,
+		metadata_keys: &BTreeSet<String>,
+	) -> Result<Option<ManagedUser>> {
+		let user_ref = UserRef {
+			user_id: managed.zitadel_id.clone(),
+			organization_id: managed.organization_id.clone(),
+		};
+
+		if self.get_managed_by(&user_ref).await?.as_deref() != Some(source_name) {
+			return Ok(None);
+		}
+
+		managed.user.preferred_username = self
+			.get_user_metadata(&user_ref, "preferred_username")
+			.await?
+			.unwrap_or_default()
+			.into();
+
+		for key in metadata_keys {
+			if let Some(value) = self.get_user_metadata(&user_ref, key).await? {
+				managed.user.metadata.insert(key.clone(), value.into());
 			}
 		}
 
-		Ok(status.map(|user| user.map(|u| u.id))?)
+		managed.grants = self.get_user_grants(&user_ref).await?;
+
+		self.user_ids.insert(managed.user.external_user_id.to_string(), user_ref);
+
+		Ok(Some(managed))
+	}
+
+	/// Fetch the Matrix localparts already taken in the configured
+	/// organizations, mapped to the external IDs of the users holding
+	/// them, or their Zitadel IDs for users without one
+	///
+	/// Users of any source, and users not managed by the sync, are
+	/// included, as localparts must be unique on the homeserver.
+	///
+	/// Fetching the localpart of a user takes a request, so Zitadel is
+	/// only scanned if the localparts are not known yet, e.g. from the
+	/// state store. Localparts the sync writes are added to the known
+	/// ones, while those of deleted users stay taken.
+	pub(crate) async fn get_localparts(&self) -> Result<HashMap<String, String>> {
+		if let Some(localparts) = &*self.localparts.lock().unwrap_or_else(PoisonError::into_inner) {
+			return Ok(localparts.clone());
+		}
+
+		tracing::info!("Fetching the localparts taken in Zitadel");
+		let users: Vec<_> = self
+			.list_human_users()
+			.await?
+			.into_iter()
+			.map(|(user_ref, user)| {
+				let owner = match user.external_user_id.to_string() {
+					nick_name if nick_name.is_empty() => user_ref.user_id.clone(),
+					nick_name => nick_name,
+				};
+				(owner.clone(), (user_ref, owner))
+			})
+			.collect();
+
+		let localparts = Arc::new(Mutex::new(HashMap::new()));
+		let summary = self
+			.for_each_user("fetch the localpart of", users, {
+				let localparts = localparts.clone();
+				move |zitadel, (user_ref, owner)| {
+					let localparts = localparts.clone();
+					async move {
+						if let Some(localpart) =
+							zitadel.get_user_metadata(&user_ref, "localpart").await?
+						{
+							localparts
+								.lock()
+								.unwrap_or_else(PoisonError::into_inner)
+								.insert(localpart, owner);
+						}
+						Ok(())
+					}
+				}
+			})
+			.await;
+
+		// A missing localpart could be handed out a second time
+		if summary.failed > 0 || summary.skipped > 0 {
+			bail!(
+				"failed to fetch the localparts of {} Zitadel users",
+				summary.failed + summary.skipped
+			);
+		}
+
+		let localparts =
+			std::mem::take(&mut *localparts.lock().unwrap_or_else(PoisonError::into_inner));
+		*self.localparts.lock().unwrap_or_else(PoisonError::into_inner) = Some(localparts.clone());
+		Ok(localparts)
+	}
+
+	/// The localparts taken in Zitadel, mapped to their holders, if they
+	/// are known
+	pub(crate) fn localparts(&self) -> Option<HashMap<String, String>> {
+		self.localparts.lock().unwrap_or_else(PoisonError::into_inner).clone()
+	}
+
+	/// Compute the changes needed to bring Zitadel in line with the
+	/// full list of users of a source, ignoring any cached state
+	///
+	/// Besides the given metadata keys the source is configured with,
+	/// keys set on any of the users, e.g. by a mapping script, are
+	/// compared.
+	pub(crate) async fn get_reconcile_diff(
+		&self,
+		source_name: &str,
+		mut metadata_keys: BTreeSet<String>,
+		users: Vec<User>,
+	) -> Result<SourceDiff> {
+		metadata_keys.extend(users.iter().flat_map(|user| user.metadata.keys().cloned()));
+		let managed_users = self.get_managed_users(source_name, &metadata_keys).await?;
+		Ok(reconcile::compute_diff(users, managed_users, &self.zitadel_config))
+	}
+
+	/// Mark an existing Zitadel user, identified by their login name, as
+	/// managed by the given source
+	pub(crate) async fn adopt_user(&self, source_name: &str, login_name: &str) -> Result<()> {
+		if self.feature_flags.is_enabled(FeatureFlag::DryRun) {
+			tracing::info!("Not adopting user `{}` due to dry run", login_name);
+			return Ok(());
+		}
+
+		let Some(user) =
+			self.call(|| self.zitadel_client.get_user_by_login_name(login_name)).await?
+		else {
+			bail!("could not find user `{}` to adopt", login_name);
+		};
+
+		let user_ref = self.user_ref(user.id, user.details.map(|details| details.resource_owner));
+
+		if let Some(managed_by) = self.get_managed_by(&user_ref).await? {
+			bail!("user `{}` is already managed by source `{}`", login_name, managed_by);
+		}
+
+		self.set_managed_by(&user_ref, None, source_name).await?;
+
+		tracing::info!("Successfully adopted user {} for source {}", login_name, source_name);
+
+		Ok(())
+	}
+
+	/// Mark the existing Zitadel users with the given external IDs as
+	/// managed by the given source, skipping users that cannot be found
+	/// or are managed already, and return how many users were adopted
+	///
+	/// This takes over users imported before the sync marked the users
+	/// it manages, whose nick names are their external IDs.
+	pub(crate) async fn adopt_users_by_external_id(
+		&self,
+		source_name: &str,
+		external_ids: Vec<String>,
+	) -> Result<usize> {
+		if self.feature_flags.is_enabled(FeatureFlag::DryRun) {
+			tracing::info!("Not adopting users due to dry run");
+			return Ok(0);
+		}
+
+		let adopted = Arc::new(AtomicUsize::new(0));
+		let users = external_ids.into_iter().map(|external_id| (external_id.clone(), external_id));
+		let summary = self
+			.for_each_user("adopt", users.collect(), {
+				let adopted = adopted.clone();
+				let source_name = source_name.to_owned();
+				move |zitadel, external_id| {
+					let adopted = adopted.clone();
+					let source_name = source_name.clone();
+					async move {
+						let Some(user_ref) =
+							zitadel.get_user_ref_by_external_id(&external_id).await?
+						else {
+							return Ok(());
+						};
+						if zitadel.get_managed_by(&user_ref).await?.is_some() {
+							return Ok(());
+						}
+						zitadel.set_managed_by(&user_ref, Some(&external_id), &source_name).await?;
+						adopted.fetch_add(1, Ordering::Relaxed);
+						Ok(())
+					}
+				}
+			})
+			.await;
+
+		if summary.failed > 0 || summary.skipped > 0 {
+			bail!("failed to adopt {} users", summary.failed + summary.skipped);
+		}
+
+		Ok(adopted.load(Ordering::Relaxed))
+	}
+
+	/// Mark a user as managed by the given source
+	async fn set_managed_by(
+		&self,
+		user_ref: &UserRef,
+		external_id: Option<&str>,
+		source_name: &str,
+	) -> Result<()> {
+		let record =
+			AuditRecord::new(source_name, AuditAction::SetMetadata, external_id, Some(user_ref))
+				.after(MANAGED_BY_METADATA_KEY, source_name);
+		self.write(record, || {
+			self.zitadel_client.set_user_metadata(
+				Some(&user_ref.organization_id),
+				user_ref.user_id.clone(),
+				MANAGED_BY_METADATA_KEY.to_owned(),
+				source_name,
+			)
+		})
+		.await?;
+
+		Ok(())
+	}
+
+	/// Get the name of the source managing a user, or None if the user
+	/// is not managed by the sync
+	async fn get_managed_by(&self, user_ref: &UserRef) -> Result<Option<String>> {
+		self.get_user_metadata(user_ref, MANAGED_BY_METADATA_KEY).await
+	}
+
+	/// Get a metadata value of a user, or None if it is not set
+	async fn get_user_metadata(&self, user_ref: &UserRef, key: &str) -> Result<Option<String>> {
+		Ok(self
+			.call(|| {
+				self.zitadel_client.get_user_metadata(
+					Some(user_ref.organization_id.clone()),
+					&user_ref.user_id,
+					key,
+				)
+			})
+			.await?)
+	}
+
+	/// Check whether a user is managed by the sync, i.e. has been
+	/// imported or explicitly adopted by any source
+	async fn is_managed_user(&self, user_ref: &UserRef) -> Result<bool> {
+		Ok(self.get_managed_by(user_ref).await?.is_some())
+	}
+
+	/// Remove a Zitadel user, refusing to touch users the sync does not
+	/// manage
+	async fn remove_managed_user(
+		&self,
+		user_ref: &UserRef,
+		external_id: Option<&str>,
+	) -> Result<()> {
+		let Some(source_name) = self.get_managed_by(user_ref).await? else {
+			bail!("refusing to delete user `{}` not managed by the sync", user_ref.user_id);
+		};
+
+		let record =
+			AuditRecord::new(&source_name, AuditAction::Delete, external_id, Some(user_ref))
+				.before("organization_id", user_ref.organization_id.clone());
+		self.write(record, || self.zitadel_client.remove_user(user_ref.user_id.clone())).await?;
+		self.user_ids.remove_zitadel_id(&user_ref.user_id);
+
+		Ok(())
+	}
+
+	/// Get the roles currently granted to a user, keyed by project ID
+	async fn get_user_grants(&self, user_ref: &UserRef) -> Result<Grants> {
+		let grants = self
+			.call(|| {
+				self.zitadel_client.list_user_grants(&user_ref.organization_id, &user_ref.user_id)
+			})
+			.await?;
+
+		Ok(grants
+			.result
+			.into_iter()
+			.map(|grant| (grant.project_id, grant.role_keys.into_iter().collect()))
+			.collect())
+	}
+
+	/// Bring the grants of a user in the configured projects in line
+	/// with the grant configuration, adding, updating and removing
+	/// grants as necessary
+	///
+	/// Grants in projects that are not configured are left alone.
+	async fn sync_user_grants(
+		&self,
+		user_ref: &UserRef,
+		user: &User,
+		source_name: &str,
+	) -> Result<()> {
+		let external_id = user.external_user_id.to_string();
+		let record =
+			|action| AuditRecord::new(source_name, action, Some(&external_id), Some(user_ref));
+
+		let existing_grants = self
+			.call(|| {
+				self.zitadel_client.list_user_grants(&user_ref.organization_id, &user_ref.user_id)
+			})
+			.await?
+			.result;
+
+		for (project_id, roles) in
+			grants::desired_grants(&self.zitadel_config.grant_configs(), user)
+		{
+			let existing_grant =
+				existing_grants.iter().find(|grant| grant.project_id == project_id);
+			let role_keys: Vec<String> = roles.iter().cloned().collect();
+
+			match existing_grant {
+				None if role_keys.is_empty() => {}
+				None => {
+					let record =
+						record(AuditAction::AddGrant).after(&project_id, role_keys.clone());
+					self.write(record, || {
+						self.zitadel_client.add_user_grant(
+							Some(user_ref.organization_id.clone()),
+							user_ref.user_id.clone(),
+							project_id.clone(),
+							None,
+							role_keys.clone(),
+						)
+					})
+					.await?;
+				}
+				Some(grant) if role_keys.is_empty() => {
+					let record = record(AuditAction::RemoveGrant)
+						.before(&project_id, grant.role_keys.clone());
+					self.write(record, || {
+						self.zitadel_client.remove_user_grant(
+							Some(user_ref.organization_id.clone()),
+							user_ref.user_id.clone(),
+							grant.id.clone(),
+						)
+					})
+					.await?;
+				}
+				Some(grant)
+					if grant.role_keys.iter().cloned().collect::<BTreeSet<_>>() != roles =>
+				{
+					let record = record(AuditAction::UpdateGrant).change(
+						&project_id,
+						grant.role_keys.clone(),
+						role_keys.clone(),
+					);
+					self.write(record, || {
+						self.zitadel_client.update_user_grant(
+							Some(user_ref.organization_id.clone()),
+							user_ref.user_id.clone(),
+							grant.id.clone(),
+							role_keys.clone(),
+						)
+					})
+					.await?;
+				}
+				Some(_) => {}
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Retrieve the Zitadel ID and organization of a user, or None if
+	/// the user cannot be found
+	async fn get_user_ref(&self, user: &ZitadelUser) -> Result<Option<UserRef>> {
+		self.get_user_ref_by_external_id(&user.user_data.external_user_id.to_string()).await
+	}
+
+	/// Retrieve the Zitadel ID and organization of a user given their
+	/// stable external ID, or None if the user cannot be found
+	///
+	/// The locally cached ID is preferred, falling back to looking the
+	/// user up by nick name, which is where the external ID is stored,
+	/// in each of the organizations users may be routed to.
+	async fn get_user_ref_by_external_id(&self, external_id: &str) -> Result<Option<UserRef>> {
+		if let Some(cached) = self.user_ids.get(external_id) {
+			if let Some(user_ref) = self.get_user_ref_by_id(&cached.user_id).await? {
+				if user_ref != cached {
+					self.user_ids.insert(external_id.to_owned(), user_ref.clone());
+				}
+				return Ok(Some(user_ref));
+			}
+
+			tracing::debug!("Cached Zitadel ID of user `{}` is stale", external_id);
+			self.user_ids.remove(external_id);
+		}
+
+		for organization_id in self.zitadel_config.organization_ids() {
+			let status = self
+				.call(|| {
+					self.zitadel_client.get_user_by_nick_name(
+						Some(organization_id.clone()),
+						external_id.to_owned(),
+					)
+				})
+				.await;
+
+			if let Err(ZitadelError::TonicResponseError(ref error)) = status {
+				if error.code() == TonicErrorCode::NotFound {
+					continue;
+				}
+			}
+
+			if let Some(user) = status? {
+				let user_ref = UserRef { user_id: user.id, organization_id };
+				self.user_ids.insert(external_id.to_owned(), user_ref.clone());
+				return Ok(Some(user_ref));
+			}
+		}
+
+		Ok(None)
+	}
+
+	/// Retrieve the organization of a user given their Zitadel ID, or
+	/// None if the user cannot be found
+	async fn get_user_ref_by_id(&self, user_id: &str) -> Result<Option<UserRef>> {
+		match self.call(|| self.zitadel_client.get_user_by_id(user_id)).await {
+			Ok(user) => Ok(user.map(|user| {
+				self.user_ref(user.id, user.details.map(|details| details.resource_owner))
+			})),
+			Err(ZitadelError::TonicResponseError(status))
+				if status.code() == TonicErrorCode::NotFound =>
+			{
+				Ok(None)
+			}
+			Err(error) => Err(error.into()),
+		}
+	}
+
+	/// Construct a user reference from a user's ID and the resource
+	/// owner reported by Zitadel, which is the user's organization
+	fn user_ref(&self, user_id: String, resource_owner: Option<String>) -> UserRef {
+		UserRef {
+			user_id,
+			organization_id: resource_owner
+				.filter(|owner| !owner.is_empty())
+				.unwrap_or_else(|| self.zitadel_config.organization_id.clone()),
+		}
+	}
+
+	/// The cached mapping of external IDs to Zitadel IDs
+	pub(crate) fn user_refs(&self) -> HashMap<String, UserRef> {
+		self.user_ids.to_map()
+	}
+
+	/// Persist the cached mapping of external IDs to Zitadel IDs
+	pub(crate) async fn persist_user_ids(&self) -> Result<()> {
+		if self.feature_flags.is_enabled(FeatureFlag::DryRun) {
+			tracing::warn!("Not writing user ID cache during a dry run");
+			return Ok(());
+		}
+
+		self.user_ids.persist().await
 	}
 
 	/// Delete a Zitadel user
@@ -385,8 +1410,9 @@ impl Zitadel {
 			return Ok(());
 		}
 
-		if let Some(user_id) = self.get_user_id(user).await? {
-			self.zitadel_client.remove_user(user_id).await?;
+		if let Some(user_ref) = self.get_user_ref(user).await? {
+			self.remove_managed_user(&user_ref, Some(&user.user_data.external_user_id.to_string()))
+				.await?;
 		} else {
 			bail!("could not find user `{}` for deletion", user.user_data.email);
 		}
@@ -396,8 +1422,9 @@ impl Zitadel {
 		Ok(())
 	}
 
-	/// Import a user into Zitadel
-	async fn import_user(&self, user: &ZitadelUser) -> Result<()> {
+	/// Import a user into Zitadel, marking it as managed by the given
+	/// source
+	async fn import_user(&self, user: &ZitadelUser, source_name: &str) -> Result<()> {
 		if self.feature_flags.is_enabled(FeatureFlag::DryRun) {
 			tracing::info!("Not importing user due to dry run: {:?}", user);
 			return Ok(());
@@ -408,51 +1435,412 @@ impl Zitadel {
 			return Ok(());
 		}
 
-		let new_user_id = self
-			.zitadel_client
-			.create_human_user(&self.zitadel_config.organization_id, user.clone().into())
-			.await?;
+		let organization_id = self.zitadel_config.organization_for(&user.user_data);
+
+		let external_id = user.user_data.external_user_id.to_string();
+		let mut record =
+			AuditRecord::new(source_name, AuditAction::Create, Some(&external_id), None)
+				.after("login_name", user.login_name.clone())
+				.after("first_name", user.user_data.first_name.to_string())
+				.after("last_name", user.user_data.last_name.to_string())
+				.after("display_name", user.get_display_name())
+				.after("email", user.user_data.email.to_string())
+				.after("phone", user.user_data.phone.as_ref().map(ToString::to_string))
+				.after("organization_id", organization_id);
+
+		// Creating a user is not idempotent, so the request is only
+		// retried if Zitadel rejected it without processing it
+		self.audit_intent(&record).await?;
+		let result = self
+			.call_retrying(
+				|| self.zitadel_client.create_human_user(organization_id, user.clone().into()),
+				Self::is_rate_limited_error,
+			)
+			.await;
+		if let Ok(new_user_id) = &result {
+			record.zitadel_id = Some(new_user_id.clone());
+		}
+		self.audit(record, result.as_ref().map(drop)).await;
+
+		let new_user_id = match result {
+			Ok(new_user_id) => new_user_id,
+			Err(error) if Self::is_already_exists_error(&error) => {
+				match self.get_interrupted_import(user, organization_id).await? {
+					Some(user_id) => {
+						tracing::info!("Finishing the interrupted import of {}", user.log_name());
+						user_id
+					}
+					None => return self.resolve_import_conflict(user, source_name).await,
+				}
+			}
+			Err(error) => return Err(error.into()),
+		};
 
-		self.zitadel_client
-			.set_user_metadata(
-				Some(&self.zitadel_config.organization_id),
-				new_user_id.clone(),
-				"preferred_username".to_owned(),
-				&user.user_data.preferred_username.clone().to_string(),
+		let user_ref =
+			UserRef { user_id: new_user_id, organization_id: organization_id.to_owned() };
+
+		// Without the marker, the sync could never update or delete the
+		// user, so the user is removed again to be imported next run
+		if let Err(error) = self.set_managed_by(&user_ref, Some(&external_id), source_name).await {
+			let record = AuditRecord::new(
+				source_name,
+				AuditAction::Delete,
+				Some(&external_id),
+				Some(&user_ref),
+			);
+			if let Err(remove_error) = self
+				.write(record, || self.zitadel_client.remove_user(user_ref.user_id.clone()))
+				.await
+			{
+				tracing::error!(
+					"Failed to remove user `{}` that could not be marked as managed: {:?}",
+					user_ref.user_id,
+					remove_error
+				);
+			}
+			return Err(error.context("failed to mark the imported user as managed"));
+		}
+
+		self.user_ids.insert(external_id, user_ref.clone());
+		self.set_user_metadata(&user_ref, user, source_name).await?;
+
+		self.sync_user_grants(&user_ref, &user.user_data, source_name).await?;
+
+		tracing::info!("Successfully imported user {:?}", user);
+
+		Ok(())
+	}
+
+	/// Find the Zitadel ID of a user left behind by an earlier import of
+	/// the given user, which Zitadel created without the sync learning
+	/// of it, e.g. because the response was lost
+	///
+	/// Such a user has the login name and external ID of the user and is
+	/// in their organization, but was never marked as managed.
+	async fn get_interrupted_import(
+		&self,
+		user: &ZitadelUser,
+		organization_id: &str,
+	) -> Result<Option<String>> {
+		let Some(existing_user) =
+			self.call(|| self.zitadel_client.get_user_by_login_name(&user.login_name)).await?
+		else {
+			return Ok(None);
+		};
+
+		let external_id = user.user_data.external_user_id.to_string();
+		let Some(user_ref) = self.get_user_ref_by_external_id(&external_id).await? else {
+			return Ok(None);
+		};
+
+		let interrupted = user_ref.user_id == existing_user.id
+			&& user_ref.organization_id == organization_id
+			&& !self.is_managed_user(&user_ref).await?;
+		Ok(interrupted.then_some(user_ref.user_id))
+	}
+
+	/// Handle a user that could not be imported because a user with the
+	/// same login name already exists
+	///
+	/// An existing user this source already manages under the same
+	/// external ID was imported before, e.g. by a source that reports all
+	/// of its users as new, and is only updated where it differs from the
+	/// source. Other users are adopted or reported according to the
+	/// configured import conflict policy.
+	async fn resolve_import_conflict(&self, user: &ZitadelUser, source_name: &str) -> Result<()> {
+		let login_name = user.login_name.clone();
+
+		let Some(existing_user) =
+			self.call(|| self.zitadel_client.get_user_by_login_name(&login_name)).await?
+		else {
+			bail!("could not find conflicting user `{}`", login_name);
+		};
+
+		let external_id = user.user_data.external_user_id.to_string();
+		let enabled = matches!(existing_user.state(), UserState::Active | UserState::Initial);
+		let human = match existing_user.r#type.clone() {
+			Some(UserType::Human(human)) => Some(human),
+			_ => None,
+		};
+		let existing_login_name = existing_user.user_name.clone();
+		let existing_user = self.user_ref(
+			existing_user.id,
+			existing_user.details.map(|details| details.resource_owner),
+		);
+		let managed_by = self.get_managed_by(&existing_user).await?;
+
+		if let Some(human) = human.filter(|human| {
+			managed_by.as_deref() == Some(source_name)
+				&& human.profile.as_ref().is_some_and(|profile| profile.nick_name == external_id)
+		}) {
+			tracing::debug!("User `{}` was already imported, updating it", user.log_name());
+			self.user_ids.insert(external_id, existing_user.clone());
+
+			let profile = human.profile.unwrap_or_default();
+			let mut old = User {
+				first_name: profile.first_name.into(),
+				last_name: profile.last_name.into(),
+				email: human.email.map(|email| email.email).unwrap_or_default().into(),
+				phone: human
+					.phone
+					.map(|phone| phone.phone)
+					.filter(|phone| !phone.is_empty())
+					.map(Into::into),
+				enabled,
+				preferred_username: self
+					.get_user_metadata(&existing_user, "preferred_username")
+					.await?
+					.unwrap_or_default()
+					.into(),
+				display_name: Some(profile.display_name),
+				login_name: Some(existing_login_name),
+				..user.user_data.clone()
+			};
+			old.metadata.clear();
+			for key in user.user_data.metadata.keys() {
+				if let Some(value) = self.get_user_metadata(&existing_user, key).await? {
+					old.metadata.insert(key.clone(), value.into());
+				}
+			}
+
+			let old = old.to_zitadel_user(&self.feature_flags, &self.zitadel_config);
+			return self.update_changed_user(&old, user.clone(), source_name).await;
+		}
+
+		// Never take over users another source is responsible for
+		let adopt = match self.zitadel_config.import_conflict_policy {
+			ImportConflictPolicy::Strict => false,
+			ImportConflictPolicy::Adopt => {
+				managed_by.map_or(true, |managed_by| managed_by == source_name)
+			}
+		};
+
+		if !adopt {
+			tracing::warn!("User `{}` already exists in Zitadel, not importing", user.log_name());
+			self.report
+				.record(ReportEntry::ImportConflict { source: source_name.to_owned(), login_name });
+			return Ok(());
+		}
+
+		self.adopt_existing_user(&existing_user, user, source_name).await?;
+
+		tracing::info!("Successfully adopted existing user {:?}", user);
+
+		Ok(())
+	}
+
+	/// Take over an existing Zitadel user on import, bringing its data
+	/// in line with the source
+	async fn adopt_existing_user(
+		&self,
+		user_ref: &UserRef,
+		user: &ZitadelUser,
+		source_name: &str,
+	) -> Result<()> {
+		let external_id = user.user_data.external_user_id.to_string();
+		let record =
+			|action| AuditRecord::new(source_name, action, Some(&external_id), Some(user_ref));
+
+		let profile = record(AuditAction::UpdateProfile)
+			.after("first_name", user.user_data.first_name.to_string())
+			.after("last_name", user.user_data.last_name.to_string())
+			.after("display_name", user.get_display_name());
+		self.write(profile, || {
+			self.zitadel_client.update_human_user_profile(
+				&user_ref.organization_id,
+				user_ref.user_id.clone(),
+				user.user_data.first_name.clone().to_string(),
+				user.user_data.last_name.clone().to_string(),
+				None,
+				Some(user.get_display_name()),
+				None,
+				None,
 			)
+		})
+		.await?;
+
+		let email =
+			record(AuditAction::UpdateEmail).after("email", user.user_data.email.to_string());
+		self.write(email, || {
+			self.zitadel_client.update_human_user_email(
+				&user_ref.organization_id,
+				user_ref.user_id.clone(),
+				user.user_data.email.clone().to_string(),
+				!user.needs_email_verification,
+			)
+		})
+		.await?;
+
+		if let Some(phone) = &user.user_data.phone {
+			let record = record(AuditAction::UpdatePhone).after("phone", phone.to_string());
+			self.write(record, || {
+				self.zitadel_client.update_human_user_phone(
+					&user_ref.organization_id,
+					user_ref.user_id.clone(),
+					phone.clone().to_string(),
+					!user.needs_phone_verification,
+				)
+			})
 			.await?;
+		}
 
-		let id = match &user.user_data.external_user_id {
-			StringOrBytes::String(value) => value.as_bytes(),
-			StringOrBytes::Bytes(value) => value,
+		for idp in user.get_idps() {
+			let record = record(AuditAction::LinkIdp).after("idp", idp.config_id.clone());
+			match self
+				.write(record, || {
+					self.zitadel_client.add_user_idp_link(user_ref.user_id.clone(), idp.clone())
+				})
+				.await
+			{
+				Err(error) if !Self::is_already_exists_error(&error) => return Err(error.into()),
+				_ => {}
+			}
+		}
+
+		self.user_ids.insert(user.user_data.external_user_id.to_string(), user_ref.clone());
+		self.set_managed_by(user_ref, Some(&external_id), source_name).await?;
+		self.set_user_metadata(user_ref, user, source_name).await?;
+
+		self.sync_user_grants(user_ref, &user.user_data, source_name).await?;
+
+		Ok(())
+	}
+
+	/// Set the metadata the sync maintains for a user, besides the
+	/// marker of the source managing the user
+	async fn set_user_metadata(
+		&self,
+		user_ref: &UserRef,
+		user: &ZitadelUser,
+		source_name: &str,
+	) -> Result<()> {
+		let external_id = user.user_data.external_user_id.to_string();
+		let record = |key: &str, value: &str| {
+			AuditRecord::new(
+				source_name,
+				AuditAction::SetMetadata,
+				Some(&external_id),
+				Some(user_ref),
+			)
+			.after(key, value)
 		};
 
-		self.zitadel_client
-			.set_user_metadata(
-				Some(&self.zitadel_config.organization_id),
-				new_user_id.clone(),
-				"localpart".to_owned(),
-				&Uuid::new_v5(&FAMEDLY_NAMESPACE, id).to_string(),
+		let preferred_username = user.user_data.preferred_username.to_string();
+		self.write(record("preferred_username", &preferred_username), || {
+			self.zitadel_client.set_user_metadata(
+				Some(&user_ref.organization_id),
+				user_ref.user_id.clone(),
+				"preferred_username".to_owned(),
+				&preferred_username,
 			)
-			.await?;
+		})
+		.await?;
 
-		self.zitadel_client
-			.add_user_grant(
-				Some(self.zitadel_config.organization_id.clone()),
-				new_user_id,
-				self.zitadel_config.project_id.clone(),
-				None,
-				vec![FAMEDLY_USER_ROLE.to_owned()],
+		let localpart = user.user_data.localpart.clone().unwrap_or_else(|| {
+			localpart::derive(&self.zitadel_config.localpart.strategy, &user.user_data)
+		});
+
+		self.write(record("localpart", &localpart), || {
+			self.zitadel_client.set_user_metadata(
+				Some(&user_ref.organization_id),
+				user_ref.user_id.clone(),
+				"localpart".to_owned(),
+				&localpart,
 			)
+		})
+		.await?;
+		if let Some(localparts) =
+			self.localparts.lock().unwrap_or_else(PoisonError::into_inner).as_mut()
+		{
+			localparts.insert(localpart, external_id.clone());
+		}
+
+		self.sync_user_metadata(
+			user_ref,
+			&BTreeMap::new(),
+			&user.user_data.metadata,
+			source_name,
+			&external_id,
+		)
+		.await?;
+
+		Ok(())
+	}
+
+	/// Bring the additional metadata of a user in line with the source,
+	/// setting changed values and removing metadata whose source
+	/// attribute disappeared
+	async fn sync_user_metadata(
+		&self,
+		user_ref: &UserRef,
+		old: &BTreeMap<String, StringOrBytes>,
+		new: &BTreeMap<String, StringOrBytes>,
+		source_name: &str,
+		external_id: &str,
+	) -> Result<()> {
+		let record =
+			|action| AuditRecord::new(source_name, action, Some(external_id), Some(user_ref));
+
+		for (key, value) in new {
+			let value = value.to_string();
+			let old_value = old.get(key).map(ToString::to_string);
+			if old_value.as_ref() == Some(&value) {
+				continue;
+			}
+
+			let record = record(AuditAction::SetMetadata).change(key, old_value, value.clone());
+			self.write(record, || {
+				self.zitadel_client.set_user_metadata(
+					Some(&user_ref.organization_id),
+					user_ref.user_id.clone(),
+					key.clone(),
+					&value,
+				)
+			})
 			.await?;
+		}
 
-		tracing::info!("Successfully imported user {:?}", user);
+		for (key, value) in old.iter().filter(|(key, _)| !new.contains_key(*key)) {
+			let record = record(AuditAction::RemoveMetadata).before(key, value.to_string());
+			self.write(record, || {
+				self.zitadel_client.remove_user_metadata(
+					Some(&user_ref.organization_id),
+					user_ref.user_id.clone(),
+					key.clone(),
+				)
+			})
+			.await?;
+		}
 
 		Ok(())
 	}
 
+	/// Check if an error means that the created entity already exists
+	fn is_already_exists_error(error: &ZitadelError) -> bool {
+		matches!(error, ZitadelError::TonicResponseError(status) if status.code() == TonicErrorCode::AlreadyExists)
+	}
+
+	/// Check if an error means that Zitadel is temporarily overloaded
+	/// or unavailable, so the request may be retried
+	fn is_retryable_error(error: &ZitadelError) -> bool {
+		matches!(error, ZitadelError::TonicResponseError(status) if matches!(status.code(), TonicErrorCode::ResourceExhausted | TonicErrorCode::Unavailable))
+	}
+
+	/// Check if an error means that Zitadel rejected a request due to
+	/// the rate limit without processing it, so even requests that are
+	/// not idempotent may be retried
+	fn is_rate_limited_error(error: &ZitadelError) -> bool {
+		matches!(error, ZitadelError::TonicResponseError(status) if status.code() == TonicErrorCode::ResourceExhausted)
+	}
+
+	/// Check if an error means that Zitadel cannot be reached or does
+	/// not accept the sync's credentials
+	fn is_connection_error(error: &ZitadelError) -> bool {
+		matches!(error, ZitadelError::TonicResponseError(status) if matches!(status.code(), TonicErrorCode::Unavailable | TonicErrorCode::DeadlineExceeded | TonicErrorCode::Unauthenticated))
+	}
+
 	/// Check if an error is an invalid phone error
-	fn is_invalid_phone_error(error: anyhow::Error) -> bool {
+	fn is_invalid_phone_error(error: &anyhow::Error) -> bool {
 		/// Part of the error message returned by Zitadel
 		/// when a phone number is invalid for a new user
 		const INVALID_PHONE_IMPORT_ERROR: &str = "invalid ImportHumanUserRequest_Phone";
@@ -461,7 +1849,8 @@ impl Zitadel {
 		/// when a phone number is invalid for an existing user being updated
 		const INVALID_PHONE_UPDATE_ERROR: &str = "invalid UpdateHumanPhoneRequest";
 
-		if let Ok(ZitadelError::TonicResponseError(ref error)) = error.downcast::<ZitadelError>() {
+		if let Some(ZitadelError::TonicResponseError(error)) = error.downcast_ref::<ZitadelError>()
+		{
 			return error.code() == TonicErrorCode::InvalidArgument
 				&& (error.message().contains(INVALID_PHONE_IMPORT_ERROR)
 					|| error.message().contains(INVALID_PHONE_UPDATE_ERROR));
@@ -478,26 +1867,187 @@ pub struct ZitadelConfig {
 	pub url: Url,
 	/// File containing a private key for authentication to Famedly Zitadel
 	pub key_file: PathBuf,
-	/// Organization ID provided by Famedly Zitadel
+	/// Organization ID provided by Famedly Zitadel; users are created in
+	/// it unless an organization rule matches
 	pub organization_id: String,
-	/// Project ID provided by Famedly Zitadel
+	/// Rules routing users to other organizations, the first matching
+	/// rule wins
+	#[serde(default)]
+	pub organization_rules: Vec<OrganizationRule>,
+	/// Project ID provided by Famedly Zitadel; users are granted the
+	/// `User` role in it unless `grants` are configured
 	pub project_id: String,
+	/// The roles to grant users in Zitadel projects
+	#[serde(default)]
+	pub grants: Vec<GrantConfig>,
 	/// IDP ID provided by Famedly Zitadel
 	pub idp_id: String,
+	/// What to do when a user to import already exists in Zitadel
+	#[serde(default)]
+	pub import_conflict_policy: ImportConflictPolicy,
+	/// Where to cache the Zitadel IDs of synced users; if unset, users
+	/// are looked up by their external ID on every run
+	pub user_id_cache_path: Option<PathBuf>,
+	/// Template for the display names of users, see
+	/// [`ZitadelConfig::display_name`]
+	#[serde(default = "default_display_name_template")]
+	pub display_name_template: String,
+	/// How to derive the Matrix localparts of users
+	#[serde(default)]
+	pub localpart: LocalpartConfig,
+	/// Where the login names of users are taken from
+	#[serde(default)]
+	pub login_name: LoginNameSource,
+	/// How many users are written to Zitadel at once
+	#[serde(default = "default_concurrency")]
+	pub concurrency: usize,
+	/// How fast requests are sent to Zitadel, and how failed requests
+	/// are retried
+	#[serde(default)]
+	pub rate_limit: RateLimitConfig,
+	/// How many requests in a row may fail to reach Zitadel before the
+	/// run is aborted
+	#[serde(default = "default_circuit_breaker_threshold")]
+	pub circuit_breaker_threshold: u32,
+	/// Where to keep the journal of all changes made to Zitadel, if
+	/// anywhere
+	pub audit_journal_path: Option<PathBuf>,
+}
+
+/// The default for [`ZitadelConfig::display_name_template`]
+fn default_display_name_template() -> String {
+	DEFAULT_DISPLAY_NAME_TEMPLATE.to_owned()
+}
+
+/// The default for [`ZitadelConfig::concurrency`]
+fn default_concurrency() -> usize {
+	DEFAULT_CONCURRENCY
+}
+
+/// The default for [`ZitadelConfig::circuit_breaker_threshold`]
+fn default_circuit_breaker_threshold() -> u32 {
+	DEFAULT_CIRCUIT_BREAKER_THRESHOLD
+}
+
+impl ZitadelConfig {
+	/// The organization a user belongs in according to the
+	/// organization rules
+	pub(crate) fn organization_for(&self, user: &User) -> &str {
+		organizations::route(&self.organization_rules, &self.organization_id, user)
+	}
+
+	/// All organizations users may be routed to, the default one first
+	pub(crate) fn organization_ids(&self) -> Vec<String> {
+		let mut organization_ids = vec![self.organization_id.clone()];
+
+		for rule in &self.organization_rules {
+			if !organization_ids.contains(&rule.organization_id) {
+				organization_ids.push(rule.organization_id.clone());
+			}
+		}
+
+		organization_ids
+	}
+
+	/// Render the display name of a user from the display name template
+	///
+	/// In the template, `{first_name}`, `{last_name}`, `{email}`,
+	/// `{phone}`, `{preferred_username}` and `{external_user_id}` refer to
+	/// the user's fields, and any other `{name}` to the first value of
+	/// the named source attribute. Falls back to the default template if
+	/// the result is empty.
+	pub(crate) fn display_name(&self, user: &User) -> String {
+		let lookup = |name: &str| user.field_or_attribute(name);
+
+		match transform::render_template(&self.display_name_template, lookup).trim() {
+			"" => transform::render_template(DEFAULT_DISPLAY_NAME_TEMPLATE, lookup),
+			display_name => display_name.to_owned(),
+		}
+	}
+
+	/// The login name of a user according to the login name source,
+	/// falling back to the email address if the source is empty
+	pub(crate) fn login_name(&self, user: &User) -> String {
+		let login_name = match &self.login_name {
+			LoginNameSource::Email => None,
+			LoginNameSource::ExternalUserId => Some(user.external_user_id.to_string()),
+			LoginNameSource::PreferredUsername => Some(user.preferred_username.to_string()),
+			LoginNameSource::Attribute(attribute) => {
+				user.attribute_values(attribute).first().map(ToString::to_string)
+			}
+		};
+
+		login_name
+			.filter(|login_name| !login_name.is_empty())
+			.unwrap_or_else(|| user.email.to_string())
+	}
+
+	/// The configured grants, falling back to the `User` role in the
+	/// configured project
+	pub(crate) fn grant_configs(&self) -> Vec<GrantConfig> {
+		if self.grants.is_empty() {
+			vec![GrantConfig {
+				project_id: self.project_id.clone(),
+				roles: vec![FAMEDLY_USER_ROLE.to_owned()],
+				condition: None,
+			}]
+		} else {
+			self.grants.clone()
+		}
+	}
+}
+
+/// How to handle users that already exist in Zitadel when importing
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportConflictPolicy {
+	/// Leave the existing user alone and note the conflict in the sync
+	/// report
+	#[default]
+	Strict,
+	/// Take over the existing user, linking it to the identity provider,
+	/// setting the sync's metadata and grants and updating its profile
+	/// from the source
+	Adopt,
+}
+
+/// Where the login names of users are taken from; the email address is
+/// synced separately either way
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LoginNameSource {
+	/// The email address
+	#[default]
+	Email,
+	/// The external user ID, e.g. the LDAP `uid`
+	ExternalUserId,
+	/// The preferred username
+	PreferredUsername,
+	/// The first value of the named source attribute, e.g.
+	/// `userPrincipalName`
+	Attribute(String),
 }
 
 /// The different ways to identify a user in Zitadel
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum UserId {
 	/// The login name is actually the email address
 	Login(String),
-	/// The nick name is actually the LDAP ID
+	/// The nick name is actually the stable external ID of the user
 	Nick(String),
 	/// The Zitadel ID
-	#[allow(dead_code)]
 	ZitadelId(String),
 }
 
+impl UserId {
+	/// The identifier itself, whichever kind it is
+	pub(crate) fn key(&self) -> &str {
+		match self {
+			Self::Login(id) | Self::Nick(id) | Self::ZitadelId(id) => id,
+		}
+	}
+}
+
 /// The difference between the source and Zitadel
 #[derive(Debug)]
 pub struct SourceDiff {
@@ -510,10 +2060,23 @@ pub struct SourceDiff {
 }
 
 /// A user that has changed returned from the LDAP poller
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChangedUser {
 	/// The old state
 	pub old: User,
 	/// The new state
 	pub new: User,
 }
+
+/// A user as currently stored in Zitadel
+#[derive(Debug)]
+pub struct ManagedUser {
+	/// The Zitadel ID of the user
+	pub zitadel_id: String,
+	/// The organization the user belongs to
+	pub organization_id: String,
+	/// The user's data as stored in Zitadel
+	pub user: User,
+	/// The roles currently granted to the user, keyed by project ID
+	pub(crate) grants: Grants,
+}
@@ -1,6 +1,10 @@
 #![cfg(test)]
 
-use std::{collections::HashSet, path::Path, time::Duration};
+use std::{
+	collections::{BTreeMap, HashSet},
+	path::Path,
+	time::Duration,
+};
 
 use base64::prelude::{Engine, BASE64_STANDARD};
 use ldap3::{Ldap as LdapClient, LdapConnAsync, LdapConnSettings, Mod};
@@ -9,7 +13,8 @@ use ldap_sync::{
 	ukt_test_helpers::{
 		get_mock_server_url, prepare_endpoint_mock, prepare_oauth2_mock, ENDPOINT_PATH, OAUTH2_PATH,
 	},
-	AttributeMapping, Config, FeatureFlag,
+	AttributeCondition, AttributeMapping, Config, FeatureFlag, GrantConfig, ImportConflictPolicy,
+	LocalpartStrategy, LoginNameSource, OrganizationRule, PhoneConfig, ValidationPolicy,
 };
 use tempfile::TempDir;
 use test_log::test;
@@ -211,6 +216,323 @@ async fn test_e2e_sync_change() {
 	}
 }
 
+#[test(tokio::test)]
+#[test_log(default_log_filter = "debug")]
+async fn test_e2e_sync_conditional_grants() {
+	let mut config = config().await.clone();
+	config.zitadel.grants = vec![
+		GrantConfig {
+			project_id: config.zitadel.project_id.clone(),
+			roles: vec![FAMEDLY_USER_ROLE.to_owned()],
+			condition: None,
+		},
+		GrantConfig {
+			project_id: config.zitadel.project_id.clone(),
+			roles: vec!["Admin".to_owned()],
+			condition: Some(AttributeCondition {
+				attribute: "title".to_owned(),
+				value: "admin".to_owned(),
+			}),
+		},
+	];
+	if let Some(ldap) = config.sources.ldap.as_mut() {
+		ldap.attributes.additional = vec!["title".to_owned()];
+	}
+
+	let mut ldap = Ldap::new().await;
+	ldap.create_user(
+		"Bob",
+		"Tables",
+		"Bobby",
+		"grants@famedly.de",
+		Some("+12015550123"),
+		"grants",
+		false,
+	)
+	.await;
+	ldap.change_user("grants", vec![("title", HashSet::from(["Admin"]))]).await;
+
+	config.perform_sync().await.expect("syncing failed");
+
+	let zitadel = open_zitadel_connection().await;
+	let user = zitadel
+		.get_user_by_login_name("grants@famedly.de")
+		.await
+		.expect("could not query Zitadel users")
+		.expect("missing Zitadel user");
+
+	let roles = get_user_roles(&zitadel, &config, &user.id).await;
+	assert_eq!(roles, HashSet::from([FAMEDLY_USER_ROLE.to_owned(), "Admin".to_owned()]));
+
+	ldap.change_user("grants", vec![("title", HashSet::from(["Staff"]))]).await;
+
+	config.perform_sync().await.expect("syncing failed");
+
+	let roles = get_user_roles(&zitadel, &config, &user.id).await;
+	assert_eq!(roles, HashSet::from([FAMEDLY_USER_ROLE.to_owned()]));
+}
+
+#[test(tokio::test)]
+#[test_log(default_log_filter = "debug")]
+async fn test_e2e_sync_metadata() {
+	let mut config = config().await.clone();
+	if let Some(ldap) = config.sources.ldap.as_mut() {
+		ldap.attributes.metadata = BTreeMap::from([(
+			"department".to_owned(),
+			AttributeMapping::NoBinaryOption("departmentNumber".to_owned()),
+		)]);
+	}
+
+	let mut ldap = Ldap::new().await;
+	ldap.create_user(
+		"Bob",
+		"Tables",
+		"Bobby",
+		"metadata@famedly.de",
+		Some("+12015550123"),
+		"metadata",
+		false,
+	)
+	.await;
+	ldap.change_user("metadata", vec![("departmentNumber", HashSet::from(["Radiology"]))]).await;
+
+	config.perform_sync().await.expect("syncing failed");
+
+	let zitadel = open_zitadel_connection().await;
+	let user = zitadel
+		.get_user_by_login_name("metadata@famedly.de")
+		.await
+		.expect("could not query Zitadel users")
+		.expect("missing Zitadel user");
+
+	let department = zitadel
+		.get_user_metadata(Some(config.zitadel.organization_id.clone()), &user.id, "department")
+		.await
+		.expect("could not get user metadata");
+	assert_eq!(department, Some("Radiology".to_owned()));
+
+	ldap.change_user("metadata", vec![("departmentNumber", HashSet::new())]).await;
+
+	config.perform_sync().await.expect("syncing failed");
+
+	let department = zitadel
+		.get_user_metadata(Some(config.zitadel.organization_id.clone()), &user.id, "department")
+		.await
+		.expect("could not get user metadata");
+	assert_eq!(department, None);
+}
+
+#[test(tokio::test)]
+#[test_log(default_log_filter = "debug")]
+async fn test_e2e_sync_display_name_template() {
+	let mut config = config().await.clone();
+	config.zitadel.display_name_template = "{title} {first_name} {last_name}".to_owned();
+	if let Some(ldap) = config.sources.ldap.as_mut() {
+		ldap.attributes.additional = vec!["title".to_owned()];
+	}
+
+	let mut ldap = Ldap::new().await;
+	ldap.create_user(
+		"Bob",
+		"Tables",
+		"Bobby",
+		"display_name@famedly.de",
+		None,
+		"display_name",
+		false,
+	)
+	.await;
+	ldap.change_user("display_name", vec![("title", HashSet::from(["Dr."]))]).await;
+
+	config.perform_sync().await.expect("syncing failed");
+
+	let zitadel = open_zitadel_connection().await;
+	let user = zitadel
+		.get_user_by_login_name("display_name@famedly.de")
+		.await
+		.expect("could not query Zitadel users")
+		.expect("missing Zitadel user");
+	let Some(UserType::Human(human)) = user.r#type else {
+		panic!("user lacks details");
+	};
+	assert_eq!(human.profile.expect("user lacks a profile").display_name, "Dr. Bob Tables");
+
+	ldap.change_user("display_name", vec![("title", HashSet::from(["Prof."]))]).await;
+
+	config.perform_sync().await.expect("syncing failed");
+
+	let user = zitadel
+		.get_user_by_login_name("display_name@famedly.de")
+		.await
+		.expect("could not query Zitadel users")
+		.expect("missing Zitadel user");
+	let Some(UserType::Human(human)) = user.r#type else {
+		panic!("user lacks details");
+	};
+	assert_eq!(human.profile.expect("user lacks a profile").display_name, "Prof. Bob Tables");
+}
+
+#[test(tokio::test)]
+#[test_log(default_log_filter = "debug")]
+async fn test_e2e_sync_localpart_strategy() {
+	let mut config = config().await.clone();
+	config.zitadel.localpart.strategy = LocalpartStrategy::EmailLocalpart;
+
+	let mut ldap = Ldap::new().await;
+	ldap.create_user(
+		"Bob",
+		"Tables",
+		"Bobby",
+		"Localpart.Strategy@famedly.de",
+		None,
+		"localpart_strategy",
+		false,
+	)
+	.await;
+
+	config.perform_sync().await.expect("syncing failed");
+
+	let zitadel = open_zitadel_connection().await;
+	let user = zitadel
+		.get_user_by_login_name("Localpart.Strategy@famedly.de")
+		.await
+		.expect("could not query Zitadel users")
+		.expect("missing Zitadel user");
+
+	let localpart = zitadel
+		.get_user_metadata(Some(config.zitadel.organization_id.clone()), &user.id, "localpart")
+		.await
+		.expect("could not get user metadata");
+	assert_eq!(localpart, Some("localpart.strategy".to_owned()));
+}
+
+#[test(tokio::test)]
+#[test_log(default_log_filter = "debug")]
+async fn test_e2e_sync_login_name_source() {
+	let mut config = config().await.clone();
+	config.zitadel.login_name = LoginNameSource::ExternalUserId;
+
+	let mut ldap = Ldap::new().await;
+	ldap.create_user("Bob", "Tables", "Bobby", "login_name@famedly.de", None, "login_name", false)
+		.await;
+
+	config.perform_sync().await.expect("syncing failed");
+
+	let zitadel = open_zitadel_connection().await;
+	let user = zitadel
+		.get_user_by_login_name("login_name")
+		.await
+		.expect("could not query Zitadel users")
+		.expect("missing Zitadel user");
+	assert_eq!(user.user_name, "login_name");
+
+	let Some(UserType::Human(human)) = user.r#type else {
+		panic!("user lacks details");
+	};
+	assert_eq!(human.email.expect("user lacks an email address").email, "login_name@famedly.de");
+
+	// Email changes leave the login name alone
+	ldap.change_user("login_name", vec![("mail", HashSet::from(["login_name2@famedly.de"]))]).await;
+
+	config.perform_sync().await.expect("syncing failed");
+
+	let user = zitadel
+		.get_user_by_login_name("login_name")
+		.await
+		.expect("could not query Zitadel users")
+		.expect("missing Zitadel user");
+	let Some(UserType::Human(human)) = user.r#type else {
+		panic!("user lacks details");
+	};
+	assert_eq!(human.email.expect("user lacks an email address").email, "login_name2@famedly.de");
+}
+
+#[test(tokio::test)]
+#[test_log(default_log_filter = "debug")]
+async fn test_e2e_sync_phone_normalization() {
+	let mut config = config().await.clone();
+	config.phone =
+		Some(PhoneConfig { default_country: Some("US".parse().expect("invalid country")) });
+
+	let mut ldap = Ldap::new().await;
+	ldap.create_user(
+		"Bob",
+		"Tables",
+		"Bobby",
+		"phone_normalization@famedly.de",
+		Some("(201) 555-0125 ext. 12"),
+		"phone_normalization",
+		false,
+	)
+	.await;
+	ldap.create_user(
+		"Bob",
+		"Tables",
+		"Bobby",
+		"phone_invalid@famedly.de",
+		Some("12"),
+		"phone_invalid",
+		false,
+	)
+	.await;
+
+	config.perform_sync().await.expect("syncing failed");
+
+	let zitadel = open_zitadel_connection().await;
+	for (login_name, phone) in
+		[("phone_normalization@famedly.de", "+12015550125"), ("phone_invalid@famedly.de", "")]
+	{
+		let user = zitadel
+			.get_user_by_login_name(login_name)
+			.await
+			.expect("could not query Zitadel users")
+			.expect("missing Zitadel user");
+		let Some(UserType::Human(human)) = user.r#type else {
+			panic!("user lacks details");
+		};
+		assert_eq!(human.phone.expect("user lacks a phone number object").phone, phone);
+	}
+}
+
+#[test(tokio::test)]
+#[test_log(default_log_filter = "debug")]
+async fn test_e2e_sync_validation() {
+	let mut config = config().await.clone();
+	let mut ldap = Ldap::new().await;
+
+	config.validation.email = ValidationPolicy::Abort;
+	ldap.create_user(
+		"Bob",
+		"Tables",
+		"Bobby",
+		"validation_abort@@famedly.de",
+		None,
+		"validation_abort",
+		false,
+	)
+	.await;
+	assert!(config.perform_sync().await.is_err());
+
+	config.validation.email = ValidationPolicy::SkipUser;
+	ldap.create_user(
+		"Bob",
+		"Tables",
+		"Bobby",
+		"validation_skip@@famedly.de",
+		None,
+		"validation_skip",
+		false,
+	)
+	.await;
+	config.perform_sync().await.expect("syncing failed");
+
+	let zitadel = open_zitadel_connection().await;
+	for login_name in ["validation_abort@@famedly.de", "validation_skip@@famedly.de"] {
+		let user = zitadel.get_user_by_login_name(login_name).await;
+		assert!(user.is_err_and(|error| matches!(error, ZitadelError::TonicResponseError(status) if status.code() == TonicErrorCode::NotFound)));
+	}
+}
+
 #[test(tokio::test)]
 #[test_log(default_log_filter = "debug")]
 async fn test_e2e_sync_disable_and_reenable() {
@@ -245,6 +567,127 @@ async fn test_e2e_sync_disable_and_reenable() {
 	assert!(user.is_ok_and(|u| u.is_some()));
 }
 
+#[test(tokio::test)]
+#[test_log(default_log_filter = "debug")]
+async fn test_e2e_sync_organization_move() {
+	let mut config = config().await.clone();
+	let second_organization_id = config.zitadel.organization_rules[0].organization_id.clone();
+
+	// The project is not granted to the second organization
+	config.zitadel.grants = vec![GrantConfig {
+		project_id: config.zitadel.project_id.clone(),
+		roles: vec![FAMEDLY_USER_ROLE.to_owned()],
+		condition: Some(AttributeCondition {
+			attribute: "mail".to_owned(),
+			value: "move@famedly.de".to_owned(),
+		}),
+	}];
+
+	let mut ldap = Ldap::new().await;
+	ldap.create_user("Bob", "Tables", "Bobby", "move@famedly.de", None, "move", false).await;
+
+	config.perform_sync().await.expect("syncing failed");
+
+	let zitadel = open_zitadel_connection().await;
+	let old_user = zitadel
+		.get_user_by_login_name("move@famedly.de")
+		.await
+		.expect("could not query Zitadel users")
+		.expect("missing Zitadel user");
+	assert_eq!(
+		old_user.details.expect("user lacks details").resource_owner,
+		config.zitadel.organization_id
+	);
+
+	ldap.change_user("move", vec![("mail", HashSet::from(["move@moved.famedly.de"]))]).await;
+	config.perform_sync().await.expect("syncing failed");
+
+	let user = zitadel
+		.get_user_by_login_name("move@moved.famedly.de")
+		.await
+		.expect("could not query Zitadel users")
+		.expect("missing Zitadel user");
+	assert_ne!(user.id, old_user.id);
+	assert_eq!(user.details.expect("user lacks details").resource_owner, second_organization_id);
+
+	let managed_by = zitadel
+		.get_user_metadata(Some(second_organization_id), &user.id, "managed_by")
+		.await
+		.expect("could not get user metadata");
+	assert_eq!(managed_by, Some("LDAP".to_owned()));
+
+	match zitadel.get_user_by_id(&old_user.id).await {
+		Ok(user) => assert!(user.is_none()),
+		Err(ZitadelError::TonicResponseError(status)) => {
+			assert_eq!(status.code(), TonicErrorCode::NotFound);
+		}
+		Err(error) => panic!("could not query Zitadel users: {error:?}"),
+	}
+}
+
+#[test(tokio::test)]
+#[test_log(default_log_filter = "debug")]
+async fn test_e2e_sync_organization_move_by_attribute() {
+	let mut config = config().await.clone();
+	let second_organization_id = config.zitadel.organization_rules[0].organization_id.clone();
+
+	// Moving by an attribute keeps the login name, which the old account
+	// holds until the new one exists
+	config.zitadel.organization_rules = vec![OrganizationRule {
+		organization_id: second_organization_id.clone(),
+		base_dn: None,
+		email_domain: None,
+		condition: Some(AttributeCondition {
+			attribute: "displayName".to_owned(),
+			value: "Moved".to_owned(),
+		}),
+	}];
+
+	let mut ldap = Ldap::new().await;
+	ldap.create_user(
+		"Bob",
+		"Tables",
+		"Bobby",
+		"attribute_move@famedly.de",
+		None,
+		"attribute_move",
+		false,
+	)
+	.await;
+
+	config.perform_sync().await.expect("syncing failed");
+
+	let zitadel = open_zitadel_connection().await;
+	let old_user = zitadel
+		.get_user_by_login_name("attribute_move@famedly.de")
+		.await
+		.expect("could not query Zitadel users")
+		.expect("missing Zitadel user");
+	assert_eq!(
+		old_user.details.expect("user lacks details").resource_owner,
+		config.zitadel.organization_id
+	);
+
+	ldap.change_user("attribute_move", vec![("displayName", HashSet::from(["Moved"]))]).await;
+	config.perform_sync().await.expect("syncing failed");
+
+	let user = zitadel
+		.get_user_by_login_name("attribute_move@famedly.de")
+		.await
+		.expect("could not query Zitadel users")
+		.expect("missing Zitadel user");
+	assert_ne!(user.id, old_user.id);
+	assert_eq!(user.details.expect("user lacks details").resource_owner, second_organization_id);
+
+	match zitadel.get_user_by_id(&old_user.id).await {
+		Ok(user) => assert!(user.is_none()),
+		Err(ZitadelError::TonicResponseError(status)) => {
+			assert_eq!(status.code(), TonicErrorCode::NotFound);
+		}
+		Err(error) => panic!("could not query Zitadel users: {error:?}"),
+	}
+}
+
 #[test(tokio::test)]
 #[test_log(default_log_filter = "debug")]
 async fn test_e2e_sync_email_change() {
@@ -260,18 +703,78 @@ async fn test_e2e_sync_email_change() {
 	)
 	.await;
 
-	let config = config().await;
-	config.perform_sync().await.expect("syncing failed");
+	let config = config().await;
+	config.perform_sync().await.expect("syncing failed");
+
+	ldap.change_user("email_change", vec![("mail", HashSet::from(["email_changed@famedly.de"]))])
+		.await;
+
+	config.perform_sync().await.expect("syncing failed");
+
+	let zitadel = open_zitadel_connection().await;
+	let user = zitadel.get_user_by_login_name("email_changed@famedly.de").await;
+
+	assert!(user.is_ok());
+}
+
+#[test(tokio::test)]
+#[test_log(default_log_filter = "debug")]
+async fn test_e2e_sync_manual_email_change() {
+	let mut ldap = Ldap::new().await;
+	ldap.create_user(
+		"Bob",
+		"Tables",
+		"Bobby2",
+		"manual_email@famedly.de",
+		Some("+12015550124"),
+		"manual_email",
+		false,
+	)
+	.await;
+
+	let config = config().await;
+	config.perform_sync().await.expect("syncing failed");
+
+	let zitadel = open_zitadel_connection().await;
+	let user = zitadel
+		.get_user_by_login_name("manual_email@famedly.de")
+		.await
+		.expect("could not query Zitadel users")
+		.expect("could not find user");
+
+	// Change the login name behind the sync's back, so it can no longer
+	// be used to find the user
+	zitadel
+		.update_human_user_name(
+			&config.zitadel.organization_id,
+			user.id.clone(),
+			"manually_changed@famedly.de".to_owned(),
+		)
+		.await
+		.expect("failed to change user name");
 
-	ldap.change_user("email_change", vec![("mail", HashSet::from(["email_changed@famedly.de"]))])
+	ldap.change_user("manual_email", vec![("telephoneNumber", HashSet::from(["+12015550123"]))])
 		.await;
-
 	config.perform_sync().await.expect("syncing failed");
 
-	let zitadel = open_zitadel_connection().await;
-	let user = zitadel.get_user_by_login_name("email_changed@famedly.de").await;
+	let user = zitadel
+		.get_user_by_login_name("manually_changed@famedly.de")
+		.await
+		.expect("could not query Zitadel users")
+		.expect("could not find user");
 
-	assert!(user.is_ok());
+	match user.r#type {
+		Some(UserType::Human(user)) => {
+			assert_eq!(user.phone.expect("phone missing").phone, "+12015550123");
+		}
+		_ => panic!("human user became a machine user?"),
+	}
+
+	ldap.delete_user("manual_email").await;
+	config.perform_sync().await.expect("syncing failed");
+
+	let user = zitadel.get_user_by_login_name("manually_changed@famedly.de").await;
+	assert!(user.is_err_and(|error| matches!(error, ZitadelError::TonicResponseError(status) if status.code() == TonicErrorCode::NotFound)));
 }
 
 #[test(tokio::test)]
@@ -610,6 +1113,59 @@ async fn test_e2e_binary_attr_valid_utf8() {
 	}
 }
 
+#[test(tokio::test)]
+#[test_log(default_log_filter = "debug")]
+async fn test_e2e_binary_user_id_deletion() {
+	let mut config = config().await.clone();
+
+	// Treat the ID as binary, so the deletion has to arrive at the same
+	// base64 encoding as the one stored in the nick name on import
+	config
+		.sources
+		.ldap
+		.as_mut()
+		.expect("ldap must be configured for this test")
+		.attributes
+		.user_id = AttributeMapping::OptionalBinary { name: "uid".to_owned(), is_binary: true };
+
+	let mut ldap = Ldap::new().await;
+	ldap.create_user(
+		"Bob",
+		"Tables",
+		"Bobby",
+		"binary_deletion@famedly.de",
+		Some("+12015550123"),
+		"binary_deletion",
+		false,
+	)
+	.await;
+
+	config.perform_sync().await.expect("syncing failed");
+
+	let zitadel = open_zitadel_connection().await;
+	let user = zitadel
+		.get_user_by_login_name("binary_deletion@famedly.de")
+		.await
+		.expect("could not query Zitadel users")
+		.expect("could not find user");
+
+	match user.r#type {
+		Some(UserType::Human(user)) => {
+			assert_eq!(
+				user.profile.expect("user lacks a profile").nick_name,
+				BASE64_STANDARD.encode("binary_deletion")
+			);
+		}
+		_ => panic!("user lacks details"),
+	}
+
+	ldap.delete_user("binary_deletion").await;
+	config.perform_sync().await.expect("syncing failed");
+
+	let user = zitadel.get_user_by_login_name("binary_deletion@famedly.de").await;
+	assert!(user.is_err_and(|error| matches!(error, ZitadelError::TonicResponseError(status) if status.code() == TonicErrorCode::NotFound)));
+}
+
 #[test(tokio::test)]
 #[test_log(default_log_filter = "debug")]
 async fn test_e2e_dry_run() {
@@ -670,6 +1226,234 @@ async fn test_e2e_dry_run() {
 		.is_ok_and(|user| user.is_some()));
 }
 
+#[test(tokio::test)]
+#[test_log(default_log_filter = "debug")]
+async fn test_e2e_full_reconcile() {
+	let mut config = config().await.clone();
+	config.sources.ukt = None;
+	config.sources.csv = None;
+	config.feature_flags.push(FeatureFlag::FullReconcile);
+
+	// Use a separate cache, since this test removes it
+	let tempdir = TempDir::new().expect("failed to initialize cache dir");
+	let cache_path = tempdir.path().join("cache.bin");
+	config.sources.ldap.as_mut().expect("ldap must be configured for this test").cache_path =
+		cache_path.clone();
+
+	let mut ldap = Ldap::new().await;
+	ldap.create_user(
+		"Bob",
+		"Tables",
+		"Bobby",
+		"reconcile@famedly.de",
+		Some("+12015550123"),
+		"reconcile",
+		false,
+	)
+	.await;
+
+	config.perform_sync().await.expect("syncing failed");
+
+	let zitadel = open_zitadel_connection().await;
+	let user = zitadel
+		.get_user_by_login_name("reconcile@famedly.de")
+		.await
+		.expect("could not query Zitadel users")
+		.expect("could not find user");
+
+	// Simulate a manual edit in the Zitadel console, which the cache
+	// knows nothing about
+	zitadel
+		.update_human_user_phone(
+			&config.zitadel.organization_id,
+			user.id.clone(),
+			"+12015550199".to_owned(),
+			true,
+		)
+		.await
+		.expect("failed to update phone number");
+
+	config.perform_sync().await.expect("syncing failed");
+
+	let user = zitadel
+		.get_user_by_login_name("reconcile@famedly.de")
+		.await
+		.expect("could not query Zitadel users")
+		.expect("could not find user");
+
+	match user.r#type {
+		Some(UserType::Human(user)) => {
+			assert_eq!(user.phone.expect("phone missing").phone, "+12015550123");
+		}
+		_ => panic!("human user became a machine user?"),
+	}
+
+	// Losing the cache must not cause any drift either
+	ldap.delete_user("reconcile").await;
+	std::fs::remove_file(&cache_path).expect("failed to remove cache");
+
+	config.perform_sync().await.expect("syncing failed");
+
+	let user = zitadel.get_user_by_login_name("reconcile@famedly.de").await;
+	assert!(user.is_err_and(|error| matches!(error, ZitadelError::TonicResponseError(status) if status.code() == TonicErrorCode::NotFound)));
+}
+
+#[test(tokio::test)]
+#[test_log(default_log_filter = "debug")]
+async fn test_e2e_import_conflict_adopt() {
+	let mut config = config().await.clone();
+	config.zitadel.import_conflict_policy = ImportConflictPolicy::Adopt;
+
+	let user = ImportHumanUserRequest {
+		user_name: "conflict@famedly.de".to_owned(),
+		profile: Some(Profile {
+			first_name: "Manually".to_owned(),
+			last_name: "Created".to_owned(),
+			display_name: "Manually Created".to_owned(),
+			gender: Gender::Unspecified.into(),
+			nick_name: String::default(),
+			preferred_language: String::default(),
+		}),
+		email: Some(Email { email: "conflict@famedly.de".to_owned(), is_email_verified: true }),
+		phone: None,
+		password: String::default(),
+		hashed_password: None,
+		password_change_required: false,
+		request_passwordless_registration: false,
+		otp_code: String::default(),
+		idps: vec![],
+	};
+
+	let zitadel = open_zitadel_connection().await;
+	zitadel
+		.create_human_user(&config.zitadel.organization_id, user)
+		.await
+		.expect("failed to create user");
+
+	let mut ldap = Ldap::new().await;
+	ldap.create_user(
+		"Bob",
+		"Tables",
+		"Bobby",
+		"conflict@famedly.de",
+		Some("+12015550123"),
+		"conflict",
+		false,
+	)
+	.await;
+
+	config.perform_sync().await.expect("syncing failed");
+
+	let user = zitadel
+		.get_user_by_login_name("conflict@famedly.de")
+		.await
+		.expect("could not query Zitadel users")
+		.expect("could not find user");
+
+	if let Some(UserType::Human(human)) = user.r#type {
+		let profile = human.profile.expect("user lacks a profile");
+		assert_eq!(profile.first_name, "Bob");
+		assert_eq!(profile.last_name, "Tables");
+		assert_eq!(human.phone.expect("user lacks a phone number").phone, "+12015550123");
+	} else {
+		panic!("user lacks details");
+	}
+
+	let managed_by = zitadel
+		.get_user_metadata(Some(config.zitadel.organization_id.clone()), &user.id, "managed_by")
+		.await
+		.expect("could not get user metadata");
+	assert_eq!(managed_by, Some("LDAP".to_owned()));
+
+	let localpart = zitadel
+		.get_user_metadata(Some(config.zitadel.organization_id.clone()), &user.id, "localpart")
+		.await
+		.expect("could not get user metadata");
+	assert_eq!(
+		localpart,
+		Some(Uuid::new_v5(&FAMEDLY_NAMESPACE, "conflict".as_bytes()).to_string())
+	);
+
+	let grants = zitadel
+		.list_user_grants(&config.zitadel.organization_id, &user.id)
+		.await
+		.expect("failed to get user grants");
+	let grant = grants.result.first().expect("no user grants found");
+	assert!(grant.role_keys.clone().into_iter().any(|key| key == FAMEDLY_USER_ROLE));
+}
+
+#[test(tokio::test)]
+#[test_log(default_log_filter = "debug")]
+async fn test_e2e_adopt_all() {
+	let config = config().await;
+
+	// A user imported before the sync marked the users it manages
+	let user = ImportHumanUserRequest {
+		user_name: "legacy@famedly.de".to_owned(),
+		profile: Some(Profile {
+			first_name: "Bob".to_owned(),
+			last_name: "Tables".to_owned(),
+			display_name: "Tables, Bob".to_owned(),
+			gender: Gender::Unspecified.into(),
+			nick_name: "legacy".to_owned(),
+			preferred_language: String::default(),
+		}),
+		email: Some(Email { email: "legacy@famedly.de".to_owned(), is_email_verified: true }),
+		phone: Some(Phone { phone: "+12015550124".to_owned(), is_phone_verified: true }),
+		password: String::default(),
+		hashed_password: None,
+		password_change_required: false,
+		request_passwordless_registration: false,
+		otp_code: String::default(),
+		idps: vec![],
+	};
+
+	let zitadel = open_zitadel_connection().await;
+	zitadel
+		.create_human_user(&config.zitadel.organization_id, user)
+		.await
+		.expect("failed to create user");
+
+	let mut ldap = Ldap::new().await;
+	ldap.create_user(
+		"Bob",
+		"Tables",
+		"Bobby",
+		"legacy@famedly.de",
+		Some("+12015550124"),
+		"legacy",
+		false,
+	)
+	.await;
+
+	config.perform_sync().await.expect("syncing failed");
+
+	let adopted = config.adopt_all_users("LDAP").await.expect("adopting failed");
+	assert!(adopted >= 1);
+
+	ldap.change_user("legacy", vec![("telephoneNumber", HashSet::from(["+12015550123"]))]).await;
+	config.perform_sync().await.expect("syncing failed");
+
+	let user = zitadel
+		.get_user_by_login_name("legacy@famedly.de")
+		.await
+		.expect("could not query Zitadel users")
+		.expect("missing Zitadel user");
+
+	let managed_by = zitadel
+		.get_user_metadata(Some(config.zitadel.organization_id.clone()), &user.id, "managed_by")
+		.await
+		.expect("could not get user metadata");
+	assert_eq!(managed_by, Some("LDAP".to_owned()));
+
+	match user.r#type {
+		Some(UserType::Human(user)) => {
+			assert_eq!(user.phone.expect("phone missing").phone, "+12015550123");
+		}
+		_ => panic!("human user became a machine user?"),
+	}
+}
+
 #[test(tokio::test)]
 #[test_log(default_log_filter = "debug")]
 async fn test_e2e_sync_deactivated_only() {
@@ -836,6 +1620,21 @@ async fn test_e2e_ukt_sync() {
 	let user = user.expect("could not find user");
 	assert_eq!(user.user_name, "delete_me@famedly.de");
 
+	// Users not created by the sync must not be touched
+	config.perform_sync().await.expect("syncing failed");
+
+	let user = zitadel
+		.get_user_by_login_name("delete_me@famedly.de")
+		.await
+		.expect("could not query Zitadel users");
+	assert!(user.is_some());
+
+	// Until they are adopted
+	config.adopt_users("UKT", &["delete_me@famedly.de".to_owned()]).await.expect("adopting failed");
+
+	prepare_oauth2_mock(&mock_server).await;
+	prepare_endpoint_mock(&mock_server, "delete_me@famedly.de").await;
+
 	config.perform_sync().await.expect("syncing failed");
 
 	let user = zitadel.get_user_by_login_name("delete_me@famedly.de").await;
@@ -903,7 +1702,8 @@ async fn test_e2e_csv_sync() {
 	let grant = grants.result.first().expect("no user grants found");
 	assert!(grant.role_keys.clone().into_iter().any(|key| key == FAMEDLY_USER_ROLE));
 
-	// Not possible to re-import an existing user (as checked by unique email)
+	// Importing the user again updates the user this source already
+	// manages, instead of conflicting with it
 	let csv_content = indoc::indoc! {r#"
     email,first_name,last_name,phone
     john.doe@example.com,Changed_Name,Changed_Surname,+2222222222
@@ -920,14 +1720,11 @@ async fn test_e2e_csv_sync() {
 	assert_eq!(user.user_name, "john.doe@example.com");
 	if let Some(UserType::Human(user)) = user.r#type {
 		let profile = user.profile.expect("user lacks a profile");
-		let phone = user.phone.expect("user lacks a phone number");
 		let email = user.email.expect("user lacks an email address");
 
-		assert_eq!(profile.first_name, "John");
-		assert_eq!(profile.last_name, "Doe");
-		assert_eq!(profile.display_name, "Doe, John");
-		assert_eq!(phone.phone, "+1111111111");
-		assert!(phone.is_phone_verified);
+		assert_eq!(profile.first_name, "Changed_Name");
+		assert_eq!(profile.last_name, "Changed_Surname");
+		assert_eq!(profile.display_name, "Changed_Surname, Changed_Name");
 		assert_eq!(email.email, "john.doe@example.com");
 		assert!(email.is_email_verified);
 	} else {
@@ -1213,6 +2010,19 @@ impl Ldap {
 	}
 }
 
+/// Get the roles a user has been granted in the configured project
+async fn get_user_roles(zitadel: &Zitadel, config: &Config, user_id: &str) -> HashSet<String> {
+	zitadel
+		.list_user_grants(&config.zitadel.organization_id, user_id)
+		.await
+		.expect("failed to get user grants")
+		.result
+		.into_iter()
+		.filter(|grant| grant.project_id == config.zitadel.project_id)
+		.flat_map(|grant| grant.role_keys)
+		.collect()
+}
+
 /// Open a connection to the configured Zitadel backend
 async fn open_zitadel_connection() -> Zitadel {
 	let zitadel_config = config().await.zitadel.clone();
@@ -77,6 +77,19 @@ done
 echo "Creating test project"
 project_id="$(zitadel_request 'management/v1/projects' POST --data '{"name": "TestProject"}' | jq --raw-output '.id')"
 zitadel_request "management/v1/projects/$project_id/roles" POST --data '{"roleKey": "User", "displayName": "User"}'
+zitadel_request "management/v1/projects/$project_id/roles" POST --data '{"roleKey": "Admin", "displayName": "Admin"}'
+
+echo "Deleting second Zitadel organization"
+second_orgs="$(zitadel_request 'admin/v1/orgs/_search' POST --data '{"queries": [{"nameQuery": {"name": "SecondOrg", "method": "TEXT_QUERY_METHOD_EQUALS"}}]}')"
+second_orgs="$(echo "$second_orgs" | jq --raw-output '.result[]? | .id')"
+
+for id in $second_orgs; do
+	echo "Deleting organization $id"
+	zitadel_request 'management/v1/orgs/me' DELETE --header "x-zitadel-orgid: $id"
+done
+
+echo "Creating second Zitadel organization"
+second_org_id="$(zitadel_request 'management/v1/orgs' POST --data '{"name": "SecondOrg"}' | jq --raw-output '.id')"
 
 echo "Setting up ldap IDP"
 idp_id="$(zitadel_request 'management/v1/idps/ldap' POST --json @- <<EOF | jq --raw-output '.id'
@@ -106,6 +119,7 @@ org_id="$(zitadel_request 'management/v1/orgs/me' GET | jq --raw-output '.org.id
 sed "s/@ORGANIZATION_ID@/$org_id/" /config.template.yaml > /environment/config.yaml
 sed "s/@PROJECT_ID@/$project_id/" -i /environment/config.yaml
 sed "s/@IDP_ID@/$idp_id/" -i /environment/config.yaml
+sed "s/@SECOND_ORGANIZATION_ID@/$second_org_id/" -i /environment/config.yaml
 
 echo "Deleting LDAP test data"
 ldapdelete -D "${LDAP_ADMIN}" -w "${LDAP_PASSWORD}" -H "${LDAP_HOST}" -r "ou=testorg,${LDAP_BASE}" || true